@@ -0,0 +1,11 @@
+#![no_main]
+
+// any text at all has to come out as a program or a list of errors, never a panic.
+// run with `cargo +nightly fuzz run assemble`, the examples make a good starting corpus
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(src) = std::str::from_utf8(data) {
+        let _ = urcl_rs::check(src.to_owned());
+    }
+});
@@ -9,6 +9,9 @@ use serenity::model::prelude::Ready;
 
 use crate::emulator::*;
 
+// counted in instructions instead of time so every run gets the same limit no matter how busy the bot is
+const MAX_STEPS: u64 = 10_000_000;
+
 struct Handler {}
 
 #[async_trait]
@@ -16,8 +19,14 @@ impl EventHandler for Handler {
     async fn message(&self, ctx: Context, msg: Message) {
         if msg.content.starts_with("!emu") {
             let body;
+            // the first attachment is the program, the others can be @included by their file name
+            let mut files = source::VirtualFiles::new();
             if msg.attachments.len() > 0 {
                 body = reqwest::get(msg.attachments[0].url.clone()).await.unwrap().text().await.unwrap();
+                for file in msg.attachments[1..].iter() {
+                    let src = reqwest::get(file.url.clone()).await.unwrap().text().await.unwrap();
+                    files.add_file(&file.filename, src);
+                }
             } else {
                 let tmp = msg.content.split("```").collect::<Vec<&str>>();
 
@@ -30,9 +39,10 @@ impl EventHandler for Handler {
                 body = tmp[1].to_string();
             }
 
-            let mut emu = match emulator::silence_emulate(body) {
+            let mut emu = match emulator::silence_emulate_with_files(body, &files, limits::ResourceLimits::untrusted()) {
                 Ok(emu) => emu,
-                Err(err) => {
+                Err(diagnostics) => {
+                    let err = diagnostic::render(&diagnostics, diagnostic::Format::Ansi);
                     if let Err(err) = msg.channel_id.say(&ctx.http, format!("Cannot compile URCL code: ```ansi\n{}```", err)).await {
                         println!("\x1b[1;93mDiscord bot warning: Unable to send message, reason: {}\x1b[0;0m", err)
                     };
@@ -40,7 +50,7 @@ impl EventHandler for Handler {
                 }
             };
 
-            let result = silence_run_for_ms(&mut emu, 1000.0);
+            let result = emu.run_for_steps(MAX_STEPS).result;
             let mut att = Vec::<AttachmentType>::new();
 
             let screen = emu.get_screen();
@@ -69,7 +79,7 @@ impl EventHandler for Handler {
                 },
                 StepResult::Continue => {
                     let output = emu.get_output();
-                    if let Err(err) = msg.channel_id.send_files(&ctx.http, att, |m| m.content(format!("Program ran for more than 1000ms: ```\n{}```", output))).await {
+                    if let Err(err) = msg.channel_id.send_files(&ctx.http, att, |m| m.content(format!("Program ran for more than {} instructions: ```\n{}```", MAX_STEPS, output))).await {
                         println!("\x1b[1;93mDiscord bot warning: Unable to send message, reason: {}\x1b[0;0m", err)
                     };
                 },
@@ -102,27 +112,6 @@ pub async fn init_bot(token: &str) -> Result<(), SerenityError> {
     client.start().await
 }
 
-
-pub fn silence_run_for_ms(emu: &mut emulator::emulator::EmulatorState, max_time_ms: f64) -> emulator::emulator::StepResult {
-    use emulator::emulator::StepResult;
-
-    const BURST_LENGTH: u32 = 1024;
-    let start = crate::now();
-    let end = start + max_time_ms;
-    while crate::now() < end {
-        for _ in 0..BURST_LENGTH {
-            let result = emu.step();
-            match result {
-                StepResult::Continue => (),
-                _ => {
-                    return result;
-                }
-            }
-        }
-    }
-    return StepResult::Continue
-}
-
 #[derive(Clone)]
 pub struct RGBA24 {
     pub r: u8,
@@ -1,6 +1,9 @@
-use std::{collections::HashMap, str::FromStr, rc::Rc};
+use std::{collections::{HashMap, HashSet}, str::FromStr, rc::Rc};
 
-use super::{lexer::{Token, Kind, UToken}, errorcontext::{ErrorContext, ErrorKind}, devices::IOPort};
+use num_traits::FromPrimitive;
+use strum::VariantNames;
+
+use super::{custom, float, expr::{self, Expr, ExprError}, isa::{with_isa, isa_inst, isa_any, same, position, InstInfo, OperandKind}, lexer::{self, Token, Kind, UToken}, errorcontext::{ErrorContext, ErrorKind, closest}, devices::{IOPort, screen}, limits::ResourceLimits, preprocess::Preprocessor, source::{FileProvider, SourceFiles}, emulator::{PC, SP}};
 
 struct TokenBuffer<'a> {
     index: usize,
@@ -10,7 +13,7 @@ impl <'a> TokenBuffer<'a> {
     #[inline]
     pub fn new(toks: Vec<UToken<'a>>) -> Self {
         TokenBuffer {
-            toks: toks,
+            toks,
             index: 0,
         }
     }
@@ -18,21 +21,39 @@ impl <'a> TokenBuffer<'a> {
     pub fn has_next(&self) -> bool {
         self.index < self.toks.len()
     }
+    // stops at the end of file token, so running past the end just keeps giving EOF
     #[inline]
     pub fn advance(&mut self) {
-        self.index += 1;
-        while matches!(self.current().kind, Kind::White | Kind::Comment) {
+        let last = self.toks.len() - 1;
+        self.index = (self.index + 1).min(last);
+        while self.index < last && matches!(self.toks[self.index].kind, Kind::White | Kind::Comment) {
             self.index += 1;
         }
     }
     #[inline]
     pub fn peek(&mut self) -> UToken<'a> {
-        let mut a = self.index + 1;
-        while matches!(self.toks[a].kind, Kind::White | Kind::Comment | Kind::LF) {
+        let last = self.toks.len() - 1;
+        let mut a = (self.index + 1).min(last);
+        while a < last && matches!(self.toks[a].kind, Kind::White | Kind::Comment | Kind::LF) {
             a += 1;
         }
         self.toks[a].clone()
     }
+    // the index of the first token after index that isnt whitespace, line breaks count
+    pub fn after(&self, index: usize) -> usize {
+        let mut a = index + 1;
+        while a < self.toks.len() - 1 && matches!(self.toks[a].kind, Kind::White | Kind::Comment) {
+            a += 1;
+        }
+        a.min(self.toks.len() - 1)
+    }
+    // whether the token at index continues an expression, `.a - 1` does but the - in `%NUMB -.a` is a sign
+    pub fn is_operator(&self, index: usize) -> bool {
+        let sign = matches!(self.toks[index].kind, Kind::Plus | Kind::Minus)
+            && self.toks[index - 1].kind == Kind::White
+            && self.toks.get(index + 1).is_some_and(|t| t.kind != Kind::White);
+        expr::is_binary(self.toks[index].kind) && !sign
+    }
     #[inline]
     pub fn next(&mut self) -> UToken<'a> {
         self.advance();
@@ -43,7 +64,7 @@ impl <'a> TokenBuffer<'a> {
         if self.has_next() {
             self.toks[self.index].clone()
         } else {
-            Token {kind: Kind::EOF, str: ""}
+            Token::new(Kind::EOF, "")
         }
     }
     pub fn cur(&self) -> &UToken<'a> {
@@ -59,325 +80,290 @@ pub struct Parser<'a> {
     buf: TokenBuffer<'a>,
     pub err: ErrorContext<'a>,
     pub ast: Program,
-    pub at_line: usize,
-    pub macros: HashMap<&'a str, UToken<'a>>
+    files: &'a SourceFiles,
+    limits: ResourceLimits,
+    pub macros: HashMap<&'a str, Vec<UToken<'a>>>,
+    defines: Vec<&'a str>, // the defines define_op is inside of right now
+    pre: Preprocessor<'a>,
+    raw: usize, // the conditional the preprocessor stopped at, tokens after it arent expanded yet
+    pub header_toks: HashMap<&'static str, UToken<'a>>,
+    float_tok: Option<UToken<'a>>,
+    conds: Vec<(UToken<'a>, bool)>, // the @ifs we are inside of and whether we got to their @else yet
+    exprs: Vec<(Expr<'a>, UToken<'a>)>, // expressions waiting for their labels, Operand::Expr indexes this
+    dw_exprs: Vec<(usize, usize)>, // the same for DW, address and expression
+    global: &'a str, // the last label that isnt local, ..name labels belong to it
+    regs: HashMap<&'a str, (u64, bool)>, // @reg names, the bool is whether they go away at the next label
+    max_reg: Option<(u64, UToken<'a>)>, // the highest register used and where, for checking MINREG
+    pub inst_toks: Vec<UToken<'a>>, // the mnemonic of every instruction, for pointing lints at them
+    pub label_defs: Vec<(String, UToken<'a>, bool)>, // every label in order, the bool is whether its on DW data
+    pub used_labels: HashSet<String>,
+    hints: HashMap<(&'a str, String), Option<String>>, // did you mean notes by name and the error code or label it was looked up as
 }
 
-pub fn gen_ast<'a>(toks: Vec<UToken<'a>>, src: Rc<str>) -> Parser<'a> {
-    let err = ErrorContext::new();
-    let ast = Program::new(src);
+pub fn gen_ast<'a>(toks: Vec<UToken<'a>>, files: &'a Rc<SourceFiles>, provider: &'a dyn FileProvider, limits: &ResourceLimits) -> Parser<'a> {
+    let mut err = ErrorContext::new();
+    let mut pre = Preprocessor::new(files, provider);
+    let (toks, raw) = pre.preprocess(toks, &mut err);
+    let ast = Program::new(files.clone());
     let buf = TokenBuffer::new(toks);
-    let mut p = Parser {buf, err, ast, at_line: 1, macros: HashMap::new() };
+    let mut p = Parser {buf, err, ast, files, limits: *limits, pre, raw, macros: HashMap::new(), defines: Vec::new(), header_toks: HashMap::new(), float_tok: None, conds: Vec::new(), exprs: Vec::new(), dw_exprs: Vec::new(), global: "", regs: HashMap::new(), max_reg: None, inst_toks: Vec::new(), label_defs: Vec::new(), used_labels: HashSet::new(), hints: HashMap::new() };
+
+    // defines from the command line or the api, as if they were at the top of the file
+    for (name, value) in files.defines() {
+        let value = lexer::lex(value).into_iter().filter(|t| !matches!(t.kind, Kind::White | Kind::Comment | Kind::EOF)).collect();
+        p.macros.insert(name, value);
+    }
 
     let mut dw_lab_repl: HashMap<String, Vec<u64>> = HashMap::new();
     let mut dw_mem_repl: Vec<u64> = Vec::new();
 
     while p.buf.has_next() {
+        if p.buf.index > p.raw {
+            p.expand_rest();
+        }
         match p.buf.current().kind {
             Kind::Name => {
-                match p.buf.current().str.to_lowercase().as_str() {
+                let start = p.buf.current();
+                match start.str.to_lowercase().as_str() {
                     "bits" => {
-                        p.ast.headers.bits = match p.buf.next().kind { Kind::Int(v) => v as u64, _ => match p.buf.next().kind {Kind::Int(v) => v as u64, _ => continue} };
-                        p.buf.advance();
-                    },
-                    "minreg" => {
-                        p.ast.headers.minreg = match p.buf.next().kind {Kind::Int(v) => v as u64, _ => {continue;}};
-                        p.buf.advance();
-                    },
-                    "minheap" => {
-                        p.ast.headers.minheap = match p.buf.next().kind {Kind::Int(v) => v as u64, _ => {continue;}};
-                        p.buf.advance();
-                    },
-                    "minstack" => {
-                        p.ast.headers.minstack = match p.buf.next().kind {Kind::Int(v) => v as u64, _ => {continue;}};
-                        p.buf.advance();
+                        if let Some(v) = p.header_value(true) {
+                            p.ast.headers.bits = v;
+                        }
+                        p.assert_done();
                     },
-
-                    "dw" => {
-                        match p.buf.next().kind {
-                            Kind::LSquare => {
-                                while p.buf.has_next() {
-                                    if matches!(p.buf.next().kind, Kind::RSquare) { break }
-                                    let mut a = p.parse_dw(&mut dw_lab_repl, &mut dw_mem_repl);
-                                    p.ast.memory.append(&mut a);
-                                }
-                            },
-                            _ => {
-                                let mut a = p.parse_dw(&mut dw_lab_repl, &mut dw_mem_repl);
-                                p.ast.memory.append(&mut a)
+                    "minreg" | "minheap" | "minstack" => {
+                        let header = match start.str.to_lowercase().as_str() {
+                            "minreg" => "minreg",
+                            "minheap" => "minheap",
+                            _ => "minstack",
+                        };
+                        if let Some(v) = p.header_value(false) {
+                            match header {
+                                "minreg" => p.ast.headers.minreg = v,
+                                "minheap" => p.ast.headers.minheap = v,
+                                _ => p.ast.headers.minstack = v,
                             }
+                            p.header_toks.insert(header, p.buf.current());
                         }
+                        p.assert_done();
+                    },
+                    "float" => {
+                        p.ast.headers.float = true;
+                        p.assert_done();
                     },
 
-                    "imm"     => inst(Inst::MOV(p.get_reg(), p.get_imm())           , &mut p),
-                    "mov"     => inst(Inst::MOV(p.get_reg(), p.get_op())            , &mut p),
-                    "add"     => inst(Inst::ADD(p.get_reg(), p.get_op(), p.get_op()), &mut p),
-                    "rsh"     => inst(Inst::RSH(p.get_reg(), p.get_op())            , &mut p),
-                    "lod"     => inst(Inst::LOD(p.get_reg(), p.get_mem())           , &mut p),
-                    "str"     => inst(Inst::STR(p.get_mem(), p.get_op())            , &mut p),
-                    "bge"     => inst(Inst::BGE(p.get_jmp(), p.get_op(), p.get_op()), &mut p),
-                    "nor"     => inst(Inst::NOR(p.get_reg(), p.get_op(), p.get_op()), &mut p),
-                    "inc"     => inst(Inst::INC(p.get_reg(), p.get_op())            , &mut p),
-                    "dec"     => inst(Inst::DEC(p.get_reg(), p.get_op())            , &mut p),
-                    "hlt"     => inst(Inst::HLT                                     , &mut p),
-                    "sub"     => inst(Inst::SUB(p.get_reg(), p.get_op(), p.get_op()), &mut p),
-                    "nop"     => inst(Inst::NOP                                     , &mut p),
-                    "lsh"     => inst(Inst::LSH(p.get_reg(), p.get_op())            , &mut p),
-                    "out"     => inst(Inst::OUT(p.get_port(), p.get_op())           , &mut p),
-                    "in"      => inst(Inst::IN (p.get_reg(), p.get_port())          , &mut p),
-                    "psh"     => inst(Inst::PSH(p.get_op())                         , &mut p),
-                    "pop"     => inst(Inst::POP(p.get_reg())                        , &mut p),
-                    "jmp"     => inst(Inst::JMP(p.get_jmp())                        , &mut p),
-                    "neg"     => inst(Inst::NEG(p.get_reg(), p.get_op())            , &mut p),
-                    "and"     => inst(Inst::AND(p.get_reg(), p.get_op(), p.get_op()), &mut p),
-                    "or"      => inst(Inst::OR (p.get_reg(), p.get_op(), p.get_op()), &mut p),
-                    "not"     => inst(Inst::NOT(p.get_reg(), p.get_op())            , &mut p),
-                    "nand"    => inst(Inst::NAND(p.get_reg(), p.get_op(), p.get_op()),&mut p),
-                    "cpy"     => inst(Inst::CPY(p.get_mem(), p.get_mem())           , &mut p),
-                    "mlt"     => inst(Inst::MLT(p.get_reg(), p.get_op(), p.get_op()), &mut p),
-                    "div"     => inst(Inst::DIV(p.get_reg(), p.get_op(), p.get_op()), &mut p),
-                    "mod"     => inst(Inst::MOD(p.get_reg(), p.get_op(), p.get_op()), &mut p),
-                    "abs"     => inst(Inst::ABS(p.get_reg(), p.get_op())            , &mut p),
-                    "llod"    => inst(Inst::LLOD(p.get_reg(), p.get_op(), p.get_op()),&mut p),
-                    "lstr"    => inst(Inst::LSTR(p.get_op(), p.get_op(), p.get_op()), &mut p),
-                    "sdiv"    => inst(Inst::SDIV(p.get_reg(), p.get_op(), p.get_op()),&mut p),
-                    "sete"    => inst(Inst::SETE(p.get_reg(), p.get_op(), p.get_op()),&mut p),
-                    "setne"   => inst(Inst::SETNE(p.get_reg(), p.get_op(), p.get_op()),&mut p),
-                    "setg"    => inst(Inst::SETG(p.get_reg(), p.get_op(), p.get_op()),&mut p),
-                    "setge"   => inst(Inst::SETGE(p.get_reg(), p.get_op(), p.get_op()),&mut p),
-                    "setl"    => inst(Inst::SETL(p.get_reg(), p.get_op(), p.get_op()),&mut p),
-                    "setle"   => inst(Inst::SETLE(p.get_reg(), p.get_op(), p.get_op()),&mut p),
-                    "xor"     => inst(Inst::XOR(p.get_reg(), p.get_op(), p.get_op()), &mut p),
-                    "xnor"    => inst(Inst::XNOR(p.get_reg(), p.get_op(), p.get_op()),&mut p),
-                    "bne"     => inst(Inst::BNE(p.get_op(), p.get_op(), p.get_op()) , &mut p),
-                    "bre"     => inst(Inst::BRE(p.get_op(), p.get_op(), p.get_op()) , &mut p),
-                    "ssetg"   => inst(Inst::SSETG(p.get_reg(), p.get_op(), p.get_op()),&mut p),
-                    "ssetge"  => inst(Inst::SSETGE(p.get_reg(), p.get_op(), p.get_op()),&mut p),
-                    "ssetl"   => inst(Inst::SSETL(p.get_reg(), p.get_op(), p.get_op()),&mut p),
-                    "ssetle"  => inst(Inst::SSETLE(p.get_reg(), p.get_op(), p.get_op()),&mut p),
-                    "brl"     => inst(Inst::BRL(p.get_op(), p.get_op(), p.get_op()),  &mut p),
-                    "brg"     => inst(Inst::BRG(p.get_op(), p.get_op(), p.get_op()),  &mut p),
-                    "ble"     => inst(Inst::BLE(p.get_op(), p.get_op(), p.get_op()),  &mut p),
-                    "brz"     => inst(Inst::BRZ(p.get_op(), p.get_op())            ,  &mut p),
-                    "bnz"     => inst(Inst::BNZ(p.get_op(), p.get_op())            ,  &mut p),
-                    "setc"    => inst(Inst::SETC(p.get_reg(), p.get_op(), p.get_op()),&mut p),
-                    "setnc"   => inst(Inst::SETNC(p.get_reg(), p.get_op(), p.get_op()), &mut p),
-                    "bnc"     => inst(Inst::BNC(p.get_op(), p.get_op(), p.get_op()),  &mut p),
-                    "brc"     => inst(Inst::BRC(p.get_op(), p.get_op(), p.get_op()),  &mut p),
-                    "sbrl"    => inst(Inst::SBRL(p.get_op(), p.get_op(), p.get_op()), &mut p),
-                    "sbrg"    => inst(Inst::SBRG(p.get_op(), p.get_op(), p.get_op()), &mut p),
-                    "sble"    => inst(Inst::SBLE(p.get_op(), p.get_op(), p.get_op()), &mut p),
-                    "sbge"    => inst(Inst::SBGE(p.get_op(), p.get_op(), p.get_op()), &mut p),
-                    "bod"     => inst(Inst::BOD(p.get_op(), p.get_op())             , &mut p),
-                    "bev"     => inst(Inst::BEV(p.get_op(), p.get_op())             , &mut p),
-                    "brn"     => inst(Inst::BRN(p.get_op(), p.get_op()),              &mut p),
-                    "brp"     => inst(Inst::BRP(p.get_op(), p.get_op()),              &mut p),
-                    "bsr"     => inst(Inst::BSR(p.get_reg(), p.get_op(), p.get_op()), &mut p),
-                    "bsl"     => inst(Inst::BSL(p.get_reg(), p.get_op(), p.get_op()), &mut p),
-                    "srs"     => inst(Inst::SRS(p.get_reg(), p.get_op())            , &mut p),
-                    "bss"     => inst(Inst::BSS(p.get_reg(), p.get_op(), p.get_op()), &mut p),
-                    "cal"     => inst(Inst::CAL(p.get_jmp())                        , &mut p),
-                    "ret"     => inst(Inst::RET                                     , &mut p),
+                    "dw" | "dwz" | "dwp" => {
+                        let strings = match start.str.to_lowercase().as_str() {
+                            "dwz" => Strings::Zero,
+                            "dwp" => Strings::Packed,
+                            _ => Strings::Plain,
+                        };
+                        p.buf.advance();
+                        p.dw_entry(strings, &mut dw_lab_repl, &mut dw_mem_repl);
+                        p.assert_done();
+                    },
 
                     "yomamma" => { p.err.error(&p.buf.current(), ErrorKind::YoMamma); p.buf.advance(); },
-                    _ => { p.err.error(&p.buf.current(), ErrorKind::UnknownInstruction); p.buf.advance(); },
+                    name => match Inst::parse(name, &mut p) {
+                        Some(i) => {
+                            // instructions from a macro are on the line of the call
+                            p.ast.debug.add(start.origin.unwrap_or(start.str));
+                            p.inst_toks.push(start.clone());
+                            inst(i, &mut p)
+                        },
+                        None => { p.unknown(&start, ErrorKind::UnknownInstruction); p.skip_line(); },
+                    },
                 }
-                p.ast.debug.pc_to_line_start.push(p.at_line);
             },
             Kind::Label => {
-                match p.ast.labels.get(p.buf.current().str) {
+                let tok = p.buf.current();
+                // labels from macros dont start a new scope, they are local to the expansion already
+                if !tok.str.starts_with("..") && tok.scope == 0 {
+                    p.global = tok.str;
+                    p.regs.retain(|_, (_, local)| !*local);
+                }
+                let label_name = p.label_key(&tok);
+                if !matches!(p.ast.labels.get(&label_name), Some(Label::Defined(_))) {
+                    let data = is_dw(p.buf.peek().str);
+                    if !data {
+                        p.ast.debug.labels.entry(p.ast.instructions.len()).or_default().push(label_name.clone());
+                    }
+                    p.label_defs.push((label_name.clone(), tok.clone(), data));
+                }
+                match p.ast.labels.get(&label_name) {
                     Some(Label::Defined(_)) => p.err.error(&p.buf.current(), ErrorKind::DuplicatedLabelName),
                     Some(Label::Undefined(v)) => {
-                        let label_name = p.buf.current().str;
-                        let pc = match p.buf.peek().str.to_lowercase().as_str() {
-                            "dw" => p.ast.memory.len(),
-                            _ => p.ast.instructions.len()
-                        };
+                        let pc = if is_dw(p.buf.peek().str) {p.ast.memory.len()} else {p.ast.instructions.len()};
 
-                        if dw_lab_repl.get(label_name).is_some() {
-                            for i in dw_lab_repl.get(label_name).unwrap().iter() {
+                        if dw_lab_repl.get(&label_name).is_some() {
+                            for i in dw_lab_repl.get(&label_name).unwrap().iter() {
                                 p.ast.memory[*i as usize] = pc as u64;
                             }
                         }
 
                         for i in v.references.iter() {
-                            p.ast.instructions[*i] = match &p.ast.instructions[*i] {
-                                Inst::PSH(a) => Inst::PSH(a.clone().transform_label(label_name, pc)),
-                                Inst::JMP(a) => Inst::JMP(a.clone().transform_label(label_name, pc)),
-                                Inst::MOV(a, b) => Inst::MOV(a.clone(), b.clone().transform_label(label_name, pc)),
-                                Inst::IN (a, b) => Inst::IN(a.clone(), b.clone().transform_label(label_name, pc)),
-                                Inst::OUT(a, b) => Inst::OUT(a.clone(), b.clone().transform_label(label_name, pc)),
-                                Inst::INC(a, b) => Inst::INC(a.clone(), b.clone().transform_label(label_name, pc)),
-                                Inst::DEC(a, b) => Inst::DEC(a.clone(), b.clone().transform_label(label_name, pc)),
-                                Inst::LSH(a, b) => Inst::LSH(a.clone(), b.clone().transform_label(label_name, pc)),
-                                Inst::RSH(a, b) => Inst::RSH(a.clone(), b.clone().transform_label(label_name, pc)),
-                                Inst::LOD(a, b) => Inst::LOD(a.clone(), b.clone().transform_label(label_name, pc)),
-                                Inst::STR(a, b) => Inst::STR(a.clone().transform_label(label_name, pc), b.clone()),
-                                Inst::ADD(a, b, c) => Inst::ADD(a.clone(), b.clone().transform_label(label_name, pc), c.clone().transform_label(label_name, pc)),
-                                Inst::SUB(a, b, c) => Inst::SUB(a.clone(), b.clone().transform_label(label_name, pc), c.clone().transform_label(label_name, pc)),
-                                Inst::NOR(a, b, c) => Inst::NOR(a.clone(), b.clone().transform_label(label_name, pc), c.clone().transform_label(label_name, pc)),
-                                Inst::BGE(a, b, c) => Inst::BGE(a.clone().transform_label(label_name, pc), b.clone().transform_label(label_name, pc), c.clone().transform_label(label_name, pc)),
-                                Inst::NEG(a, b) => Inst::NEG(a.clone(), b.clone().transform_label(label_name, pc)),
-                                Inst::AND(a, b, c) => Inst::AND(a.clone(), b.clone().transform_label(label_name, pc), c.clone().transform_label(label_name, pc)),
-                                Inst::OR(a, b, c) => Inst::OR(a.clone(), b.clone().transform_label(label_name, pc), c.clone().transform_label(label_name, pc)),
-                                Inst::NOT(a, b) => Inst::NOT(a.clone(), b.clone().transform_label(label_name, pc)),
-                                Inst::NAND(a, b, c) => Inst::NAND(a.clone(), b.clone().transform_label(label_name, pc), c.clone().transform_label(label_name, pc)),
-                                Inst::MLT(a, b, c) => Inst::MLT(a.clone(), b.clone().transform_label(label_name, pc), c.clone().transform_label(label_name, pc)),
-                                Inst::DIV(a, b, c) => Inst::DIV(a.clone(), b.clone().transform_label(label_name, pc), c.clone().transform_label(label_name, pc)),
-                                Inst::MOD(a, b, c) => Inst::MOD(a.clone(), b.clone().transform_label(label_name, pc), c.clone().transform_label(label_name, pc)),
-                                Inst::ABS(a, b) => Inst::ABS(a.clone(), b.clone().transform_label(label_name, pc)),
-                                Inst::LLOD(a, b, c) => Inst::LLOD(a.clone(), b.clone().transform_label(label_name, pc), c.clone().transform_label(label_name, pc)),
-                                Inst::LSTR(a, b, c) => Inst::LSTR(a.clone().transform_label(label_name, pc), b.clone().transform_label(label_name, pc), c.clone()),
-                                Inst::SDIV(a, b, c) => Inst::SDIV(a.clone(), b.clone().transform_label(label_name, pc), c.clone().transform_label(label_name, pc)),
-                                Inst::SETE(a, b, c) => Inst::SETE(a.clone(), b.clone().transform_label(label_name, pc), c.clone().transform_label(label_name, pc)),
-                                Inst::SETNE(a, b, c) => Inst::SETNE(a.clone(), b.clone().transform_label(label_name, pc), c.clone().transform_label(label_name, pc)),
-                                Inst::SETG(a, b, c) => Inst::SETG(a.clone(), b.clone().transform_label(label_name, pc), c.clone().transform_label(label_name, pc)),
-                                Inst::SETGE(a, b, c) => Inst::SETGE(a.clone(), b.clone().transform_label(label_name, pc), c.clone().transform_label(label_name, pc)),
-                                Inst::SETL(a, b, c) => Inst::SETL(a.clone(), b.clone().transform_label(label_name, pc), c.clone().transform_label(label_name, pc)),
-                                Inst::SETLE(a, b, c) => Inst::SETLE(a.clone(), b.clone().transform_label(label_name, pc), c.clone().transform_label(label_name, pc)),
-                                Inst::XOR(a, b, c) => Inst::XOR(a.clone(), b.clone().transform_label(label_name, pc), c.clone().transform_label(label_name, pc)),
-                                Inst::XNOR(a, b, c) => Inst::XNOR(a.clone(), b.clone().transform_label(label_name, pc), c.clone().transform_label(label_name, pc)),
-                                Inst::BNE(a, b, c) => Inst::BNE(a.clone().transform_label(label_name, pc), b.clone().transform_label(label_name, pc), c.clone().transform_label(label_name, pc)),
-                                Inst::BRE(a, b, c) => Inst::BRE(a.clone().transform_label(label_name, pc), b.clone().transform_label(label_name, pc), c.clone().transform_label(label_name, pc)),
-                                Inst::SSETG(a, b, c) => Inst::SSETG(a.clone(), b.clone().transform_label(label_name, pc), c.clone().transform_label(label_name, pc)),
-                                Inst::SSETGE(a, b, c) => Inst::SSETGE(a.clone(), b.clone().transform_label(label_name, pc), c.clone().transform_label(label_name, pc)),
-                                Inst::SSETL(a, b, c) => Inst::SSETL(a.clone(), b.clone().transform_label(label_name, pc), c.clone().transform_label(label_name, pc)),
-                                Inst::SSETLE(a, b, c) => Inst::SSETLE(a.clone(), b.clone().transform_label(label_name, pc), c.clone().transform_label(label_name, pc)),
-                                Inst::BRL(a, b, c) => Inst::BRL(a.clone().transform_label(label_name, pc), b.clone().transform_label(label_name, pc), c.clone().transform_label(label_name, pc)),
-                                Inst::BRG(a, b, c) => Inst::BRG(a.clone().transform_label(label_name, pc), b.clone().transform_label(label_name, pc), c.clone().transform_label(label_name, pc)),
-                                Inst::BLE(a, b, c) => Inst::BLE(a.clone().transform_label(label_name, pc), b.clone().transform_label(label_name, pc), c.clone().transform_label(label_name, pc)),
-                                Inst::BRZ(a, b) => Inst::BRZ(a.clone().transform_label(label_name, pc), b.clone().transform_label(label_name, pc)),
-                                Inst::BNZ(a, b) => Inst::BNZ(a.clone().transform_label(label_name, pc), b.clone().transform_label(label_name, pc)),
-                                Inst::SETC(a, b, c) => Inst::SETC(a.clone(), b.clone().transform_label(label_name, pc), c.clone().transform_label(label_name, pc)),
-                                Inst::SETNC(a, b, c) => Inst::SETNC(a.clone(), b.clone().transform_label(label_name, pc), c.clone().transform_label(label_name, pc)),
-                                Inst::BNC(a, b, c) => Inst::BNC(a.clone().transform_label(label_name, pc), b.clone().transform_label(label_name, pc), c.clone().transform_label(label_name, pc)),
-                                Inst::BRC(a, b, c) => Inst::BRC(a.clone().transform_label(label_name, pc), b.clone().transform_label(label_name, pc), c.clone().transform_label(label_name, pc)),
-                                Inst::SBRL(a, b, c) => Inst::SBRL(a.clone().transform_label(label_name, pc), b.clone().transform_label(label_name, pc), c.clone().transform_label(label_name, pc)),
-                                Inst::SBRG(a, b, c) => Inst::SBRG(a.clone().transform_label(label_name, pc), b.clone().transform_label(label_name, pc), c.clone().transform_label(label_name, pc)),
-                                Inst::SBLE(a, b, c) => Inst::SBLE(a.clone().transform_label(label_name, pc), b.clone().transform_label(label_name, pc), c.clone().transform_label(label_name, pc)),
-                                Inst::SBGE(a, b, c) => Inst::SBGE(a.clone().transform_label(label_name, pc), b.clone().transform_label(label_name, pc), c.clone().transform_label(label_name, pc)),
-                                Inst::BOD(a, b) => Inst::BOD(a.clone().transform_label(label_name, pc), b.clone().transform_label(label_name, pc)),
-                                Inst::BEV(a, b) => Inst::BEV(a.clone().transform_label(label_name, pc), b.clone().transform_label(label_name, pc)),
-                                Inst::BRN(a, b) => Inst::BRN(a.clone().transform_label(label_name, pc), b.clone().transform_label(label_name, pc)),
-                                Inst::BRP(a, b) => Inst::BRP(a.clone().transform_label(label_name, pc), b.clone().transform_label(label_name, pc)),
-                                Inst::BSR(a, b, c) => Inst::BSR(a.clone(), b.clone().transform_label(label_name, pc), c.clone().transform_label(label_name, pc)),
-                                Inst::BSL(a, b, c) => Inst::BSL(a.clone(), b.clone().transform_label(label_name, pc), c.clone().transform_label(label_name, pc)),
-                                Inst::SRS(a, b) => Inst::SRS(a.clone(), b.clone().transform_label(label_name, pc)),
-                                Inst::BSS(a, b, c) => Inst::BSS(a.clone(), b.clone().transform_label(label_name, pc), c.clone().transform_label(label_name, pc)),
-                                Inst::CAL(a) => Inst::CAL(a.clone().transform_label(label_name, pc)),
-                                _ => continue,
+                            for op in p.ast.instructions[*i].operands_mut() {
+                                op.transform_label(&label_name, pc);
                             }
                         }
-                        p.ast.labels.insert(p.buf.current().str.to_string(), Label::Defined(p.ast.instructions.len()));
+                        p.ast.labels.insert(label_name, Label::Defined(pc));
                     },
                     None => {
-                        let pc = match p.buf.peek().str.to_lowercase().as_str() {
-                            "dw" => p.ast.memory.len(),
-                            _ => p.ast.instructions.len()
-                        };
-                        p.ast.labels.insert(p.buf.current().str.to_string(), Label::Defined(pc));
+                        let pc = if is_dw(p.buf.peek().str) {p.ast.memory.len()} else {p.ast.instructions.len()};
+                        p.ast.labels.insert(label_name, Label::Defined(pc));
                     },
                 }
                 p.buf.advance();
             },
             Kind::Macro => {
-                match p.buf.current().str {
+                let start = p.buf.current();
+                match start.str.to_lowercase().as_str() {
                     "@define" => {
-                        let to_replace = p.buf.next().str;
-                        p.macros.insert(to_replace, p.buf.next());
+                        let name = p.buf.next();
+                        if name.kind == Kind::Name {
+                            let body = p.rest_of_line();
+                            p.macros.insert(name.str, body);
+                        } else {
+                            p.err.error(if matches!(name.kind, Kind::LF | Kind::EOF) {&start} else {&name}, ErrorKind::DefineNoName);
+                            p.skip_line();
+                        }
+                    },
+                    "@if" | "@ifdef" | "@ifndef" => {
+                        let cond = p.rest_of_line();
+                        let value = match (start.str.to_lowercase().as_str(), cond.as_slice()) {
+                            ("@ifdef", [name]) if name.kind == Kind::Name => Some(p.macros.contains_key(name.str)),
+                            ("@ifndef", [name]) if name.kind == Kind::Name => Some(!p.macros.contains_key(name.str)),
+                            ("@if", cond) => p.condition(cond),
+                            _ => None,
+                        };
+                        let value = value.unwrap_or_else(|| {
+                            p.err.error(&start, ErrorKind::InvalidCondition);
+                            false
+                        });
+                        if value {
+                            p.conds.push((start, false));
+                        } else {
+                            if p.skip_block(&start) {
+                                p.conds.push((start, true));
+                            }
+                            p.buf.advance();
+                        }
+                    },
+                    "@else" => {
+                        match p.conds.pop() {
+                            // the block before was assembled so this one isnt
+                            Some((open, false)) => while p.skip_block(&open) {
+                                p.err.error(&p.buf.current(), ErrorKind::DuplicatedElse);
+                            },
+                            Some(open) => {
+                                p.err.error(&start, ErrorKind::DuplicatedElse);
+                                p.conds.push(open);
+                            },
+                            None => p.err.error(&start, ErrorKind::NoMatchingIf),
+                        }
+                        p.buf.advance();
+                    },
+                    // @reg name R3, names made after a label only last until the next one like ..locals
+                    "@reg" => {
+                        let name = p.buf.next();
+                        let reg = if name.kind == Kind::Name {p.buf.next()} else {name.clone()};
+                        match reg.kind {
+                            Kind::Reg(v) if name.kind == Kind::Name && v != PC && v != SP => {
+                                p.use_reg(&reg, v);
+                                p.regs.insert(name.str, (v, !p.global.is_empty()));
+                                let names = p.ast.debug.reg_names.entry(v).or_default();
+                                if !names.iter().any(|n| n == name.str) {
+                                    names.push(name.str.to_owned());
+                                }
+                                p.assert_done();
+                            },
+                            _ => {
+                                p.err.error(&start, ErrorKind::InvalidRegAlias);
+                                p.skip_line();
+                            },
+                        }
                     },
-                    _ => {p.err.error(&p.buf.current(), ErrorKind::UnexpectedMacro); p.buf.advance()},
+                    "@endif" => {
+                        if p.conds.pop().is_none() {
+                            p.err.error(&start, ErrorKind::NoMatchingIf);
+                        }
+                        p.buf.advance();
+                    },
+                    _ => {p.err.error(&p.buf.current(), ErrorKind::UnexpectedMacro); p.skip_line()},
                 }
             }
-            Kind::White | Kind::Comment | Kind::Char | Kind::String => p.buf.advance(),
+            Kind::White | Kind::Comment | Kind::LF => p.buf.advance(),
+            Kind::RSquare => {p.err.error(&p.buf.current(), ErrorKind::NoMatchingSquare); p.buf.advance()},
             Kind::EOF => break,
-            Kind::LF => {p.at_line += 1; p.buf.advance()},
-            _ => { p.buf.advance(); },
+            // nothing else can start a line, skip it and carry on with the next one
+            _ => {p.err.error(&p.buf.current(), ErrorKind::UnexpectedToken); p.skip_line()},
         }
     }
 
-    for (_, el) in p.ast.labels.iter() {
+    for (open, _) in std::mem::take(&mut p.conds) {
+        p.err.error(&open, ErrorKind::IfNoEnding);
+    }
+
+    let mut undefined = Vec::new();
+    for (name, el) in p.ast.labels.iter() {
         match el {
             Label::Undefined(a) => {
                 for i in a.referenced_tokens.iter() {
-                    p.err.error(&p.buf.toks[*i], ErrorKind::UndefinedLabel);
+                    undefined.push((p.buf.toks[*i].clone(), name.clone()));
                 }
             },
             _ => (),
         }
     }
+    for (tok, name) in undefined {
+        p.undefined_label(&tok, &name);
+    }
+
+    // registers past MINREG would be out of bounds when running, so MINREG grows to fit them. before the
+    // expressions, @MINREG in them is the grown one
+    if let Some((reg, tok)) = p.max_reg.clone() {
+        let minreg = p.ast.headers.minreg;
+        if reg > minreg {
+            let kind = ErrorKind::RegisterAboveMinreg { reg, minreg };
+            // a MINREG the program asked for is probably a mistake, the default one isnt
+            if p.header_toks.contains_key("minreg") {p.err.warn(&tok, kind)} else {p.err.info(&tok, kind)}
+            p.ast.headers.minreg = reg;
+            p.header_toks.insert("minreg", tok);
+        }
+    }
+
+    // every label is known by now
+    let exprs = std::mem::take(&mut p.exprs);
+    let values: Vec<u64> = exprs.iter().map(|(expr, start)| p.eval(expr, start, true).unwrap_or(0)).collect();
+    for el in p.ast.instructions.iter_mut() {
+        for op in el.operands_mut() {
+            if let Operand::Expr(i) = op {
+                *op = Operand::Imm(values[*i]);
+            }
+        }
+    }
+    for (address, i) in p.dw_exprs.iter() {
+        p.ast.memory[*address] = values[*i];
+    }
+
+    // done at the end so the FLOAT and BITS headers can go anywhere
+    if let Some(tok) = p.float_tok.clone() {
+        if !p.ast.headers.float {
+            p.err.error(&tok, ErrorKind::FloatNotEnabled);
+        } else if !float::supported_width(p.ast.headers.bits) {
+            p.err.error(&tok, ErrorKind::FloatWidth(p.ast.headers.bits));
+        }
+    }
 
     let ms = p.ast.memory.len();
     for el in p.ast.instructions.iter_mut() {
-        *el = match el {
-            Inst::ADD(d, a, b) => Inst::ADD(d.clone().transform_mem(ms), a.clone().transform_mem(ms), b.clone().transform_mem(ms)),
-            Inst::RSH(d, a) => Inst::RSH(d.clone().transform_mem(ms), a.clone().transform_mem(ms)),
-            Inst::LOD(d, a) => Inst::LOD(d.clone().transform_mem(ms), a.clone().transform_mem(ms)),
-            Inst::STR(d, a) => Inst::STR(d.clone().transform_mem(ms), a.clone().transform_mem(ms)),
-            Inst::BGE(d, a, b) => Inst::BGE(d.clone().transform_mem(ms), a.clone().transform_mem(ms), b.clone().transform_mem(ms)),
-            Inst::NOR(d, a, b) => Inst::NOR(d.clone().transform_mem(ms), a.clone().transform_mem(ms), b.clone().transform_mem(ms)),
-            Inst::MOV(d, a) => Inst::MOV(d.clone().transform_mem(ms), a.clone().transform_mem(ms)),
-            Inst::INC(d, a) => Inst::INC(d.clone().transform_mem(ms), a.clone().transform_mem(ms)),
-            Inst::DEC(d, a) => Inst::DEC(d.clone().transform_mem(ms), a.clone().transform_mem(ms)),
-            Inst::OUT(d, a) => Inst::OUT(d.clone().transform_mem(ms), a.clone().transform_mem(ms)),
-            Inst::IN(d, a) => Inst::IN(d.clone().transform_mem(ms), a.clone().transform_mem(ms)),
-
-            Inst::PSH(a) => Inst::PSH(a.clone().transform_mem(ms)),
-            Inst::POP(d) => Inst::POP(d.clone().transform_mem(ms)),
-            Inst::JMP(d) => Inst::JMP(d.clone().transform_mem(ms)),
-            Inst::SUB(d, a, b) => Inst::SUB(d.clone().transform_mem(ms), a.clone().transform_mem(ms), b.clone().transform_mem(ms)),
-            Inst::LSH(d, a) => Inst::LSH(d.clone().transform_mem(ms), a.clone().transform_mem(ms)),
-            Inst::NEG(d, a) => Inst::NEG(d.clone().transform_mem(ms), a.clone().transform_mem(ms)),
-            Inst::AND(d, a, b) => Inst::AND(d.clone().transform_mem(ms), a.clone().transform_mem(ms), b.clone().transform_mem(ms)),
-            Inst::OR(d, a, b) => Inst::OR(d.clone().transform_mem(ms), a.clone().transform_mem(ms), b.clone().transform_mem(ms)),
-            Inst::NOT(d, a) => Inst::NOT(d.clone().transform_mem(ms), a.clone().transform_mem(ms)),
-            Inst::NAND(d, a, b) => Inst::NAND(d.clone().transform_mem(ms), a.clone().transform_mem(ms), b.clone().transform_mem(ms)),
-            Inst::CPY(d, a) => Inst::CPY(d.clone().transform_mem(ms), a.clone().transform_mem(ms)),
-            Inst::MLT(d, a, b) => Inst::MLT(d.clone().transform_mem(ms), a.clone().transform_mem(ms), b.clone().transform_mem(ms)),
-            Inst::DIV(d, a, b) => Inst::DIV(d.clone().transform_mem(ms), a.clone().transform_mem(ms), b.clone().transform_mem(ms)),
-            Inst::MOD(d, a, b) => Inst::MOD(d.clone().transform_mem(ms), a.clone().transform_mem(ms), b.clone().transform_mem(ms)),
-            Inst::ABS(d, a) => Inst::ABS(d.clone().transform_mem(ms), a.clone().transform_mem(ms)),
-            Inst::LLOD(d, a, b) => Inst::LLOD(d.clone().transform_mem(ms), a.clone().transform_mem(ms), b.clone().transform_mem(ms)),
-            Inst::LSTR(d, a, b) => Inst::LSTR(d.clone().transform_mem(ms), a.clone().transform_mem(ms), b.clone().transform_mem(ms)),
-            Inst::SDIV(d, a, b) => Inst::SDIV(d.clone().transform_mem(ms), a.clone().transform_mem(ms), b.clone().transform_mem(ms)),
-            Inst::SETE(d, a, b) => Inst::SETE(d.clone().transform_mem(ms), a.clone().transform_mem(ms), b.clone().transform_mem(ms)),
-            Inst::SETNE(d, a, b) => Inst::SETNE(d.clone().transform_mem(ms), a.clone().transform_mem(ms), b.clone().transform_mem(ms)),
-            Inst::SETG(d, a, b) => Inst::SETG(d.clone().transform_mem(ms), a.clone().transform_mem(ms), b.clone().transform_mem(ms)),
-            Inst::SETGE(d, a, b) => Inst::SETGE(d.clone().transform_mem(ms), a.clone().transform_mem(ms), b.clone().transform_mem(ms)),
-            Inst::SETL(d, a, b) => Inst::SETL(d.clone().transform_mem(ms), a.clone().transform_mem(ms), b.clone().transform_mem(ms)),
-            Inst::SETLE(d, a, b) => Inst::SETLE(d.clone().transform_mem(ms), a.clone().transform_mem(ms), b.clone().transform_mem(ms)),
-            Inst::XOR(d, a, b) => Inst::XOR(d.clone().transform_mem(ms), a.clone().transform_mem(ms), b.clone().transform_mem(ms)),
-            Inst::XNOR(d, a, b) => Inst::XNOR(d.clone().transform_mem(ms), a.clone().transform_mem(ms), b.clone().transform_mem(ms)),
-            Inst::BNE(d, a, b) => Inst::BNE(d.clone().transform_mem(ms), a.clone().transform_mem(ms), b.clone().transform_mem(ms)),
-            Inst::BRE(d, a, b) => Inst::BRE(d.clone().transform_mem(ms), a.clone().transform_mem(ms), b.clone().transform_mem(ms)),
-            Inst::SSETG(d, a, b) => Inst::SSETG(d.clone().transform_mem(ms), a.clone().transform_mem(ms), b.clone().transform_mem(ms)),
-            Inst::SSETGE(d, a, b) => Inst::SSETGE(d.clone().transform_mem(ms), a.clone().transform_mem(ms), b.clone().transform_mem(ms)),
-            Inst::SSETL(d, a, b) => Inst::SSETL(d.clone().transform_mem(ms), a.clone().transform_mem(ms), b.clone().transform_mem(ms)),
-            Inst::SSETLE(d, a, b) => Inst::SSETLE(d.clone().transform_mem(ms), a.clone().transform_mem(ms), b.clone().transform_mem(ms)),
-            Inst::BRL(d, a, b) => Inst::BRL(d.clone().transform_mem(ms), a.clone().transform_mem(ms), b.clone().transform_mem(ms)),
-            Inst::BRG(d, a, b) => Inst::BRG(d.clone().transform_mem(ms), a.clone().transform_mem(ms), b.clone().transform_mem(ms)),
-            Inst::BLE(d, a, b) => Inst::BLE(d.clone().transform_mem(ms), a.clone().transform_mem(ms), b.clone().transform_mem(ms)),
-            Inst::BRZ(d, a) => Inst::BRZ(d.clone().transform_mem(ms), a.clone().transform_mem(ms)),
-            Inst::BNZ(d, a) => Inst::BNZ(d.clone().transform_mem(ms), a.clone().transform_mem(ms)),
-            Inst::SETC(d, a, b) => Inst::SETG(d.clone().transform_mem(ms), a.clone().transform_mem(ms), b.clone().transform_mem(ms)),
-            Inst::SETNC(d, a, b) => Inst::SETGE(d.clone().transform_mem(ms), a.clone().transform_mem(ms), b.clone().transform_mem(ms)),
-            Inst::BRC(d, a, b) => Inst::BRC(d.clone().transform_mem(ms), a.clone().transform_mem(ms), b.clone().transform_mem(ms)),
-            Inst::BNC(d, a, b) => Inst::BNC(d.clone().transform_mem(ms), a.clone().transform_mem(ms), b.clone().transform_mem(ms)),
-            Inst::SBRL(d, a, b) => Inst::SBRL(d.clone().transform_mem(ms), a.clone().transform_mem(ms), b.clone().transform_mem(ms)),
-            Inst::SBRG(d, a, b) => Inst::SBRG(d.clone().transform_mem(ms), a.clone().transform_mem(ms), b.clone().transform_mem(ms)),
-            Inst::SBLE(d, a, b) => Inst::SBLE(d.clone().transform_mem(ms), a.clone().transform_mem(ms), b.clone().transform_mem(ms)),
-            Inst::SBGE(d, a, b) => Inst::SBGE(d.clone().transform_mem(ms), a.clone().transform_mem(ms), b.clone().transform_mem(ms)),
-            Inst::BOD(d, a) => Inst::BOD(d.clone().transform_mem(ms), a.clone().transform_mem(ms)),
-            Inst::BEV(d, a) => Inst::BEV(d.clone().transform_mem(ms), a.clone().transform_mem(ms)),
-            Inst::BRP(d, a) => Inst::BRP(d.clone().transform_mem(ms), a.clone().transform_mem(ms)),
-            Inst::BRN(d, a) => Inst::BRN(d.clone().transform_mem(ms), a.clone().transform_mem(ms)),
-            Inst::BSL(d, a, b) => Inst::BSR(d.clone().transform_mem(ms), a.clone().transform_mem(ms), b.clone().transform_mem(ms)),
-            Inst::BSR(d, a, b) => Inst::BSL(d.clone().transform_mem(ms), a.clone().transform_mem(ms), b.clone().transform_mem(ms)),
-            Inst::SRS(d, a) => Inst::SRS(d.clone().transform_mem(ms), a.clone().transform_mem(ms)),
-            Inst::BSS(d, a, b) => Inst::BSS(d.clone().transform_mem(ms), a.clone().transform_mem(ms), b.clone().transform_mem(ms)),
-            Inst::CAL(d) => Inst::CAL(d.clone().transform_mem(ms)),
-            _ => continue
-        };
+        for (kind, op) in el.operand_kinds().iter().zip(el.operands_mut()) {
+            // these get read and written through when running, so they have to stay addresses
+            match (kind, &op) {
+                (OperandKind::Loc, Operand::Mem(v)) => *op = Operand::Mem(v + ms as u64),
+                _ => op.transform_mem(ms),
+            }
+        }
     }
 
     for i in dw_mem_repl.iter() {
@@ -387,42 +373,154 @@ pub fn gen_ast<'a>(toks: Vec<UToken<'a>>, src: Rc<str>) -> Parser<'a> {
     p
 }
 
+// how DW lays out strings, DWZ puts a 0 after them and DWP packs as many chars into a word as fit
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Strings {
+    Plain, Zero, Packed,
+}
+
+fn is_dw(name: &str) -> bool {
+    matches!(name.to_lowercase().as_str(), "dw" | "dwz" | "dwp")
+}
+
+// a line in a DW list starting like this is code, so the list is missing its ]
+fn starts_statement(tok: &UToken) -> bool {
+    tok.kind == Kind::Name && (is_dw(tok.str)
+        || INSTRUCTIONS.iter().any(|i| i.mnemonic.eq_ignore_ascii_case(tok.str)) || custom::find(tok.str).is_some()
+        || matches!(tok.str.to_lowercase().as_str(), "bits" | "minreg" | "minheap" | "minstack" | "float"))
+}
+
 fn inst<'a>(inst: Inst, p: &mut Parser<'a>) {
     p.ast.instructions.push(inst);
     p.assert_done();
 }
 
 impl <'a> Parser<'a> {
-    fn parse_dw(&mut self, dw_lab_repl: &mut HashMap<String, Vec<u64>>, dw_mem_repl: &mut Vec<u64>) -> Vec<u64> {
+    // a value or a [ ] list of them, lists can be nested and repeated with [ ]*n. returns false if a
+    // list never ended, the buffer is then left on the line break before the code that came after it
+    fn dw_entry(&mut self, strings: Strings, dw_lab_repl: &mut HashMap<String, Vec<u64>>, dw_mem_repl: &mut Vec<u64>) -> bool {
+        let open = self.buf.current();
+        if open.kind != Kind::LSquare {
+            let mut a = self.parse_dw(strings, dw_lab_repl, dw_mem_repl);
+            self.ast.memory.append(&mut a);
+            return true;
+        }
+
+        let start = self.ast.memory.len();
+        loop {
+            match self.buf.next().kind {
+                Kind::RSquare => break,
+                Kind::EOF => {
+                    self.err.error(&open, ErrorKind::DWNoEnding);
+                    return false;
+                },
+                Kind::LF if starts_statement(&self.buf.peek()) => {
+                    self.err.error(&open, ErrorKind::DWNoEnding);
+                    return false;
+                },
+                Kind::LF => {},
+                _ => if !self.dw_entry(strings, dw_lab_repl, dw_mem_repl) {
+                    return false;
+                },
+            }
+        }
+
+        let star = self.buf.after(self.buf.index);
+        if self.buf.toks[star].kind == Kind::Star {
+            let star = self.buf.next();
+            if matches!(self.buf.toks[self.buf.after(self.buf.index)].kind, Kind::LF | Kind::EOF) {
+                self.err.error(&star, ErrorKind::InvalidExpression);
+                return true;
+            }
+            self.buf.advance();
+            let toks = self.expression_tokens();
+            if let Some(count) = self.value_now(&toks) {
+                self.repeat_dw(start, count, &toks[0], dw_lab_repl, dw_mem_repl);
+            }
+        }
+        true
+    }
+
+    // the list from start is there once already, the words waiting for labels and the end of DW
+    // get copied along with it
+    fn repeat_dw(&mut self, start: usize, count: i128, tok: &UToken<'a>, dw_lab_repl: &mut HashMap<String, Vec<u64>>, dw_mem_repl: &mut Vec<u64>) {
+        if count < 0 {
+            self.err.error(tok, ErrorKind::InvalidRepeat);
+            return;
+        }
+        // checked here too so `[0]*99999999999` doesnt run out of memory before check_limits sees it
+        let len = self.ast.memory.len() - start;
+        let size = (start as i128).saturating_add((len as i128).saturating_mul(count));
+        let limit = self.limits.max_heap;
+        if size > limit as i128 {
+            self.err.error(tok, ErrorKind::ResourceLimit { what: "DW", value: size.min(u64::MAX as i128) as u64, limit, unit: "words" });
+            return;
+        }
+
+        let count = count as usize;
+        let data = self.ast.memory.split_off(start);
+        let exprs: Vec<_> = self.dw_exprs.iter().filter(|(a, _)| *a >= start).cloned().collect();
+        self.dw_exprs.retain(|(a, _)| *a < start);
+        let repeat = |addrs: &mut Vec<u64>| {
+            let copies: Vec<u64> = addrs.iter().filter(|a| **a >= start as u64).cloned().collect();
+            addrs.retain(|a| *a < start as u64);
+            for k in 0..count {
+                addrs.extend(copies.iter().map(|a| a + (k * len) as u64));
+            }
+        };
+        dw_lab_repl.values_mut().for_each(&repeat);
+        repeat(dw_mem_repl);
+        for k in 0..count {
+            self.ast.memory.extend_from_slice(&data);
+            self.dw_exprs.extend(exprs.iter().map(|(a, i)| (a + k * len, *i)));
+        }
+    }
+
+    fn parse_dw(&mut self, strings: Strings, dw_lab_repl: &mut HashMap<String, Vec<u64>>, dw_mem_repl: &mut Vec<u64>) -> Vec<u64> {
         let a = self.buf.current();
+        if self.at_expression() || matches!(a.kind, Kind::Name | Kind::Macro | Kind::Char | Kind::Relative(_)) {
+            let toks = self.expression_tokens();
+            return match self.expression(&toks) {
+                Some(Operand::Expr(i)) => {
+                    self.dw_exprs.push((self.ast.memory.len(), i));
+                    vec![0]
+                },
+                Some(Operand::Imm(v)) => vec![v],
+                _ => vec![0],
+            };
+        }
         match a.kind {
-            Kind::Int(v) => vec![v as u64],
+            Kind::Int(_) => vec![self.literal(&a)],
+            Kind::BadInt(err) => {
+                self.err.error(&a, ErrorKind::InvalidNumber(err));
+                vec![0]
+            },
+            Kind::Float(v) => {
+                self.use_float();
+                vec![float::from_float(f64::from_bits(v), self.ast.headers.bits)]
+            },
             Kind::Memory(v) => {
-                dw_mem_repl.push(v);
+                dw_mem_repl.push(self.ast.memory.len() as u64);
                 vec![v]
             },
             Kind::Label => {
-                self.buf.advance();
-                match self.ast.labels.get(a.str) {
+                let name = self.label_key(&a);
+                self.used_labels.insert(name.clone());
+                match self.ast.labels.get(&name) {
                     Some(Label::Defined(v)) => vec![*v as u64],
                     Some(Label::Undefined(_)) => {
-                        dw_lab_repl.get_mut(a.str).unwrap().push(self.ast.memory.len() as u64);
+                        dw_lab_repl.entry(name).or_default().push(self.ast.memory.len() as u64);
                         vec![0]
                     },
                     _ => {
-                        self.ast.labels.insert(a.str.to_string(), Label::Undefined(
+                        self.ast.labels.insert(name.clone(), Label::Undefined(
                             UndefinedLabel { references: vec![], referenced_tokens: vec![] }
                         ));
-                        dw_lab_repl.insert(a.str.to_string(), vec![self.ast.memory.len() as u64]);
+                        dw_lab_repl.insert(name, vec![self.ast.memory.len() as u64]);
                         vec![0]
                     },
                 }
             },
-            Kind::Macro => {
-                let a = self.parse_macro(a.str).unwrap();
-                self.buf.advance();
-                vec![a]
-            },
             Kind::String => {
                 let mut text = String::new();
                 while self.buf.has_next() {match self.buf.next().kind {
@@ -434,7 +532,19 @@ impl <'a> Parser<'a> {
                         break;
                     }
                 }}
-                text.chars().map(|a| a as u64).collect()
+                match strings {
+                    Strings::Plain => text.chars().map(|a| a as u64).collect(),
+                    Strings::Zero => text.chars().map(|a| a as u64).chain([0]).collect(),
+                    Strings::Packed => self.pack(&text, &a),
+                }
+            },
+            Kind::LF | Kind::EOF => {
+                self.err.error(&a, ErrorKind::NotEnoughOperands);
+                vec![]
+            },
+            Kind::RSquare => {
+                self.err.error(&a, ErrorKind::NoMatchingSquare);
+                vec![]
             },
             _ => {
                 self.err.error(&a, ErrorKind::YoMamma);
@@ -442,6 +552,19 @@ impl <'a> Parser<'a> {
             },
         }
     }
+    // 8 bit chars, as many as fit in a word at the BITS so far, the first one in the highest byte
+    fn pack(&mut self, text: &str, tok: &UToken<'a>) -> Vec<u64> {
+        let per_word = (self.ast.headers.bits.clamp(8, 64) / 8) as usize;
+        if text.chars().any(|c| c as u32 > 0xff) {
+            self.err.error(tok, ErrorKind::Overflow(8));
+        }
+        let chars: Vec<u64> = text.chars().map(|c| c as u64 & 0xff).collect();
+        chars.chunks(per_word).map(|chunk| {
+            let word = chunk.iter().fold(0, |word, c| word << 8 | c);
+            word << (8 * (per_word - chunk.len()))
+        }).collect()
+    }
+
     fn get_reg(&mut self) -> Operand {
         let (ast, op) = self.get_ast_op();
         match ast {
@@ -469,7 +592,7 @@ impl <'a> Parser<'a> {
     fn get_mem(&mut self) -> Operand {
         let (ast, op) = self.get_ast_op();
         match ast {
-            AstOp::Reg(_) | AstOp::Mem(_) | AstOp::Unknown => {},
+            AstOp::Reg(_) | AstOp::Mem(_) | AstOp::Expr | AstOp::Unknown => {},
             actual => {
                 self.err.warn(self.buf.cur(), ErrorKind::InvalidOperandType{
                     expected: "memory address", actual
@@ -481,7 +604,7 @@ impl <'a> Parser<'a> {
     fn get_jmp(&mut self) -> Operand {
         let (ast, op) = self.get_ast_op();
         match ast {
-            AstOp::Reg(_) | AstOp::Label(_) | AstOp::JumpLocation(_) | AstOp::Unknown => {},
+            AstOp::Reg(_) | AstOp::Label(_) | AstOp::JumpLocation(_) | AstOp::Expr | AstOp::Unknown => {},
             actual => {
                 self.err.warn(self.buf.cur(), ErrorKind::InvalidOperandType{
                     expected: "jump target", actual
@@ -506,10 +629,22 @@ impl <'a> Parser<'a> {
     fn get_op(&mut self) -> Operand {
         self.get_ast_op().1
     }
+    fn get_operand(&mut self, kind: OperandKind) -> Operand {
+        match kind {
+            OperandKind::Reg => self.get_reg(),
+            OperandKind::Op => self.get_op(),
+            OperandKind::Imm => self.get_imm(),
+            OperandKind::Mem => self.get_mem(),
+            OperandKind::Jmp => self.get_jmp(),
+            OperandKind::Port => self.get_port(),
+            OperandKind::Loc => self.get_op(),
+        }
+    }
     fn trans_op(&mut self, op: &AstOp) -> Operand {
         match op {
-            AstOp::Unknown => Operand::Imm(0),
+            AstOp::Unknown | AstOp::Expr => Operand::Imm(0),
             AstOp::Int(v) => Operand::Imm(*v),
+            AstOp::Float(v) => Operand::Imm(float::from_float(*v, self.ast.headers.bits)),
             AstOp::Reg(v) => Operand::Reg(*v),
             AstOp::Mem(v) => Operand::Mem(*v),
             AstOp::Port(v) => Operand::Imm(*v),
@@ -522,18 +657,31 @@ impl <'a> Parser<'a> {
         }
     }
     fn get_ast_op(&mut self) -> (AstOp, Operand){
+        // an operand before this one was missing already, that was reported once
+        if matches!(self.buf.current().kind, Kind::LF | Kind::EOF) {
+            return (AstOp::Unknown, Operand::Imm(0));
+        }
         self.buf.advance();
+        if self.at_expression() {
+            let toks = self.expression_tokens();
+            return self.expression_op(&toks);
+        }
         let current = self.buf.current();
         let ast = match current.kind {
-            Kind::Reg(v) => AstOp::Reg(v),
-            Kind::Int(v) => AstOp::Int(v as u64),
+            Kind::Reg(v) => self.use_reg(&current, v),
+            Kind::Int(_) => AstOp::Int(self.literal(&current)),
+            Kind::BadInt(err) => {
+                self.err.error(&current, ErrorKind::InvalidNumber(err));
+                AstOp::Unknown
+            },
+            Kind::Float(v) => {self.use_float(); AstOp::Float(f64::from_bits(v))},
             Kind::Memory(m) => AstOp::Mem(m),
             Kind::PortNum(v) => AstOp::Port(v),
             Kind::Port => {
                 match IOPort::from_str(&current.str[1..].to_uppercase()) {
                     Ok(port) => {AstOp::Port(port as u64)},
                     Err(_err) => {
-                        self.err.error(&self.buf.current(), ErrorKind::UnknownPort);
+                        self.unknown(&current, ErrorKind::UnknownPort);
                         AstOp::Port(0)
                     }
                 }
@@ -582,15 +730,8 @@ impl <'a> Parser<'a> {
                 self.err.error(&self.buf.current(), ErrorKind::NotEnoughOperands);
                 AstOp::Unknown
             }
-            Kind::Macro => {
-                match self.parse_macro(self.buf.current().str) {
-                    Some(v) => AstOp::Int(v),
-                    None => AstOp::Unknown
-                }
-            }
-            Kind::Name => {
-                self.get_ast_op_from_token(self.macros[current.str].clone()).0
-            }
+            Kind::Macro => return self.expression_op(&[current]),
+            Kind::Name => return self.define_op(&current),
             _ => {
                 self.err.error(&self.buf.current(), ErrorKind::InvalidOperand);
                 AstOp::Unknown
@@ -602,15 +743,20 @@ impl <'a> Parser<'a> {
 
     fn get_ast_op_from_token(&mut self, current: UToken<'a>) -> (AstOp, Operand) {
         let ast = match current.kind {
-            Kind::Reg(v) => AstOp::Reg(v),
-            Kind::Int(v) => AstOp::Int(v as u64),
+            Kind::Reg(v) => self.use_reg(&current, v),
+            Kind::Int(_) => AstOp::Int(self.literal(&current)),
+            Kind::BadInt(err) => {
+                self.err.error(&current, ErrorKind::InvalidNumber(err));
+                AstOp::Unknown
+            },
+            Kind::Float(v) => {self.use_float(); AstOp::Float(f64::from_bits(v))},
             Kind::Memory(m) => AstOp::Mem(m),
             Kind::PortNum(v) => AstOp::Port(v),
             Kind::Port => {
                 match IOPort::from_str(&current.str[1..].to_uppercase()) {
                     Ok(port) => {AstOp::Port(port as u64)},
                     Err(_err) => {
-                        self.err.error(&self.buf.current(), ErrorKind::UnknownPort);
+                        self.unknown(&current, ErrorKind::UnknownPort);
                         AstOp::Port(0)
                     }
                 }
@@ -659,15 +805,8 @@ impl <'a> Parser<'a> {
                 self.err.error(&self.buf.current(), ErrorKind::NotEnoughOperands);
                 AstOp::Unknown
             }
-            Kind::Macro => {
-                match self.parse_macro(current.str) {
-                    Some(v) => AstOp::Int(v),
-                    None => AstOp::Unknown
-                }
-            }
-            Kind::Name => {
-                self.get_ast_op_from_token(self.macros[current.str].clone()).0
-            }
+            Kind::Macro => return self.expression_op(&[current]),
+            Kind::Name => return self.define_op(&current),
             _ => {
                 self.err.error(&self.buf.current(), ErrorKind::InvalidOperand);
                 AstOp::Unknown
@@ -677,27 +816,384 @@ impl <'a> Parser<'a> {
         (ast, op)
     }
 
-    fn parse_macro(&self, m: &str) -> Option<u64> {
-        match m.to_lowercase().as_str() {
-            "@max" => Some(u64::MAX),
-            "@msb" => Some(1 << 63),
-            "@smax" => Some(i64::MAX as u64),
-            "@bits" => Some(self.ast.headers.bits),
-            "@minheap" => Some(self.ast.headers.minheap),
-            _ => None
+    // a number at the BITS so far, wider ones get a warning and lose their top bits
+    fn literal(&mut self, tok: &UToken<'a>) -> u64 {
+        let (value, bits) = (lexer::int_value(tok), self.ast.headers.bits);
+        match expr::fit(value, bits) {
+            Some(value) => value,
+            None => {
+                self.err.warn(tok, ErrorKind::Truncated(bits));
+                // 1 << 64 would overflow, at 64 bits theres nothing to mask anyway
+                match bits {
+                    64.. => value as u64,
+                    _ => value as u64 & ((1 << bits) - 1),
+                }
+            },
         }
     }
 
-    fn assert_done(&mut self) {
-        self.buf.advance();
+    fn use_reg(&mut self, tok: &UToken<'a>, reg: u64) -> AstOp {
+        if reg != PC && reg != SP && self.max_reg.as_ref().is_none_or(|(max, _)| reg > *max) {
+            self.max_reg = Some((reg, tok.clone()));
+        }
+        AstOp::Reg(reg)
+    }
+
+    // floats only make sense with the FLOAT header, remember where they were first used to complain later
+    fn use_float(&mut self) {
+        if self.float_tok.is_none() {
+            self.float_tok = Some(self.buf.current());
+        }
+    }
+
+    pub fn check_limits(&mut self) {
+        let limits = self.limits;
+        let heap = (self.ast.memory.len() as u64).saturating_add(self.ast.headers.minheap);
+        let checks = [
+            ("minheap", "MINHEAP", heap, limits.max_heap, "words"),
+            ("minstack", "MINSTACK", self.ast.headers.minstack, limits.max_stack, "words"),
+            ("minreg", "MINREG", self.ast.headers.minreg, limits.max_regs, "registers"),
+        ];
+        for (header, what, value, limit, unit) in checks {
+            if value <= limit {continue;}
+            // the default headers have no token, point at the end of the file instead
+            let tok = self.header_toks.get(header).cloned().unwrap_or_else(|| self.buf.cur().clone());
+            self.err.error(&tok, ErrorKind::ResourceLimit { what, value, limit, unit });
+        }
+
+        // only programs that draw need the screen, they get pointed at the first place they do
+        let draws = self.ast.instructions.iter().position(|inst| matches!(inst,
+            Inst::OUT(Operand::Imm(port), _) if matches!(IOPort::from_u64(*port), Some(IOPort::X | IOPort::Y | IOPort::COLOR))
+        ));
+        if let Some(pc) = draws {
+            let checks = [
+                ("Screen width", screen::WIDTH, limits.max_screen_width),
+                ("Screen height", screen::HEIGHT, limits.max_screen_height),
+            ];
+            for (what, value, limit) in checks {
+                if value <= limit {continue;}
+                let tok = self.inst_toks[pc].clone();
+                self.err.error(&tok, ErrorKind::ResourceLimit { what, value: value as u64, limit: limit as u64, unit: "pixels" });
+            }
+        }
+    }
+
+    // the tokens up to the end of the line, the line break is left for the main loop
+    fn rest_of_line(&mut self) -> Vec<UToken<'a>> {
+        let mut toks = Vec::new();
+        while !matches!(self.buf.next().kind, Kind::LF | Kind::EOF) {
+            toks.push(self.buf.current());
+        }
+        toks
+    }
+
+    // `@if a` is true when a isnt 0, `@if a == b` compares them, both sides can be expressions
+    fn condition(&mut self, cond: &[UToken<'a>]) -> Option<bool> {
+        let split = cond.iter().position(|t| matches!(t.kind, Kind::Eq | Kind::NE | Kind::LT | Kind::LE | Kind::GT | Kind::GE));
+        let Some(split) = split else {
+            return Some(self.value_now(cond)? != 0);
+        };
+        let (a, b) = (self.value_now(&cond[..split])?, self.value_now(&cond[split + 1..])?);
+        Some(match cond[split].kind {
+            Kind::Eq => a == b,
+            Kind::NE => a != b,
+            Kind::LT => a < b,
+            Kind::LE => a <= b,
+            Kind::GT => a > b,
+            _ => a >= b,
+        })
+    }
+
+    // for conditions and DW repeat counts, they go by the headers so far and cant wait for the rest
+    fn value_now(&mut self, toks: &[UToken<'a>]) -> Option<i128> {
+        if toks.is_empty() {
+            return None;
+        }
+        let value = expr::parse(toks, self, 0).and_then(|expr| expr.eval(&|name| self.symbol_value(name, true)));
+        match value {
+            Ok(value) => Some(value),
+            Err((tok, ErrorKind::UndefinedLabel)) => {
+                let key = self.label_key(&tok);
+                self.undefined_label(&tok, &key);
+                Some(0)
+            },
+            Err((tok, kind)) => {
+                self.unknown(&tok, kind);
+                Some(0)
+            },
+        }
+    }
+
+    // an operand is an expression when it starts with a ( or -, or an operator comes after its first value
+    fn at_expression(&self) -> bool {
+        let index = self.buf.index;
         match self.buf.current().kind {
-            Kind::LF |  Kind::EOF => {},
+            Kind::LParen | Kind::Minus => true,
+            // 'a' is three tokens
+            Kind::Char => self.buf.is_operator(self.buf.after(index + 2)),
+            _ => self.buf.is_operator(self.buf.after(index)),
+        }
+    }
+
+    // takes the tokens of an expression, leaving the buffer on its last one like any other operand
+    fn expression_tokens(&mut self) -> Vec<UToken<'a>> {
+        let mut toks = Vec::new();
+        let mut depth = 0;
+        let mut in_char = false;
+        loop {
+            let tok = self.buf.current();
+            match tok.kind {
+                Kind::LParen => depth += 1,
+                Kind::RParen => depth -= 1,
+                Kind::Char => in_char = !in_char,
+                _ => {},
+            }
+            // whitespace only ends it outside of parentheses and when no operator is waiting for a value
+            let open = depth > 0 || in_char || expr::is_binary(tok.kind);
+            toks.push(tok);
+            let next = self.buf.after(self.buf.index);
+            if matches!(self.buf.toks[next].kind, Kind::LF | Kind::EOF) || !(open || self.buf.is_operator(next)) {
+                return toks;
+            }
+            self.buf.advance();
+        }
+    }
+
+    // an expression as an operand, ones with labels that arent defined yet are filled in at the end
+    fn expression(&mut self, toks: &[UToken<'a>]) -> Option<Operand> {
+        // errors about the value as a whole point at all of it
+        let mut whole = toks[0].clone();
+        if let Some(span) = self.files.between(toks[0].str, toks[toks.len() - 1].str) {
+            whole.str = span;
+        }
+        let expr = match expr::parse(toks, self, 0) {
+            Ok(expr) => expr,
+            Err((tok, kind)) => {
+                self.unknown(&tok, kind);
+                return None;
+            },
+        };
+        match self.eval(&expr, &whole, false) {
+            Some(value) => Some(Operand::Imm(value)),
+            None => {
+                self.exprs.push((expr, whole));
+                Some(Operand::Expr(self.exprs.len() - 1))
+            },
+        }
+    }
+
+    fn expression_op(&mut self, toks: &[UToken<'a>]) -> (AstOp, Operand) {
+        match self.expression(toks) {
+            Some(op) => (AstOp::Expr, op),
+            None => (AstOp::Unknown, Operand::Imm(0)),
+        }
+    }
+
+    // the value of a label or constant in an expression. constants wait for the end like labels
+    // that arent defined yet, the headers can come after them
+    fn symbol_value(&self, name: &str, at_end: bool) -> Option<u64> {
+        match self.ast.labels.get(name) {
+            _ if name.starts_with('@') => self.ast.headers.constant(name).filter(|_| at_end),
+            Some(Label::Defined(v)) => Some(*v as u64),
+            _ => None,
+        }
+    }
+
+    // ..name is short for .global.name, so every subroutine can have its own ..loop. before the first
+    // global label they stay ..name, which is only ever a local label so it cant clash with .name. labels
+    // from a macro expansion get their scope tacked on so every expansion has its own, labels cant have
+    // spaces in them so this never clashes with a real one
+    fn label_key(&self, tok: &UToken) -> String {
+        let name = match tok.str.strip_prefix('.') {
+            Some(local) if local.starts_with('.') && !self.global.is_empty() => format!("{}{}", self.global, local),
+            _ => tok.str.to_string(),
+        };
+        match tok.scope {
+            0 => name,
+            scope => format!("{} {}", name, scope),
+        }
+    }
+
+    // reports a name that isnt defined with the closest one that is, if theres one close enough
+    fn unknown(&mut self, tok: &UToken<'a>, kind: ErrorKind<'a>) {
+        let code = kind.code().0;
+        let names: fn() -> Vec<String> = match kind {
+            ErrorKind::UnknownInstruction => || INSTRUCTIONS.iter().map(|i| i.mnemonic).chain(custom::mnemonics()).map(str::to_owned).collect(),
+            ErrorKind::UnknownPort => || IOPort::VARIANTS.iter().map(|p| format!("%{}", p)).collect(),
+            ErrorKind::UnknownConstant => || CONSTANTS.iter().map(|c| c.to_uppercase()).collect(),
+            ErrorKind::UndefinedName => {
+                // macros and @reg names come and go, so these cant be kept like the others
+                let names: Vec<&str> = self.macros.keys().chain(self.regs.keys()).copied().collect();
+                self.err.error(tok, kind);
+                self.err.suggest(tok.str, &names);
+                return;
+            },
             _ => {
-                self.err.error(&self.buf.current(), ErrorKind::ToManyOperands);
-                while match self.buf.current().kind {Kind::LF |  Kind::EOF => false, _ => true} {
-                    self.buf.advance()
-                } 
+                self.err.error(tok, kind);
+                return;
+            },
+        };
+        self.err.error(tok, kind);
+        self.hint((tok.str, code.to_owned()), |_| closest(tok.str, &names()).map(str::to_owned));
+    }
+
+    // labels are only known to be undefined at the end, when the global label has moved on, so
+    // this takes the full name the reference got back then
+    fn undefined_label(&mut self, tok: &UToken<'a>, key: &str) {
+        self.err.error(tok, ErrorKind::UndefinedLabel);
+        self.hint((tok.str, key.to_owned()), |p| p.label_hint(tok, key));
+    }
+
+    // a did you mean note for the last error. macros repeat the same mistake a lot and finding the
+    // closest name means looking at every one, so it only happens once for each name and list
+    fn hint(&mut self, memo: (&'a str, String), find: impl FnOnce(&Self) -> Option<String>) {
+        if !self.hints.contains_key(&memo) {
+            let hint = find(self);
+            self.hints.insert(memo.clone(), hint);
+        }
+        if let Some(hint) = &self.hints[&memo] {
+            self.err.did_you_mean(hint);
+        }
+    }
+
+    fn label_hint(&self, tok: &UToken<'a>, key: &str) -> Option<String> {
+        let suffix = match tok.scope {
+            0 => String::new(),
+            scope => format!(" {}", scope),
+        };
+        let key = key.strip_suffix(suffix.as_str()).unwrap_or(key);
+        // ..name is the global label and .name
+        let global = tok.str.strip_prefix("..").and_then(|_| key.strip_suffix(&tok.str[1..]));
+        let mut candidates = Vec::new();
+        for (name, label) in self.ast.labels.iter() {
+            if !matches!(label, Label::Defined(_)) {continue;}
+            // labels from other macro expansions cant be reached from here
+            let Some(name) = name.strip_suffix(suffix.as_str()).filter(|n| !n.contains(' ')) else {continue};
+            if let Some(local) = global.and_then(|g| name.strip_prefix(g)).filter(|l| l.starts_with('.')) {
+                candidates.push(format!(".{}", local));
             }
+            candidates.push(name.to_owned());
+        }
+        closest(tok.str, &candidates).map(str::to_owned)
+    }
+
+    // the value of an expression at BITS, None if it has to wait for the end and this isnt it
+    fn eval(&mut self, expr: &Expr<'a>, start: &UToken<'a>, at_end: bool) -> Option<u64> {
+        let value = expr.eval(&|name| self.symbol_value(name, at_end));
+        match value {
+            Ok(value) => match expr::fit(value, self.ast.headers.bits) {
+                Some(value) => Some(value),
+                None => {
+                    self.err.error(start, ErrorKind::Overflow(self.ast.headers.bits));
+                    Some(0)
+                },
+            },
+            Err((tok, _)) if !at_end && matches!(tok.kind, Kind::Label | Kind::Macro) => None,
+            Err((tok, ErrorKind::UndefinedLabel)) => {
+                let key = expr.label_name(&tok).unwrap_or(tok.str).to_owned();
+                self.undefined_label(&tok, &key);
+                Some(0)
+            },
+            Err((tok, kind)) => {
+                self.unknown(&tok, kind);
+                Some(0)
+            },
+        }
+    }
+
+    // a name from @reg or @define, a @define body is either one operand or an expression
+    fn define_op(&mut self, name: &UToken<'a>) -> (AstOp, Operand) {
+        if let Some((reg, _)) = self.regs.get(name.str) {
+            return (AstOp::Reg(*reg), Operand::Reg(*reg));
+        }
+        match self.macros.get(name.str).cloned() {
+            // `@define A B` and `@define B A` would go back and forth forever
+            Some(body) if body.len() == 1 && self.defines.contains(&name.str) => {
+                self.err.error(name, ErrorKind::RecursiveDefine);
+                (AstOp::Unknown, Operand::Imm(0))
+            },
+            Some(body) if body.len() == 1 => {
+                self.defines.push(name.str);
+                let op = self.get_ast_op_from_token(body[0].clone());
+                self.defines.pop();
+                op
+            },
+            Some(body) if !body.is_empty() => self.expression_op(&body),
+            Some(_) => {
+                self.err.error(name, ErrorKind::InvalidOperand);
+                (AstOp::Unknown, Operand::Imm(0))
+            },
+            None => {
+                self.unknown(name, ErrorKind::UndefinedName);
+                (AstOp::Unknown, Operand::Imm(0))
+            },
+        }
+    }
+
+    // the preprocessor stops at every @if, @else and @endif, so whatever comes after one is only
+    // expanded once the parser gets past it and knows its assembled
+    fn expand_rest(&mut self) {
+        let start = self.buf.index;
+        let (toks, len, raw) = self.pre.expand_rest(&self.buf.toks[start..], &mut self.err);
+        self.buf.toks.splice(start..start + len, toks);
+        self.raw = start + raw;
+    }
+
+    // skips a block that isnt assembled without looking at anything in it, nested blocks go with it.
+    // stops on the @else or @endif that ends it and returns whether it was an @else
+    fn skip_block(&mut self, open: &UToken<'a>) -> bool {
+        let mut depth = 0;
+        loop {
+            self.buf.advance();
+            let tok = self.buf.current();
+            match tok.kind {
+                Kind::EOF => {
+                    self.err.error(open, ErrorKind::IfNoEnding);
+                    return false;
+                },
+                Kind::Macro => match tok.str.to_lowercase().as_str() {
+                    "@if" | "@ifdef" | "@ifndef" => depth += 1,
+                    "@else" if depth == 0 => return true,
+                    "@endif" if depth == 0 => return false,
+                    "@endif" => depth -= 1,
+                    _ => {},
+                },
+                _ => {},
+            }
+        }
+    }
+
+    fn assert_done(&mut self) {
+        // a missing operand already stopped on the end of the line
+        if !matches!(self.buf.current().kind, Kind::LF | Kind::EOF) {
+            self.buf.advance();
+        }
+        if !matches!(self.buf.current().kind, Kind::LF | Kind::EOF) {
+            self.err.error(&self.buf.current(), ErrorKind::ToManyOperands);
+            self.skip_line();
+        }
+    }
+
+    // gives up on the rest of the line after an error, the line break is left for the main loop
+    fn skip_line(&mut self) {
+        while !matches!(self.buf.current().kind, Kind::LF | Kind::EOF) {
+            self.buf.advance();
+        }
+    }
+
+    // the number after a header, BITS can have a comparison in between like `BITS >= 16`
+    fn header_value(&mut self, comparison: bool) -> Option<u64> {
+        let mut tok = self.buf.next();
+        if comparison && matches!(tok.kind, Kind::Eq | Kind::GE | Kind::LE | Kind::GT | Kind::LT) {
+            tok = self.buf.next();
+        }
+        match tok.kind {
+            Kind::Int(_) => Some(lexer::int_value(&tok) as u64),
+            _ => {
+                self.err.error(&tok, ErrorKind::ExpectedHeaderValue);
+                self.skip_line();
+                None
+            },
         }
     }
 }
@@ -714,26 +1210,50 @@ pub enum Label {
     Defined(usize),
 }
 
+impl <'a> expr::Symbols<'a> for Parser<'a> {
+    fn symbol(&mut self, tok: &UToken<'a>, depth: usize) -> Result<Expr<'a>, ExprError<'a>> {
+        match tok.kind {
+            Kind::Label => {
+                let name = self.label_key(tok);
+                self.used_labels.insert(name.clone());
+                Ok(Expr::Label(name, tok.clone()))
+            },
+            Kind::Macro if self.ast.headers.constant(tok.str).is_some() => Ok(Expr::Const(tok.clone())),
+            Kind::Macro => Err((tok.clone(), ErrorKind::UnknownConstant)),
+            Kind::Relative(v) => Ok(Expr::Value(self.ast.instructions.len() as i128 + v as i128)),
+            Kind::Name => match self.macros.get(tok.str).cloned() {
+                None => Err((tok.clone(), ErrorKind::UndefinedName)),
+                Some(_) if depth >= expr::MAX_DEPTH => Err((tok.clone(), ErrorKind::MacroTooDeep)),
+                Some(body) if body.is_empty() => Err((tok.clone(), ErrorKind::InvalidExpression)),
+                Some(body) => expr::parse(&body, self, depth + 1),
+            },
+            _ => Err((tok.clone(), ErrorKind::InvalidExpression)),
+        }
+    }
+}
+
 fn label_tok_to_operand<'a>(tok: &UToken<'a>, p: &mut Parser) -> Operand {
     if (*tok).kind != Kind::Label {return Operand::Imm(0);}
 
-    match p.ast.labels.get(tok.str) {
+    let name = p.label_key(tok);
+    p.used_labels.insert(name.clone());
+    match p.ast.labels.get(&name) {
         Some(Label::Undefined(v)) => {
             let mut a = v.clone();
             a.references         .push(p.ast.instructions.len());
             a.referenced_tokens  .push(p.buf.index);
-            p.ast.labels.insert((*tok).str.to_string(), Label::Undefined(a));
-            Operand::Label(tok.str.to_string())
+            p.ast.labels.insert(name.clone(), Label::Undefined(a));
+            Operand::Label(name)
         },
         Some(Label::Defined(v)) => Operand::Imm(*v as u64),
         None => {
-            p.ast.labels.insert((*tok).str.to_string(), Label::Undefined(
+            p.ast.labels.insert(name.clone(), Label::Undefined(
                 UndefinedLabel{
                     references: vec![p.ast.instructions.len()],
                     referenced_tokens: vec![p.buf.index]
                 }
             ));
-            Operand::Label(tok.str.to_string())
+            Operand::Label(name)
         }
     }
 }
@@ -748,19 +1268,32 @@ pub struct Program {
 }
 
 impl Program {
-    pub fn new(src: Rc<str>) -> Self {
-        Self { headers: Headers::new(), instructions: Vec::new(), labels: HashMap::new(), memory: Vec::new(), debug: DebugInfo::new(src) }
+    pub fn new(files: Rc<SourceFiles>) -> Self {
+        Self { headers: Headers::new(), instructions: Vec::new(), labels: HashMap::new(), memory: Vec::new(), debug: DebugInfo::new(files) }
     }
 }
 
 #[derive(Debug)]
 pub struct DebugInfo {
-    pub src: Rc<str>,
-    pub pc_to_line_start: Vec<usize>
+    pub files: Rc<SourceFiles>,
+    pub pc_to_line_start: Vec<usize>,
+    pub pc_to_file: Vec<usize>,
+    pub reg_names: HashMap<u64, Vec<String>>, // every @reg name a register had, for showing registers
+    pub labels: HashMap<usize, Vec<String>>, // the labels in front of every instruction, DW labels arent in here
 }
 impl DebugInfo {
-    pub fn new(src: Rc<str>) -> Self {
-        Self {src, pc_to_line_start: Vec::new()}
+    pub fn new(files: Rc<SourceFiles>) -> Self {
+        Self {files, pc_to_line_start: Vec::new(), pc_to_file: Vec::new(), reg_names: HashMap::new(), labels: HashMap::new()}
+    }
+
+    pub fn add(&mut self, span: &str) {
+        let (file, line, _, _) = self.files.locate(span).unwrap_or((0, 0, 0, 0));
+        self.pc_to_file.push(file);
+        self.pc_to_line_start.push(line);
+    }
+
+    pub fn location(&self, pc: usize) -> String {
+        self.files.describe(self.pc_to_file[pc], self.pc_to_line_start[pc])
     }
 }
 
@@ -768,6 +1301,7 @@ impl DebugInfo {
 pub enum AstOp {
     Unknown,
     Int(u64),
+    Float(f64),
     Reg(u64),
     Mem(u64),
     Port(u64),
@@ -775,6 +1309,7 @@ pub enum AstOp {
     String(String),
     Label(String),
     JumpLocation(u64),
+    Expr,
 }
 
 #[derive(Debug, Clone)] // cant copy because of the String
@@ -783,21 +1318,19 @@ pub enum Operand {
     Mem(u64), // should be compiled into Imm before emulating
     Reg(u64),
     Label(String),
+    Expr(usize), // index into the parser's expressions, becomes Imm once every label is defined
 }
 
 impl Operand {
-    pub fn transform_label(self, label: &str, pc: usize) -> Self {
-        if matches!(self, Self::Label(ref l) if l == label) {
-            Self::Imm(pc as u64)
-        } else {
-            self
+    pub fn transform_label(&mut self, label: &str, pc: usize) {
+        if matches!(self, Self::Label(l) if l == label) {
+            *self = Self::Imm(pc as u64);
         }
     }
 
-    pub fn transform_mem(self, ms: usize) -> Self {
-        match self {
-            Self::Mem(v) => Self::Imm(v + ms as u64),
-            _ => self
+    pub fn transform_mem(&mut self, ms: usize) {
+        if let Self::Mem(v) = self {
+            *self = Self::Imm(*v + ms as u64);
         }
     }
 }
@@ -807,84 +1340,468 @@ pub struct Headers {
     pub bits: u64,
     pub minheap: u64,
     pub minstack: u64,
-    pub minreg: u64
+    pub minreg: u64,
+    pub float: bool,
+}
+
+impl Default for Headers {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Headers {
     pub fn new() -> Self {
-        Headers { bits: 8, minheap: 16, minstack: 16, minreg: 8 } // replace all r0 with 0
+        Headers { bits: 8, minheap: 16, minstack: 16, minreg: 8, float: false } // replace all r0 with 0
+    }
+
+    // the @ constants from the urcl spec, at the width of the program so @max-1 fits
+    pub fn constant(&self, name: &str) -> Option<u64> {
+        let bits = self.bits.clamp(1, 64);
+        let max = u64::MAX >> (64 - bits);
+        let lhalf = max >> (bits - bits / 2);
+        match name.to_lowercase().as_str() {
+            "@bits" => Some(self.bits),
+            "@minreg" => Some(self.minreg),
+            "@minheap" => Some(self.minheap),
+            "@heap" => Some(self.minheap), // the heap is exactly as big as asked for
+            "@minstack" => Some(self.minstack),
+            "@max" => Some(max),
+            "@smax" => Some(max >> 1),
+            "@msb" => Some(1 << (bits - 1)),
+            "@smsb" => Some((1 << (bits - 1)) >> 1),
+            "@smin" => Some(1 << (bits - 1)),
+            "@uhalf" => Some(max & !lhalf),
+            "@lhalf" => Some(lhalf),
+            _ => None,
+        }
     }
 }
 
-#[derive(Debug, Clone)]
-pub enum Inst {
-    ADD(Operand, Operand, Operand),
-    RSH(Operand, Operand),
-    LOD(Operand, Operand),
-    STR(Operand, Operand),
-    BGE(Operand, Operand, Operand),
-    NOR(Operand, Operand, Operand),
-    MOV(Operand, Operand),
-    INC(Operand, Operand),
-    DEC(Operand, Operand),
-    OUT(Operand, Operand),
-    IN(Operand, Operand),
-    HLT,
-    
-    PSH(Operand),
-    POP(Operand),
-    JMP(Operand),
-    SUB(Operand, Operand, Operand),
-    NOP,
-    LSH(Operand, Operand),
-    NEG(Operand, Operand),
-    AND(Operand, Operand, Operand),
-    OR(Operand, Operand, Operand),
-    NOT(Operand, Operand),
-    NAND(Operand, Operand, Operand),
-    CPY(Operand, Operand),
-    MLT(Operand, Operand, Operand),
-    DIV(Operand, Operand, Operand),
-    MOD(Operand, Operand, Operand),
-    ABS(Operand, Operand),
-    LLOD(Operand, Operand, Operand),
-    LSTR(Operand, Operand, Operand),
-    SDIV(Operand, Operand, Operand),
-    SETE(Operand, Operand, Operand),
-    SETNE(Operand, Operand, Operand),
-    SETG(Operand, Operand, Operand),
-    SETGE(Operand, Operand, Operand),
-    SETL(Operand, Operand, Operand),
-    SETLE(Operand, Operand, Operand),
-    XOR(Operand, Operand, Operand),
-    XNOR(Operand, Operand, Operand),
-    BNE(Operand, Operand, Operand),
-    BRE(Operand, Operand, Operand),
-    SSETG(Operand, Operand, Operand),
-    SSETGE(Operand, Operand, Operand),
-    SSETL(Operand, Operand, Operand),
-    SSETLE(Operand, Operand, Operand),
-    BRL(Operand, Operand, Operand),
-    BRG(Operand, Operand, Operand),
-    BLE(Operand, Operand, Operand),
-    BRZ(Operand, Operand),
-    BNZ(Operand, Operand),
-    SETC(Operand, Operand, Operand),
-    SETNC(Operand, Operand, Operand),
-    BNC(Operand, Operand, Operand),
-    BRC(Operand, Operand, Operand),
-    SBRL(Operand, Operand, Operand),
-    SBRG(Operand, Operand, Operand),
-    SBLE(Operand, Operand, Operand),
-    SBGE(Operand, Operand, Operand),
-    BOD(Operand, Operand),
-    BEV(Operand, Operand),
-    BRN(Operand, Operand),
-    BRP(Operand, Operand),
-    BSR(Operand, Operand, Operand),
-    BSL(Operand, Operand, Operand),
-    SRS(Operand, Operand),
-    BSS(Operand, Operand, Operand),
-    CAL(Operand),
-    RET,
+// every name Headers::constant knows
+pub const CONSTANTS: &[&str] = &[
+    "@bits", "@minreg", "@minheap", "@heap", "@minstack", "@max", "@smax", "@msb", "@smsb", "@smin", "@uhalf", "@lhalf",
+];
+
+// generates the Inst enum, parsing and the operand walkers from the table in isa.rs
+macro_rules! define_inst {
+    (@enum [$($done:tt)*]) => {
+        #[derive(Debug, Clone)]
+        pub enum Inst {
+            $($done)*
+            Custom(usize, Vec<Operand>), // one from custom::register, with its operands in order
+        }
+    };
+    (@enum [$($done:tt)*] $name:ident() $($rest:tt)*) => {
+        define_inst!(@enum [$($done)* $name,] $($rest)*);
+    };
+    (@enum [$($done:tt)*] $name:ident($($op:ident),+) $($rest:tt)*) => {
+        define_inst!(@enum [$($done)* $name($(operand_type!($op)),+),] $($rest)*);
+    };
+    ($($(#[$flag:ident])* $name:ident($($op:ident: $kind:ident $(as $type:ty)?),*) $(; $assign:tt)? => $body:expr,)*) => {
+        define_inst!(@enum [] $($name($($op),*))*);
+
+        // the same order as the table, so an opcode is also an index into INSTRUCTIONS
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+        pub enum Opcode {
+            $($name,)*
+        }
+
+        pub const INSTRUCTIONS: &[InstInfo] = &[$(
+            InstInfo {
+                mnemonic: stringify!($name),
+                operands: &[$(OperandKind::$kind),*],
+                destination: position(&[$(stringify!($op)),*], destination!($($assign)?)),
+                float: false $(|| same(stringify!($flag), "float"))*,
+            },
+        )*];
+
+        impl Inst {
+            fn parse(mnemonic: &str, p: &mut Parser) -> Option<Self> {
+                $(if mnemonic.eq_ignore_ascii_case(stringify!($name)) {
+                    $(use_flag!(p, $flag);)*
+                    $(let $op = p.get_operand(OperandKind::$kind);)*
+                    return Some(isa_inst!($name; $($op),*));
+                })*
+                let id = custom::find(mnemonic)?;
+                let info = custom::info(id);
+                if info.float {
+                    p.use_float();
+                }
+                Some(Inst::Custom(id, info.operands.iter().map(|kind| p.get_operand(*kind)).collect()))
+            }
+
+            // None for custom instructions, they arent in the table
+            pub fn opcode(&self) -> Option<Opcode> {
+                match self {
+                    $(isa_any!($name; $($op),*) => Some(Opcode::$name),)*
+                    Inst::Custom(..) => None,
+                }
+            }
+
+            pub fn operands(&self) -> impl Iterator<Item = &Operand> {
+                match self {
+                    $(isa_inst!($name; $($op),*) => vec![$($op),*],)*
+                    Inst::Custom(_, ops) => ops.iter().collect(),
+                }.into_iter()
+            }
+
+            pub fn operands_mut(&mut self) -> impl Iterator<Item = &mut Operand> {
+                match self {
+                    $(isa_inst!($name; $($op),*) => vec![$($op),*],)*
+                    Inst::Custom(_, ops) => ops.iter_mut().collect(),
+                }.into_iter()
+            }
+
+            pub fn map_operands(self, mut f: impl FnMut(Operand) -> Operand) -> Self {
+                match self {
+                    $(isa_inst!($name; $($op),*) => {
+                        $(let $op = f($op);)*
+                        isa_inst!($name; $($op),*)
+                    },)*
+                    Inst::Custom(id, ops) => Inst::Custom(id, ops.into_iter().map(f).collect()),
+                }
+            }
+        }
+    };
+}
+macro_rules! operand_type {
+    ($op:ident) => {
+        Operand
+    };
+}
+macro_rules! destination {
+    () => {
+        None
+    };
+    ($to:ident) => {
+        Some(stringify!($to))
+    };
+    ([$to:ident]) => {
+        None
+    };
+}
+macro_rules! use_flag {
+    ($p:ident, float) => {
+        $p.use_float()
+    };
+}
+with_isa!(define_inst);
+
+impl Inst {
+    pub fn info(&self) -> &'static InstInfo {
+        match (self, self.opcode()) {
+            (Inst::Custom(id, _), _) => custom::info(*id),
+            (_, opcode) => &INSTRUCTIONS[opcode.unwrap() as usize],
+        }
+    }
+
+    pub fn mnemonic(&self) -> &'static str {
+        self.info().mnemonic
+    }
+
+    pub fn operand_kinds(&self) -> &'static [OperandKind] {
+        self.info().operands
+    }
+
+    // the operand the result is written to, stores to memory dont have one
+    pub fn destination(&self) -> Option<&Operand> {
+        self.operands().nth(self.info().destination?)
+    }
+
+    // every operand that gets read, including the addresses of memory writes
+    pub fn sources(&self) -> impl Iterator<Item = &Operand> {
+        let destination = self.info().destination;
+        self.operands().enumerate().filter(move |(i, _)| Some(*i) != destination).map(|(_, op)| op)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::{emulator::{silence_emulate, silence_emulate_with_files, StepResult}, source::VirtualFiles};
+
+    fn program(src: &str) -> Vec<Inst> {
+        silence_emulate(src.to_string()).unwrap_or_else(|d| panic!("{:?}", d)).program().instructions.clone()
+    }
+
+    // the output of running it, or the codes of the errors if it didnt assemble
+    fn run(src: &str, files: &[(&str, &str)]) -> Result<String, Vec<&'static str>> {
+        let mut virtual_files = VirtualFiles::new();
+        for (name, src) in files {
+            virtual_files.add_file(name, src.to_string());
+        }
+        match silence_emulate_with_files(src.to_string(), &virtual_files, ResourceLimits::new()) {
+            Ok(mut emu) => {
+                assert_eq!(emu.run_for_steps(1000).result, StepResult::HLT);
+                Ok(emu.get_output())
+            },
+            Err(diagnostics) => Err(diagnostics.iter().map(|d| d.code).collect()),
+        }
+    }
+
+    #[test]
+    fn operands() {
+        let insts = program("ADD R1 R2 5\nSTR M0 R3\nHLT");
+        let add = &insts[0];
+        assert_eq!((add.mnemonic(), add.opcode()), ("ADD", Some(Opcode::ADD)));
+        assert_eq!(add.operand_kinds(), [OperandKind::Reg, OperandKind::Op, OperandKind::Op]);
+        assert!(matches!(add.destination(), Some(Operand::Reg(1))));
+        assert!(matches!(add.sources().collect::<Vec<_>>()[..], [Operand::Reg(2), Operand::Imm(5)]));
+
+        // a store has no destination, the address is read like the value
+        let str = &insts[1];
+        assert!(str.destination().is_none());
+        assert_eq!(str.sources().count(), 2);
+        assert_eq!(insts[2].operands().count(), 0);
+    }
+
+    #[test]
+    fn map_operands() {
+        let insts = program("ADD R1 R2 R3\nHLT");
+        let renamed = insts[0].clone().map_operands(|op| match op {
+            Operand::Reg(r) => Operand::Reg(r + 10),
+            op => op,
+        });
+        assert!(matches!(renamed, Inst::ADD(Operand::Reg(11), Operand::Reg(12), Operand::Reg(13))));
+
+        let mut inst = insts[0].clone();
+        for op in inst.operands_mut() {
+            *op = Operand::Imm(0);
+        }
+        assert!(inst.operands().all(|op| matches!(op, Operand::Imm(0))));
+    }
+
+    #[test]
+    fn macros() {
+        let src = "@macro SHOW d x\nADD d x 3\nOUT %NUMB d\n@endmacro\nSHOW R1 (1 + 2)\nSHOW R2 ( 4 )\nSHOW R3 'a'\nHLT";
+        assert_eq!(run(src, &[]), Ok("67100".to_owned()));
+        // labels in a macro body are new for every call
+        let src = "@macro LOOP n\nIMM R1 n\n.again\nDEC R1 R1\nBNZ .again R1\n@endmacro\nLOOP 3\nLOOP 2\nOUT %NUMB R1\nHLT";
+        assert_eq!(run(src, &[]), Ok("0".to_owned()));
+    }
+
+    #[test]
+    fn macro_errors() {
+        assert_eq!(run("@macro ONE a\nOUT %NUMB a\n@endmacro\nONE 1 2\nHLT", &[]), Err(vec!["E0023"]));
+        assert_eq!(ErrorKind::MacroArguments { expected: 1, actual: 2 }.to_string(), "Expected 1 macro argument but got 2");
+        assert_eq!(ErrorKind::MacroArguments { expected: 2, actual: 1 }.to_string(), "Expected 2 macro arguments but got 1");
+        assert_eq!(run("@macro A\nA\n@endmacro\nA\nHLT", &[]), Err(vec!["E0024"]));
+    }
+
+    #[test]
+    fn includes() {
+        let lib = ("lib/print.urcl", "@include \"util.urcl\"\nOUT %NUMB 1\n");
+        let util = ("lib/util.urcl", "OUT %NUMB 2\n");
+        // relative to the including file, and only once however often it is included
+        assert_eq!(run("@include \"lib/print.urcl\"\n@include \"lib/util.urcl\"\nHLT", &[lib, util]), Ok("21".to_owned()));
+        assert_eq!(run("@include \"missing.urcl\"\nHLT", &[]), Err(vec!["E0016"]));
+        assert_eq!(run("@include \"a.urcl\"\nHLT", &[("a.urcl", "@include \"b.urcl\""), ("b.urcl", "@include \"a.urcl\"")]), Err(vec!["E0017"]));
+        assert_eq!(run("@include a.urcl\nHLT", &[]).unwrap_err()[0], "E0018");
+    }
+
+    #[test]
+    fn include_paths_and_locations() {
+        let mut files = VirtualFiles::new();
+        files.add_file("std/lib.urcl", "OUT %NUMB 1\nLOD R1 99999\n".to_owned());
+        files.add_include_path("std");
+        let mut emu = silence_emulate_with_files("@include \"lib.urcl\"\nHLT".to_owned(), &files, ResourceLimits::new()).unwrap();
+        assert_eq!(emu.run_for_steps(100).result, StepResult::Error);
+        assert_eq!(emu.get_output(), "1");
+        assert_eq!(emu.program().debug.location(1), "std/lib.urcl:2");
+        assert_eq!(emu.program().debug.location(2), "main.urcl:2");
+    }
+
+    const PRINT: &str = "
+@if @BITS == 8
+@include \"missing8.urcl\"
+@macro PRINT
+OUT %NUMB 8
+@endmacro
+@else
+@macro PRINT
+OUT %NUMB 16
+@endmacro
+@endif
+PRINT
+HLT";
+
+    #[test]
+    fn skipped_branch_is_not_expanded() {
+        assert_eq!(run(&format!("BITS 16{}", PRINT), &[]), Ok("16".to_string()));
+        // the branch that is taken still gets its errors
+        assert_eq!(run(&format!("BITS 8{}", PRINT), &[]), Err(vec!["E0016"]));
+        assert_eq!(run(&format!("BITS 8{}", PRINT), &[("missing8.urcl", "")]), Ok("8".to_string()));
+    }
+
+    #[test]
+    fn nested_conditionals() {
+        let src = "
+@define A 1
+@if A
+    @ifdef B
+        @include \"nope.urcl\"
+    @else
+        @include \"inc.urcl\"
+    @endif
+@else
+    @include \"nope.urcl\"
+@endif
+INC_MAC
+HLT";
+        let inc = "@macro INC_MAC\nOUT %NUMB 3\n@endmacro\nOUT %NUMB 2\n";
+        assert_eq!(run(src, &[("inc.urcl", inc)]), Ok("23".to_string()));
+    }
+
+    #[test]
+    fn conditionals_in_includes_and_macros() {
+        let inc = "@ifdef X\n@include \"nope.urcl\"\n@else\n@include \"dir/b.urcl\"\n@endif\n";
+        let b = "@macro M n\n@if n > 4\nOUT %NUMB 1\n@else\n@include \"nope.urcl\"\n@endif\n@endmacro\n";
+        assert_eq!(run("@include \"dir/a.urcl\"\nM 5\nM 9\nHLT", &[("dir/a.urcl", inc), ("dir/b.urcl", b)]), Ok("11".to_string()));
+        assert_eq!(run("@include \"dir/a.urcl\"\nM 1\nHLT", &[("dir/a.urcl", inc), ("dir/b.urcl", b)]), Err(vec!["E0016"]));
+    }
+
+    #[test]
+    fn unbalanced_conditionals() {
+        assert_eq!(run("@endif\nHLT", &[]), Err(vec!["E0028"]));
+        assert_eq!(run("@if 1\nHLT", &[]), Err(vec!["E0027"]));
+        assert_eq!(run("@if 0\n@else\n@else\n@endif\nHLT", &[]), Err(vec!["E0029"]));
+    }
+
+    #[test]
+    fn command_line_defines() {
+        let mut files = VirtualFiles::new();
+        files.add_define("X", "@FOO");
+        let diagnostics = silence_emulate_with_files("IMM R1 X\nHLT".to_owned(), &files, ResourceLimits::new()).unwrap_err();
+        assert_eq!((diagnostics[0].location.file.as_str(), diagnostics[0].location.text.as_str()), ("<command line>", "X=@FOO"));
+    }
+
+    #[test]
+    fn expressions() {
+        assert_eq!(run("IMM R1 (.b)+1\nOUT %NUMB R1\nHLT\n.b\nHLT", &[]), Ok("4".to_string()));
+        assert_eq!(run("IMM R1 .end - .start\nOUT %NUMB R1\nHLT\n.start\nHLT\nHLT\n.end\nHLT", &[]), Ok("2".to_string()));
+        assert_eq!(run("BITS 8\nIMM R1 @MAX-1\nOUT %NUMB R1\nHLT", &[]), Ok("254".to_string()));
+        assert_eq!(run("OUT %TEXT 'A'+1\nHLT", &[]), Ok("B".to_string()));
+        assert_eq!(run("@define W 3\nDW [(W*2) W + 1]\nLOD R1 1\nOUT %NUMB R1\nHLT", &[]), Ok("4".to_string()));
+        assert_eq!(run("IMM R1 .nope + 1\nHLT", &[]), Err(vec!["E0006"]));
+    }
+
+    #[test]
+    fn labels_can_have_operators_in_them() {
+        assert_eq!(run("JMP .my-label\nHLT\n.my-label\nOUT %NUMB 1\nHLT", &[]), Ok("1".to_string()));
+    }
+
+    #[test]
+    fn overflow_points_at_the_whole_expression() {
+        let src = "BITS 8\nIMM R1 @MAX+1\nHLT";
+        let diagnostics = silence_emulate(src.to_string()).unwrap_err();
+        assert_eq!(diagnostics[0].code, "E0034");
+        assert_eq!(&src[diagnostics[0].location.bytes.clone()], "@MAX+1");
+    }
+
+    #[test]
+    fn constants() {
+        let headers = Headers { bits: 8, minheap: 32, minstack: 4, minreg: 6, float: false };
+        let values: Vec<_> = CONSTANTS.iter().map(|c| headers.constant(&c.to_uppercase()).unwrap()).collect();
+        assert_eq!(values, [8, 6, 32, 32, 4, 255, 127, 128, 64, 128, 240, 15]);
+        assert_eq!(Headers { bits: 64, ..headers }.constant("@MAX"), Some(u64::MAX));
+        assert_eq!(headers.constant("@NOPE"), None);
+    }
+
+    #[test]
+    fn constants_use_the_final_headers() {
+        assert_eq!(run("OUT %NUMB @UHALF\nOUT %NUMB @MINSTACK\nHLT\nBITS 16\nMINSTACK 3", &[]), Ok("652803".to_string()));
+        assert_eq!(run("IMM R1 @NOPE\nHLT", &[]), Err(vec!["E0035"]));
+    }
+
+    #[test]
+    fn local_labels() {
+        let src = ".f\nJMP ..loop\n..loop\nOUT %NUMB 1\nJMP .g\n.g\nJMP ..loop\n..loop\nOUT %NUMB 2\nHLT";
+        assert_eq!(run(src, &[]), Ok("12".to_string()));
+        // they can be reached from anywhere by their full name
+        assert_eq!(run("JMP .g.end\nHLT\n.g\n..end\nOUT %NUMB 3\nHLT", &[]), Ok("3".to_string()));
+        assert_eq!(run(".f\n..a\n..a\nHLT", &[]), Err(vec!["E0012"]));
+        assert_eq!(run(".f\n..a\n.g\n..a\nHLT", &[]), Ok(String::new()));
+    }
+
+    #[test]
+    fn local_labels_before_any_global_one() {
+        let src = "JMP ..a\nHLT\n..a\nOUT %NUMB 1\nHLT\n.a\nOUT %NUMB 2\nHLT";
+        assert_eq!(run(src, &[]), Ok("1".to_string()));
+    }
+
+    #[test]
+    fn dw_directives() {
+        let src = "DW [1]*3\nDWZ \"hi\"\nLOD R1 2\nOUT %NUMB R1\nLOD R1 4\nOUT %TEXT R1\nLOD R1 5\nOUT %NUMB R1\nHLT";
+        assert_eq!(run(src, &[]), Ok("1i0".to_string()));
+        let src = "BITS 16\nDWP \"abc\"\nLOD R1 0\nOUT %NUMB R1\nLOD R1 1\nOUT %NUMB R1\nHLT";
+        assert_eq!(run(src, &[]), Ok("2493025344".to_string()));
+        assert_eq!(run(".d\nDW [.d + 1]\nLOD R1 .d\nOUT %NUMB R1\nHLT", &[]), Ok("1".to_string()));
+    }
+
+    #[test]
+    fn stray_square_in_dw() {
+        assert_eq!(run("DW ]\nHLT", &[]), Err(vec!["E0036"]));
+        assert_eq!(run("DW [1 2\nHLT", &[]), Err(vec!["E0009"]));
+    }
+
+    #[test]
+    fn literals() {
+        assert_eq!(run("OUT %NUMB 0b1_01\nOUT %NUMB 0o7\nOUT %NUMB 0x1F\nOUT %NUMB 'A'\nHLT", &[]), Ok("573165".to_string()));
+        // too wide for BITS is a warning and loses the top bits, too wide for any word is an error
+        assert_eq!(run("BITS 8\nOUT %NUMB 300\nHLT", &[]), Ok("44".to_string()));
+        assert_eq!(run("OUT %NUMB 0x\nOUT %NUMB 99999999999999999999\nHLT", &[]), Err(vec!["E0038", "E0038"]));
+    }
+
+    #[test]
+    fn recursive_defines() {
+        assert_eq!(run("@define A A\nIMM R1 A\nHLT", &[]), Err(vec!["E0052"]));
+        assert_eq!(run("@define A B\n@define B A\nIMM R1 A\nHLT", &[]), Err(vec!["E0052"]));
+        assert_eq!(run("@define A B\n@define B 7\nIMM R1 A\nOUT %NUMB R1\nHLT", &[]), Ok("7".to_string()));
+    }
+
+    #[test]
+    fn every_error_is_reported() {
+        let src = "FOO R1\nADD R1\nJMP .nope\nIMM R1 UNDEFINED\n.end";
+        assert_eq!(run(src, &[]).unwrap_err().len(), 4);
+    }
+
+    #[test]
+    fn minreg_in_expressions() {
+        assert_eq!(run("IMM R12 @MINREG\nOUT %NUMB R12\nHLT", &[]), Ok("12".to_string()));
+    }
+
+    #[test]
+    fn registers_above_minreg() {
+        // raised with a warning when MINREG was given, quietly when it wasnt
+        let emu = silence_emulate("MINREG 2\nIMM R5 1\nHLT".to_string()).unwrap();
+        assert_eq!(emu.program().headers.minreg, 5);
+        let emu = silence_emulate("IMM R20 1\nHLT".to_string()).unwrap();
+        assert_eq!(emu.program().headers.minreg, 20);
+    }
+
+    #[test]
+    fn reg_aliases() {
+        assert_eq!(run("@reg counter R3\nIMM counter 5\nOUT %NUMB R3\nHLT", &[]), Ok("5".to_string()));
+        let emu = silence_emulate("@reg a R1\n@reg b R1\nHLT".to_string()).unwrap();
+        assert_eq!(emu.program().debug.reg_names[&1], ["a", "b"]);
+        // ones made after a label end at the next label
+        assert_eq!(run(".f\n@reg x R2\nIMM x 1\n.g\nIMM x 1\nHLT", &[]), Err(vec!["E0030"]));
+        assert_eq!(run("@reg x 5\nHLT", &[]), Err(vec!["E0042"]));
+    }
+
+    #[test]
+    fn suggestions() {
+        let notes = |src: &str| -> Vec<String> {
+            silence_emulate(src.to_string()).unwrap_err().into_iter().flat_map(|d| d.notes).collect()
+        };
+        assert_eq!(notes("ADDD R1 R1 R1\nHLT"), ["did you mean 'ADD'?"]);
+        assert_eq!(notes("OUT %NUMBER 1\nHLT"), ["did you mean '%NUMB'?"]);
+        assert_eq!(notes("IMM R1 @MAXX\nHLT"), ["did you mean '@MAX'?"]);
+        assert_eq!(notes("JMP .lop\n.loop\nHLT"), ["did you mean '.loop'?"]);
+        assert_eq!(notes(".f\nJMP ..lop\n..loop\nHLT"), ["did you mean '..loop'?"]);
+        assert_eq!(notes("@define WIDTH 3\nIMM R1 WIDHT\nHLT"), ["did you mean 'WIDTH'?"]);
+        // nothing close enough, no note
+        assert_eq!(notes("XYZZY\nHLT"), Vec::<String>::new());
+    }
 }
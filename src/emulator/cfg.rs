@@ -0,0 +1,384 @@
+use std::fmt::Write;
+
+use num_traits::FromPrimitive;
+
+use super::{ast::{Program, Inst, Operand}, isa::OperandKind, emulator::{PC, SP}, devices::IOPort};
+
+// the control flow graph of a program, for tools that need to know where code can go next like
+// an optimiser, lints or the call graph in the debugger. jumps through registers could go anywhere,
+// their blocks are marked indirect instead of getting edges
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EdgeKind {
+    FallThrough, // runs into the next block, for calls this is where the call returns to
+    Jump, // JMP
+    Branch, // a conditional branch that was taken
+    Call, // CAL
+}
+
+#[derive(Debug, Clone)]
+pub struct Edge {
+    pub from: usize, // blocks
+    pub to: usize,
+    pub kind: EdgeKind,
+}
+
+// instructions that always run one after another, only the first can be jumped to and only the last can jump
+#[derive(Debug, Clone)]
+pub struct Block {
+    pub start: usize, // pc of the first instruction
+    pub end: usize, // pc after the last one
+    pub labels: Vec<String>,
+    pub indirect: bool, // ends in a jump through a register, or a write to PC
+}
+
+#[derive(Debug, Clone)]
+pub struct Subroutine {
+    pub entry: usize, // block
+    pub name: Option<String>, // the first label on the entry, ..locals come after the global label
+    pub blocks: Vec<usize>, // every block it can get to without calling, sorted. shared code is in more than one
+    pub calls: Vec<usize>, // other subroutines
+    pub returns: bool, // has a RET, the program itself usually doesnt
+}
+
+#[derive(Debug, Clone)]
+pub struct Cfg {
+    pub blocks: Vec<Block>,
+    pub edges: Vec<Edge>,
+    pub subroutines: Vec<Subroutine>, // the first is the program itself starting at pc 0, then every CAL target
+}
+
+// what an instruction does to the pc after it
+enum Flow {
+    Next,
+    Stop, // HLT and RET
+    Jump(Option<usize>), // None when the target is in a register
+    Branch(Option<usize>),
+    Call(Option<usize>),
+}
+
+fn flow(inst: &Inst) -> Flow {
+    let target = inst.operand_kinds().iter().zip(inst.operands())
+        .find(|(kind, _)| **kind == OperandKind::Jmp)
+        .map(|(_, op)| match op {
+            Operand::Imm(to) => Some(*to as usize),
+            _ => None,
+        });
+    match (inst, target) {
+        (Inst::HLT | Inst::RET, _) => Flow::Stop,
+        (Inst::JMP(_), Some(to)) => Flow::Jump(to),
+        (Inst::CAL(_), Some(to)) => Flow::Call(to),
+        (_, Some(to)) => Flow::Branch(to),
+        // MOV PC R1 and friends jump too
+        _ if matches!(inst.destination(), Some(Operand::Reg(PC))) => Flow::Jump(None),
+        _ => Flow::Next,
+    }
+}
+
+impl Cfg {
+    pub fn new(program: &Program) -> Self {
+        let insts = &program.instructions;
+        let len = insts.len();
+
+        // a block starts at pc 0, at every label and jump target, and after every jump
+        let mut leader = vec![false; len + 1];
+        leader[0] = true;
+        leader[len] = true;
+        for pc in program.debug.labels.keys() {
+            leader[(*pc).min(len)] = true;
+        }
+        for (pc, inst) in insts.iter().enumerate() {
+            match flow(inst) {
+                Flow::Next => {},
+                Flow::Stop => leader[pc + 1] = true,
+                Flow::Jump(to) | Flow::Branch(to) | Flow::Call(to) => {
+                    leader[pc + 1] = true;
+                    if let Some(to) = to {
+                        leader[to.min(len)] = true;
+                    }
+                },
+            }
+        }
+
+        let mut blocks = Vec::new();
+        let mut start = 0;
+        for pc in (1..=len).filter(|pc| leader[*pc]) {
+            let labels = program.debug.labels.get(&start).cloned().unwrap_or_default();
+            blocks.push(Block { start, end: pc, labels, indirect: false });
+            start = pc;
+        }
+        // jumps past the last instruction end the program, they get an empty block to go to
+        if program.debug.labels.contains_key(&len) || insts.iter().any(|i| matches!(flow(i), Flow::Jump(Some(to)) | Flow::Branch(Some(to)) | Flow::Call(Some(to)) if to >= len)) {
+            blocks.push(Block { start: len, end: len, labels: program.debug.labels.get(&len).cloned().unwrap_or_default(), indirect: false });
+        }
+
+        let mut cfg = Self { blocks, edges: Vec::new(), subroutines: Vec::new() };
+        // anything past the end goes to the empty block at the end
+        let block_of = |cfg: &Self, to: usize| cfg.block_at(to).unwrap_or(cfg.blocks.len() - 1);
+        let mut edges = Vec::new();
+        let mut indirect = Vec::new();
+        for (i, block) in cfg.blocks.iter().enumerate() {
+            if block.start == block.end {
+                continue;
+            }
+            let mut edge = |to: usize, kind| edges.push(Edge { from: i, to: block_of(&cfg, to), kind });
+            let falls = match flow(&insts[block.end - 1]) {
+                Flow::Next => true,
+                Flow::Stop => false,
+                Flow::Jump(to) => {
+                    match to {
+                        Some(to) => edge(to, EdgeKind::Jump),
+                        None => indirect.push(i),
+                    }
+                    false
+                },
+                Flow::Branch(to) => {
+                    match to {
+                        Some(to) => edge(to, EdgeKind::Branch),
+                        None => indirect.push(i),
+                    }
+                    true
+                },
+                Flow::Call(to) => {
+                    match to {
+                        Some(to) => edge(to, EdgeKind::Call),
+                        None => indirect.push(i),
+                    }
+                    true
+                },
+            };
+            if falls && block.end < len {
+                edge(block.end, EdgeKind::FallThrough);
+            }
+        }
+        for i in indirect {
+            cfg.blocks[i].indirect = true;
+        }
+        cfg.edges = edges;
+
+        cfg.find_subroutines(insts);
+        cfg
+    }
+
+    fn find_subroutines(&mut self, insts: &[Inst]) {
+        if self.blocks.is_empty() {
+            return;
+        }
+        let mut entries = vec![0];
+        for edge in self.edges.iter().filter(|e| e.kind == EdgeKind::Call) {
+            if !entries.contains(&edge.to) {
+                entries.push(edge.to);
+            }
+        }
+        entries[1..].sort();
+
+        for entry in entries.iter().copied() {
+            let mut seen = vec![false; self.blocks.len()];
+            let mut todo = vec![entry];
+            let mut calls = Vec::new();
+            while let Some(block) = todo.pop() {
+                if std::mem::replace(&mut seen[block], true) {
+                    continue;
+                }
+                for edge in self.successors(block) {
+                    match edge.kind {
+                        EdgeKind::Call => calls.push(entries.iter().position(|e| *e == edge.to).unwrap()),
+                        _ => todo.push(edge.to),
+                    }
+                }
+            }
+            calls.sort();
+            calls.dedup();
+            let blocks: Vec<usize> = (0..self.blocks.len()).filter(|b| seen[*b]).collect();
+            let returns = blocks.iter().any(|b| {
+                let block = &self.blocks[*b];
+                block.start < block.end && matches!(insts[block.end - 1], Inst::RET)
+            });
+            let name = self.blocks[entry].labels.first().cloned();
+            self.subroutines.push(Subroutine { entry, name, blocks, calls, returns });
+        }
+    }
+
+    // the block an instruction is in, blocks are sorted by pc and dont overlap
+    pub fn block_at(&self, pc: usize) -> Option<usize> {
+        let i = self.blocks.partition_point(|b| b.end <= pc);
+        self.blocks.get(i).filter(|b| b.start <= pc && pc < b.end).map(|_| i)
+    }
+
+    pub fn successors(&self, block: usize) -> impl Iterator<Item = &Edge> {
+        self.edges.iter().filter(move |e| e.from == block)
+    }
+
+    pub fn predecessors(&self, block: usize) -> impl Iterator<Item = &Edge> {
+        self.edges.iter().filter(move |e| e.to == block)
+    }
+
+    fn subroutine_name(&self, i: usize) -> String {
+        let sub = &self.subroutines[i];
+        match &sub.name {
+            Some(name) => label_text(name),
+            None if i == 0 => "program".to_owned(),
+            None => format!("pc {}", self.blocks[sub.entry].start),
+        }
+    }
+
+    // for graphviz, `dot -Tsvg` turns it into a picture. calls are dashed, taken branches green.
+    // every subroutine gets a box around its blocks, and blocks nothing jumps to are grey,
+    // though a jump through a register could still get there
+    pub fn to_dot(&self, program: &Program) -> String {
+        let mut out = String::from("digraph cfg {\n    node [shape=box fontname=monospace];\n");
+        for (i, block) in self.blocks.iter().enumerate() {
+            let mut text = String::new();
+            for label in block.labels.iter() {
+                text += &format!("{}\\l", escape(&label_text(label)));
+            }
+            for inst in &program.instructions[block.start..block.end] {
+                text += &format!("    {}\\l", escape(&inst_text(inst, program)));
+            }
+            if block.start == block.end {
+                text += "end\\l";
+            }
+            let mut style = if block.indirect {" style=dashed"} else {""}.to_owned();
+            if i != 0 && self.predecessors(i).next().is_none() {
+                style += " color=grey fontcolor=grey";
+            }
+            writeln!(out, "    b{} [label=\"{}\"{}];", i, text, style).unwrap();
+        }
+        // a block shared by two subroutines can only be in one box, it goes in the first
+        let mut placed = vec![false; self.blocks.len()];
+        placed[0] = true;
+        for (i, sub) in self.subroutines.iter().enumerate().skip(1) {
+            writeln!(out, "    subgraph cluster_s{} {{\n        label=\"{}\";", i, escape(&self.subroutine_name(i))).unwrap();
+            for block in sub.blocks.iter().copied() {
+                if !std::mem::replace(&mut placed[block], true) {
+                    writeln!(out, "        b{};", block).unwrap();
+                }
+            }
+            out.push_str("    }\n");
+        }
+        for edge in self.edges.iter() {
+            let style = match edge.kind {
+                EdgeKind::FallThrough => "",
+                EdgeKind::Jump => "",
+                EdgeKind::Branch => " [color=green]",
+                EdgeKind::Call => " [style=dashed]",
+            };
+            writeln!(out, "    b{} -> b{}{};", edge.from, edge.to, style).unwrap();
+        }
+        out.push_str("}\n");
+        out
+    }
+
+    // just the subroutines and which ones they call, subroutines that never return are dashed
+    pub fn calls_to_dot(&self) -> String {
+        let mut out = String::from("digraph calls {\n    node [shape=box fontname=monospace];\n");
+        for (i, sub) in self.subroutines.iter().enumerate() {
+            let style = if i != 0 && !sub.returns {" style=dashed"} else {""};
+            writeln!(out, "    s{} [label=\"{}\"{}];", i, escape(&self.subroutine_name(i)), style).unwrap();
+        }
+        for (i, sub) in self.subroutines.iter().enumerate() {
+            for to in sub.calls.iter() {
+                writeln!(out, "    s{} -> s{};", i, to).unwrap();
+            }
+        }
+        out.push_str("}\n");
+        out
+    }
+}
+
+// jump targets get shown as a label, the closest one for jumps and the subroutine name for calls. ports by name
+fn inst_text(inst: &Inst, program: &Program) -> String {
+    let mut text = inst.mnemonic().to_owned();
+    for (kind, op) in inst.operand_kinds().iter().zip(inst.operands()) {
+        let op = match op {
+            Operand::Imm(to) if *kind == OperandKind::Jmp => match program.debug.labels.get(&(*to as usize)) {
+                Some(labels) if matches!(inst, Inst::CAL(_)) => label_text(&labels[0]),
+                Some(labels) => label_text(labels.last().unwrap()),
+                None => to.to_string(),
+            },
+            Operand::Imm(port) if *kind == OperandKind::Port => match IOPort::from_u64(*port) {
+                Some(port) => format!("%{:?}", port),
+                None => format!("%{}", port),
+            },
+            Operand::Imm(v) => v.to_string(),
+            Operand::Reg(PC) => "PC".to_owned(),
+            Operand::Reg(SP) => "SP".to_owned(),
+            Operand::Reg(r) => format!("R{}", r),
+            Operand::Mem(m) => format!("M{}", m - program.memory.len() as u64),
+            Operand::Label(l) => l.clone(),
+            Operand::Expr(_) => "?".to_owned(),
+        };
+        text += " ";
+        text += &op;
+    }
+    text
+}
+
+// labels from a macro have the number of the expansion after a space, .done 2 is shown as .done#2
+fn label_text(label: &str) -> String {
+    label.replace(' ', "#")
+}
+
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::emulator::{silence_emulate, EmulatorState};
+
+    fn cfg(src: &str) -> (Cfg, EmulatorState) {
+        let emu = silence_emulate(src.to_string()).unwrap_or_else(|d| panic!("{:?}", d));
+        (Cfg::new(emu.program()), emu)
+    }
+
+    fn edges(cfg: &Cfg) -> Vec<(usize, usize, EdgeKind)> {
+        cfg.edges.iter().map(|e| (e.from, e.to, e.kind)).collect()
+    }
+
+    #[test]
+    fn blocks_and_edges() {
+        let (cfg, _) = cfg(".loop\nINC R1 R1\nBRL .loop R1 10\nOUT %NUMB R1\nHLT");
+        let blocks: Vec<(usize, usize)> = cfg.blocks.iter().map(|b| (b.start, b.end)).collect();
+        assert_eq!(blocks, [(0, 2), (2, 4)]);
+        assert_eq!(edges(&cfg), [(0, 0, EdgeKind::Branch), (0, 1, EdgeKind::FallThrough)]);
+        assert_eq!(cfg.block_at(1), Some(0));
+        assert_eq!(cfg.block_at(3), Some(1));
+        assert_eq!(cfg.block_at(4), None);
+        assert_eq!(cfg.predecessors(0).count(), 1);
+    }
+
+    #[test]
+    fn jump_past_the_end() {
+        let (cfg, _) = cfg("JMP .end\nHLT\n.end");
+        let last = cfg.blocks.len() - 1;
+        assert_eq!(cfg.blocks[last].start, cfg.blocks[last].end);
+        assert_eq!(edges(&cfg), [(0, last, EdgeKind::Jump)]);
+    }
+
+    #[test]
+    fn indirect_jump() {
+        let (cfg, _) = cfg("JMP R1\nHLT");
+        assert!(cfg.blocks[0].indirect);
+        assert!(cfg.edges.is_empty());
+    }
+
+    #[test]
+    fn subroutines() {
+        let (cfg, _) = cfg("CAL .a\nHLT\n.a\nCAL .b\nRET\n.b\nRET\n.c\nJMP .c");
+        let subs: Vec<(Option<&str>, &[usize], bool)> = cfg.subroutines.iter()
+            .map(|s| (s.name.as_deref(), &s.calls[..], s.returns)).collect();
+        assert_eq!(subs, [(None, &[1][..], false), (Some(".a"), &[2][..], true), (Some(".b"), &[][..], true)]);
+        assert_eq!(cfg.calls_to_dot(), "digraph calls {\n    node [shape=box fontname=monospace];\n    s0 [label=\"program\"];\n    s1 [label=\".a\"];\n    s2 [label=\".b\"];\n    s0 -> s1;\n    s1 -> s2;\n}\n");
+    }
+
+    #[test]
+    fn dot() {
+        let (cfg, emu) = cfg("CAL .f\nHLT\n.f\nRET\n.dead\nHLT");
+        let dot = cfg.to_dot(emu.program());
+        assert!(dot.contains("subgraph cluster_s1 {\n        label=\".f\";\n        b2;\n    }"), "{}", dot);
+        assert!(dot.contains("b3 [label=\".dead\\l    HLT\\l\" color=grey fontcolor=grey];"), "{}", dot);
+        assert!(dot.contains("b0 -> b2 [style=dashed];"), "{}", dot);
+    }
+}
@@ -0,0 +1,114 @@
+use std::{cell::RefCell, rc::Rc};
+
+use super::isa::{InstInfo, OperandKind};
+
+// instructions from outside the crate, for when the table in isa.rs isnt enough. they are looked up
+// after the table, so they can add mnemonics but not replace the ones urcl already has
+//
+//   struct Avg;
+//   impl CustomInst for Avg {
+//       fn info(&self) -> InstInfo {
+//           InstInfo { mnemonic: "AVG", operands: &[OperandKind::Reg, OperandKind::Op, OperandKind::Op], destination: Some(0), float: false }
+//       }
+//       fn run(&self, ops: &[u64], _bits: u64) -> u64 { (ops[1] + ops[2]) / 2 }
+//   }
+//   custom::register(Avg);
+#[allow(dead_code)]
+pub trait CustomInst {
+    fn info(&self) -> InstInfo;
+    // gets every operand the way the table does, registers and memory operands read, and gives back
+    // what goes into the destination. without a destination the result is thrown away
+    fn run(&self, operands: &[u64], bits: u64) -> u64;
+}
+
+struct Registered {
+    info: &'static InstInfo,
+    inst: Rc<dyn CustomInst>,
+}
+
+thread_local! {
+    static REGISTRY: RefCell<Vec<Registered>> = const { RefCell::new(Vec::new()) };
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegisterError {
+    Taken, // urcl or an earlier custom instruction already has the mnemonic
+    Jump, // custom instructions cant jump, the control flow graph and the lints wouldnt know
+}
+
+// for programs assembled after this on the same thread
+#[allow(dead_code)]
+pub fn register(inst: impl CustomInst + 'static) -> Result<(), RegisterError> {
+    let info = inst.info();
+    if super::ast::INSTRUCTIONS.iter().any(|i| i.mnemonic.eq_ignore_ascii_case(info.mnemonic)) || find(info.mnemonic).is_some() {
+        return Err(RegisterError::Taken);
+    }
+    if info.operands.contains(&OperandKind::Jmp) {
+        return Err(RegisterError::Jump);
+    }
+    // programs keep pointing at it for as long as they live, and there are only ever a few
+    let info = Box::leak(Box::new(info));
+    REGISTRY.with(|r| r.borrow_mut().push(Registered { info, inst: Rc::new(inst) }));
+    Ok(())
+}
+
+pub(crate) fn find(mnemonic: &str) -> Option<usize> {
+    REGISTRY.with(|r| r.borrow().iter().position(|c| c.info.mnemonic.eq_ignore_ascii_case(mnemonic)))
+}
+
+pub(crate) fn info(id: usize) -> &'static InstInfo {
+    REGISTRY.with(|r| r.borrow()[id].info)
+}
+
+pub(crate) fn run(id: usize, operands: &[u64], bits: u64) -> u64 {
+    let inst = REGISTRY.with(|r| r.borrow()[id].inst.clone());
+    inst.run(operands, bits)
+}
+
+pub(crate) fn mnemonics() -> Vec<&'static str> {
+    REGISTRY.with(|r| r.borrow().iter().map(|c| c.info.mnemonic).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::emulator::{silence_emulate, StepResult};
+
+    struct Avg;
+    impl CustomInst for Avg {
+        fn info(&self) -> InstInfo {
+            InstInfo { mnemonic: "AVG", operands: &[OperandKind::Reg, OperandKind::Op, OperandKind::Op], destination: Some(0), float: false }
+        }
+        fn run(&self, ops: &[u64], _bits: u64) -> u64 {
+            (ops[1] + ops[2]) / 2
+        }
+    }
+
+    struct Named(&'static str, &'static [OperandKind]);
+    impl CustomInst for Named {
+        fn info(&self) -> InstInfo {
+            InstInfo { mnemonic: self.0, operands: self.1, destination: None, float: false }
+        }
+        fn run(&self, _ops: &[u64], _bits: u64) -> u64 {
+            0
+        }
+    }
+
+    #[test]
+    fn custom_instruction() {
+        // every test runs on its own thread, so nothing else sees these
+        register(Avg).unwrap();
+        let mut emu = silence_emulate("IMM R2 10\navg R1 R2 20\nOUT %NUMB R1\nHLT".to_string()).unwrap();
+        assert_eq!(emu.run_for_steps(100).result, StepResult::HLT);
+        assert_eq!(emu.get_output(), "15");
+        assert_eq!(register(Avg).err(), Some(RegisterError::Taken));
+    }
+
+    #[test]
+    fn register_errors() {
+        assert_eq!(register(Named("add", &[])).err(), Some(RegisterError::Taken));
+        assert_eq!(register(Named("JMPX", &[OperandKind::Jmp])).err(), Some(RegisterError::Jump));
+        assert!(silence_emulate("JMPX 0\nHLT".to_string()).is_err());
+    }
+}
@@ -0,0 +1,53 @@
+use std::collections::HashMap;
+
+use super::ast::Inst;
+
+// how many cycles each instruction takes, anything without its own cost uses the default
+#[derive(Debug, Clone)]
+pub struct CycleModel {
+    default: u64,
+    costs: HashMap<&'static str, u64>,
+}
+
+impl Default for CycleModel {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[allow(dead_code)]
+impl CycleModel {
+    // every instruction takes one cycle, so cycles and steps are the same
+    pub fn new() -> Self {
+        Self { default: 1, costs: HashMap::new() }
+    }
+
+    // memory and the stack are a bit slower, multiplication and division a lot slower
+    pub fn weighted() -> Self {
+        let mut model = Self::new();
+        for mnemonic in ["LOD", "STR", "LLOD", "LSTR", "PSH", "POP", "CAL", "RET"] {
+            model = model.with_cost(mnemonic, 2);
+        }
+        model = model.with_cost("CPY", 3);
+        model = model.with_cost("MLT", 4);
+        for mnemonic in ["DIV", "SDIV", "MOD"] {
+            model = model.with_cost(mnemonic, 8);
+        }
+        model
+    }
+
+    pub fn with_default(mut self, cost: u64) -> Self {
+        self.default = cost;
+        self
+    }
+
+    // mnemonic as written in isa.rs
+    pub fn with_cost(mut self, mnemonic: &'static str, cost: u64) -> Self {
+        self.costs.insert(mnemonic, cost);
+        self
+    }
+
+    pub fn cost(&self, inst: &Inst) -> u64 {
+        *self.costs.get(inst.mnemonic()).unwrap_or(&self.default)
+    }
+}
@@ -1,26 +1,53 @@
+use crate::emulator::float;
+
 pub struct Console { // console::console::console::console::console::console::console::console::console
-    output: String
+    output: String,
+    written: usize,
+    max_output: usize,
+}
+impl Default for Console {
+    fn default() -> Self {
+        Self::new()
+    }
 }
+
 impl Console {
     pub fn new() -> Self {
-        Self { output: String::new() }
+        Self { output: String::new(), written: 0, max_output: usize::MAX }
+    }
+    pub fn with_max_output(max_output: usize) -> Self {
+        Self { max_output, ..Self::new() }
     }
 
     pub fn outtext(&mut self, value: u64){
         if let Some(c) = char::from_u32(value as u32) {
-            self.output.push(c);
+            self.push(c.encode_utf8(&mut [0; 4]));
         } else {
             // todo: report error
         }
     }
     pub fn outnumb(&mut self, value: u64){
-        self.output.push_str(&value.to_string());
+        self.push(&value.to_string());
     }
     pub fn outhex(&mut self, value: u64){
-        self.output.push_str(&format!("{:X}", value));
+        self.push(&format!("{:X}", value));
     }
     pub fn outint(&mut self, value: u64){
-        self.output.push_str(&(value as i64).to_string())
+        self.push(&(value as i64).to_string())
+    }
+    pub fn outfloat(&mut self, value: u64, bits: u64){
+        self.push(&float::format(value, bits))
+    }
+
+    // counts everything ever written, clearing the output doesnt give the program more room
+    fn push(&mut self, text: &str) {
+        self.written = self.written.saturating_add(text.len());
+        if !self.exceeded() {
+            self.output.push_str(text);
+        }
+    }
+    pub fn exceeded(&self) -> bool {
+        self.written > self.max_output
     }
 
     pub fn get_output(&self) -> &str {
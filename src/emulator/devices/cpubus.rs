@@ -0,0 +1,43 @@
+pub struct CpuBus { // one word each way, a full slot means whoever wants it has to wait
+    inbox: Option<u64>,
+    outbox: Option<u64>,
+}
+impl Default for CpuBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CpuBus {
+    pub fn new() -> Self {
+        Self { inbox: None, outbox: None }
+    }
+
+    pub fn send(&mut self, value: u64) -> bool {
+        if self.outbox.is_some() {
+            return false;
+        }
+        self.outbox = Some(value);
+        true
+    }
+    pub fn receive(&mut self) -> Option<u64> {
+        self.inbox.take()
+    }
+
+    pub fn take_outgoing(&mut self) -> Option<u64> {
+        self.outbox.take()
+    }
+    pub fn deliver(&mut self, value: u64) -> bool {
+        if self.inbox.is_some() {
+            return false;
+        }
+        self.inbox = Some(value);
+        true
+    }
+    pub fn has_outgoing(&self) -> bool {
+        self.outbox.is_some()
+    }
+    pub fn has_incoming(&self) -> bool {
+        self.inbox.is_some()
+    }
+}
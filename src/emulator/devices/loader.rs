@@ -0,0 +1,38 @@
+use std::collections::VecDeque;
+
+pub struct Loader { // lets programs look at themselves and pull in more of themselves
+    instr: u64,
+    naddr: u64,
+    data: VecDeque<u64>,
+}
+impl Default for Loader {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Loader {
+    pub fn new() -> Self {
+        Self { instr: 0, naddr: 0, data: VecDeque::new() }
+    }
+
+    pub fn set_program(&mut self, instructions: usize, naddr: u64) {
+        self.instr = instructions as u64;
+        self.naddr = naddr;
+    }
+
+    pub fn load_data(&mut self, data: &[u64]) {
+        self.data.extend(data);
+    }
+
+    pub fn in_instr(&self) -> u64 {
+        self.instr
+    }
+    pub fn in_naddr(&self) -> u64 {
+        self.naddr
+    }
+    pub fn in_data(&mut self) -> u64 {
+        // an empty stream reads as 0 so a bootloader can stop on a 0 word
+        self.data.pop_front().unwrap_or(0)
+    }
+}
@@ -1,14 +1,19 @@
 pub mod console;
 pub mod screen;
+pub mod loader;
+pub mod cpubus;
 use console::Console;
 use self::screen::Screen;
+use self::loader::Loader;
+use self::cpubus::CpuBus;
+use super::{emulator::EmulatorErrorKind, limits::ResourceLimits};
 use super::super::*;
 
-use strum_macros::EnumString;
+use strum_macros::{EnumString, EnumVariantNames};
 use num_derive::FromPrimitive;    
 use num_traits::FromPrimitive;
 
-#[derive(Debug, Clone, Copy, EnumString, FromPrimitive)]
+#[derive(Debug, Clone, Copy, EnumString, EnumVariantNames, FromPrimitive)]
 #[repr(u8)]
 #[allow(dead_code, non_camel_case_types)]
 pub enum IOPort {
@@ -43,6 +48,11 @@ pub trait Device {
 pub struct DeviceHost {
     pub console: console::Console,
     pub screen: screen::Screen,
+    pub loader: loader::Loader,
+    pub cpubus: cpubus::CpuBus,
+    pub blocked: bool, // set when the last IN/OUT has to wait, the instruction gets retried
+    pub error: Option<EmulatorErrorKind>,
+    pub bits: u64,
 }//rip
 // we could take a break from ports and add other bits than 64
 use std::fmt::{Formatter, Result, Debug};
@@ -57,32 +67,59 @@ impl DeviceHost {
         let Some(port) = FromPrimitive::from_u64(_port) else {return 0;};
         match port {
             IOPort::RNG => crate::rand(),
-            _ => {todo!("unsupported port {:?}", port)}
+            IOPort::CPUBUS => match self.cpubus.receive() {
+                Some(value) => value,
+                None => {self.blocked = true; 0},
+            },
+            IOPort::INSTR => self.loader.in_instr(),
+            IOPort::NADDR => self.loader.in_naddr(),
+            IOPort::DATA => self.loader.in_data(),
+            _ => {self.error = Some(EmulatorErrorKind::UnsupportedPort); 0}
         }
     }
 
     pub fn out(&mut self, _port: u64, value: u64) {
         let Some(port) = FromPrimitive::from_u64(_port) else {return;};
         match port {
+            IOPort::CPUBUS => if !self.cpubus.send(value) {self.blocked = true},
             IOPort::TEXT => self.console.outtext(value),
             IOPort::NUMB => self.console.outnumb(value),
             IOPort::INT => self.console.outint(value),
             IOPort::HEX => self.console.outhex(value),
+            IOPort::FLOAT => self.console.outfloat(value, self.bits),
             IOPort::X => self.screen.out_x(value),
             IOPort::Y => self.screen.out_y(value),
             IOPort::COLOR => self.screen.out_color(value),
             IOPort::RNG => crate::srand(value),
-            _ => {todo!("unsupported port {:?}", port)}
+            _ => self.error = Some(EmulatorErrorKind::UnsupportedPort),
+        }
+        if self.console.exceeded() {
+            self.error = Some(EmulatorErrorKind::OutputLimitExceeded);
         }
     }
 
+    // the ports in_port and out know, they cant do anything with the others
+    pub fn supports_in(port: u64) -> bool {
+        matches!(FromPrimitive::from_u64(port), Some(IOPort::RNG | IOPort::CPUBUS | IOPort::INSTR | IOPort::NADDR | IOPort::DATA))
+    }
+    pub fn supports_out(port: u64) -> bool {
+        matches!(FromPrimitive::from_u64(port), Some(
+            IOPort::CPUBUS | IOPort::TEXT | IOPort::NUMB | IOPort::INT | IOPort::HEX | IOPort::FLOAT |
+            IOPort::X | IOPort::Y | IOPort::COLOR | IOPort::RNG
+        ))
+    }
+
     pub fn show(&mut self) {
         self.console.clear_output(10_000);
         jsprintln!("{}", self.console.get_output());
         out_screen(self.screen.width(), self.screen.height(), self.screen.pixels());
     }
 
-    pub fn new() -> Self {
-        Self { console: Console::new(), screen: Screen::new(32, 32) }
+    pub fn with_limits(limits: &ResourceLimits) -> Self {
+        let screen = Screen::new(screen::WIDTH, screen::HEIGHT);
+        Self {
+            console: Console::with_max_output(limits.max_output), screen, loader: Loader::new(),
+            cpubus: CpuBus::new(), blocked: false, error: None, bits: 64,
+        }
     }
 }
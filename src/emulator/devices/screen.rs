@@ -8,6 +8,10 @@ pub struct Screen {
     y: usize,
 }
 
+// every program gets a screen this big, theres no header to ask for another size
+pub const WIDTH: usize = 32;
+pub const HEIGHT: usize = 32;
+
 impl Screen {
     pub fn new(width: usize, height: usize) -> Self {
         let pixels = vec![0x00_00_00_ff; width*height];
@@ -0,0 +1,225 @@
+use std::{fmt::Write, ops::Range};
+
+use super::{errorcontext::{ErrorLevel, get_indent_level, str_width}, source::SourceFiles};
+
+// errors as plain data, made out of an ErrorContext once the tokens are gone. the cli, the web page,
+// the bot and editors all get the same list and pick a renderer
+
+#[derive(Debug, Clone)]
+pub struct Location {
+    pub file: String,
+    pub bytes: Range<usize>, // into the file
+    pub line: usize, // from 1
+    pub column: usize, // in chars, from 1
+    pub text: String, // the whole line
+    lineno: String, // the line number as it gets printed, with the file name once there are includes
+    width: usize, // of the ^^^ under it
+}
+
+impl Location {
+    pub fn new(files: &SourceFiles, span: &str) -> Self {
+        // spans outside every file, like the end of file token, go at the end of the main file
+        let main = files.main();
+        let end = &main.src[main.src.len()..];
+        let (span, (file, line, col, offset)) = match files.locate(span) {
+            Some(at) => (span, at),
+            None => (end, files.locate(end).unwrap()),
+        };
+        Self {
+            file: files.get(file).name.clone(),
+            bytes: offset..offset + span.len(),
+            line,
+            column: col + 1,
+            text: files.get(file).line(line).to_owned(),
+            lineno: files.describe(file, line),
+            width: str_width(span).max(1),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub severity: ErrorLevel,
+    pub code: &'static str, // like E0006, never changes for a kind of error
+    pub name: &'static str, // like UndefinedLabel
+    pub message: String,
+    pub location: Location,
+    pub notes: Vec<String>,
+    pub secondary: Vec<(Location, String)>, // other places that explain this one, like the macro call it came from
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Ansi, // the cli and the bot
+    Html, // the web page
+    Plain, // file:line:col, for logs and anything that reads errors like a compiler's
+    Json, // for editors and ci
+}
+
+impl std::str::FromStr for Format {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Self, ()> {
+        match s.to_lowercase().as_str() {
+            "ansi" => Ok(Format::Ansi),
+            "html" => Ok(Format::Html),
+            "plain" => Ok(Format::Plain),
+            "json" => Ok(Format::Json),
+            _ => Err(()),
+        }
+    }
+}
+
+pub fn render(diagnostics: &[Diagnostic], format: Format) -> String {
+    let mut out = String::new();
+    if format == Format::Json {
+        json(&mut out, diagnostics);
+        return out;
+    }
+    for diag in diagnostics {
+        block(&mut out, format, diag.severity, Some(diag.code), &diag.message, &diag.location);
+        for note in diag.notes.iter() {
+            match format {
+                Format::Html => writeln!(out, "= note: {}", html_escape::encode_text(note)),
+                _ => writeln!(out, "\t= note: {}", note),
+            }.unwrap();
+        }
+        // these used to be errors of their own, they still look like it
+        for (location, message) in diag.secondary.iter() {
+            block(&mut out, format, ErrorLevel::Info, None, message, location);
+        }
+    }
+    out
+}
+
+// the line after the diagnostics when nothing came out of them. json has to stay parseable and the
+// errors in it already say it failed, so it gets nothing
+pub fn failed(format: Format) -> &'static str {
+    match format {
+        Format::Ansi => "\x1b[1;31mError: Compilation failed\x1b[0;0m\n",
+        Format::Html => "<span class=\"error\">Error: Compilation failed</span>\n",
+        Format::Plain => "error: Compilation failed\n",
+        Format::Json => "",
+    }
+}
+
+fn block(out: &mut String, format: Format, level: ErrorLevel, code: Option<&str>, message: &str, at: &Location) {
+    let code = code.map_or(String::new(), |code| format!("[{}]", code));
+    let indent = get_indent_level(&at.text);
+    let line = at.text.split_at(indent).1.replace('\t', " ");
+    let lineno = format!("{} ", at.lineno);
+    let pointer = format!("{}{}", " ".repeat((at.column - 1).saturating_sub(indent)), "^".repeat(at.width));
+    match format {
+        Format::Ansi => {
+            let colour = match level {
+                ErrorLevel::Info => 36,
+                ErrorLevel::Warning => 33,
+                ErrorLevel::Error => 31,
+            };
+            writeln!(out, "\x1b[1;{}m{}{}: {}\x1b[0;0m", colour, level, code, message).unwrap();
+            writeln!(out, "\t{}| {}", lineno, line).unwrap();
+            writeln!(out, "\t{}| {}", " ".repeat(str_width(&lineno)), pointer).unwrap();
+        },
+        Format::Html => {
+            let class = level.to_string().to_lowercase();
+            writeln!(out, "<span class=\"{}\">{}{}: {}</span>", class, level, code, html_escape::encode_text(message)).unwrap();
+            writeln!(out, "{}| {}", lineno, html_escape::encode_text(&line)).unwrap();
+            writeln!(out, "{}| {}", " ".repeat(str_width(&lineno)), pointer).unwrap();
+        },
+        Format::Plain => {
+            writeln!(out, "{}:{}:{}: {}{}: {}", at.file, at.line, at.column, level.to_string().to_lowercase(), code, message).unwrap();
+            writeln!(out, "  {}", line).unwrap();
+            writeln!(out, "  {}", pointer).unwrap();
+        },
+        Format::Json => unreachable!("json is written all at once by json()"),
+    }
+}
+
+fn json(out: &mut String, diagnostics: &[Diagnostic]) {
+    out.push('[');
+    for (i, diag) in diagnostics.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        write!(out, "{{\"severity\":{},\"code\":{},\"name\":{},\"message\":{},",
+            json_string(&diag.severity.to_string().to_lowercase()), json_string(diag.code), json_string(diag.name), json_string(&diag.message)
+        ).unwrap();
+        json_location(out, &diag.location);
+        out.push_str(",\"notes\":[");
+        let notes: Vec<String> = diag.notes.iter().map(|n| json_string(n)).collect();
+        out.push_str(&notes.join(","));
+        out.push_str("],\"secondary\":[");
+        for (j, (location, message)) in diag.secondary.iter().enumerate() {
+            if j > 0 {
+                out.push(',');
+            }
+            write!(out, "{{\"message\":{},", json_string(message)).unwrap();
+            json_location(out, location);
+            out.push('}');
+        }
+        out.push_str("]}");
+    }
+    out.push(']');
+}
+
+fn json_location(out: &mut String, at: &Location) {
+    write!(out, "\"file\":{},\"line\":{},\"column\":{},\"start\":{},\"end\":{}",
+        json_string(&at.file), at.line, at.column, at.bytes.start, at.bytes.end
+    ).unwrap();
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::emulator::silence_emulate;
+
+    fn diagnostics(src: &str) -> Vec<Diagnostic> {
+        silence_emulate(src.to_string()).unwrap_err()
+    }
+
+    #[test]
+    fn in_source_order() {
+        // undefined labels are only found at the end, they still come first here
+        let codes: Vec<_> = diagnostics("JMP .nope\nFOO\nHLT").iter().map(|d| d.code).collect();
+        assert_eq!(codes, ["E0006", "E0008"]);
+    }
+
+    #[test]
+    fn plain() {
+        let out = render(&diagnostics("JMP .lop\n.loop\nHLT"), Format::Plain);
+        assert_eq!(out, "main.urcl:1:5: error[E0006]: Undefined label\n  JMP .lop\n      ^^^^\n\t= note: did you mean '.loop'?\n");
+    }
+
+    #[test]
+    fn html_is_escaped() {
+        let out = render(&diagnostics("FOO <b>"), Format::Html);
+        assert!(out.starts_with("<span class=\"error\">Error[E0008]: Unknown instruction</span>\n1 | FOO &lt;b&gt;\n"), "{}", out);
+    }
+
+    #[test]
+    fn json() {
+        let out = render(&diagnostics("JMP .lop\n.loop\nHLT"), Format::Json);
+        assert_eq!(out, concat!(
+            r#"[{"severity":"error","code":"E0006","name":"UndefinedLabel","message":"Undefined label","#,
+            r#""file":"main.urcl","line":1,"column":5,"start":4,"end":8,"notes":["did you mean '.loop'?"],"secondary":[]}]"#,
+        ));
+        assert_eq!(render(&[], Format::Json), "[]");
+        assert_eq!(json_string("a\"b\\\n\t\x01"), r#""a\"b\\\n\t\u0001""#);
+    }
+}
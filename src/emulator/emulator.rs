@@ -2,6 +2,15 @@ use devices::DeviceHost;
 use std::{rc::Rc, time::Duration};
 
 use crate::emulator::ast::Parser;
+use crate::emulator::cycles::CycleModel;
+use crate::emulator::diagnostic::{Diagnostic, Format};
+use crate::emulator::errorcontext::{ErrorContext, ErrorKind};
+use crate::emulator::isa::{with_isa, isa_inst};
+use crate::emulator::lexer::{Kind, Token};
+use crate::emulator::limits::ResourceLimits;
+use crate::emulator::lint::Lints;
+use crate::emulator::memory::{Memory, MemoryMap, Region};
+use crate::emulator::source::{FileProvider, SourceFiles, VirtualFiles};
 
 #[allow(unused_imports)]
 use wasm_bindgen::prelude::*;
@@ -16,6 +25,10 @@ pub use super::{
 pub enum EmulatorErrorKind {
     StackOverflow,
     StackUnderflow,
+    Deadlock,
+    OutputLimitExceeded,
+    UnsupportedPort,
+    OutOfBounds,
 }
 
 impl<'a> std::fmt::Display for EmulatorErrorKind {
@@ -23,6 +36,10 @@ impl<'a> std::fmt::Display for EmulatorErrorKind {
         match self {
             EmulatorErrorKind::StackOverflow => write!(f, "Stack overflow"),
             EmulatorErrorKind::StackUnderflow => write!(f, "Stack underflow"),
+            EmulatorErrorKind::Deadlock => write!(f, "Deadlock, every core is waiting on %CPUBUS"),
+            EmulatorErrorKind::OutputLimitExceeded => write!(f, "Console output limit exceeded"),
+            EmulatorErrorKind::UnsupportedPort => write!(f, "Port not supported by urcl-rs"),
+            EmulatorErrorKind::OutOfBounds => write!(f, "Memory address out of bounds"),
         }
     }
 }
@@ -41,49 +58,84 @@ impl EmulatorError {
 #[derive(Debug)]
 pub struct EmulatorState {
     regs: Vec<u64>,
-    heap: Vec<u64>,
+    heap: Memory, // DW data followed by the heap
     stack: Stack,
+    map: MemoryMap,
     pc: usize,
     program: Program,
     devices: DeviceHost,
+    cycle_model: CycleModel,
+    steps: u64,
+    cycles: u64,
     pub error: EmulatorError,
 }
 
+#[wasm_bindgen]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StackModel {
+    Urcl, // like the reference emulator, sp starts one past the end of memory and points at the last pushed value
+    Legacy, // how urcl-rs used to do it, sp points at the next free word and the lowest stack word is never used
+}
+
+// the top end of the address space, sp is a real address but data only holds the stack part
 #[derive(Debug)]
 pub struct Stack {
-    data: Vec<u64>,
+    data: Memory,
     sp: i64,
-    size: usize,
+    base: i64, // lowest address of the stack, anything below is heap
+    top: i64, // one past the highest address of the stack
+    model: StackModel,
 }
 
 impl Stack {
-    fn new(size: usize) -> Self {
-        let mut data = Vec::new();
-        data.resize(size, 0);
-        Stack { data, sp: size as i64 - 1, size }
+    fn new(base: u64, size: usize, model: StackModel) -> Self {
+        let data = Memory::new(size);
+        let (base, top) = (base as i64, (base + size as u64) as i64);
+        let mut stack = Stack { data, sp: 0, base, top, model };
+        stack.reset();
+        stack
+    }
+
+    fn reset(&mut self) {
+        self.sp = match self.model {
+            StackModel::Urcl => self.top,
+            StackModel::Legacy => self.top - 1,
+        };
     }
 
     fn push(&mut self, data: u64) -> Result<(), EmulatorError> {
-        if self.sp > 0 {
-            self.data[self.sp as usize] = data;
-            self.sp -= 1;
-            Ok(())
-        } else {
-            Err(EmulatorError(Some(EmulatorErrorKind::StackOverflow)))
+        match self.model {
+            StackModel::Urcl if self.sp > self.base && self.sp <= self.top => {
+                self.sp -= 1;
+                self.data.set((self.sp - self.base) as usize, data);
+                Ok(())
+            },
+            StackModel::Legacy if self.sp > self.base && self.sp < self.top => {
+                self.data.set((self.sp - self.base) as usize, data);
+                self.sp -= 1;
+                Ok(())
+            },
+            _ => Err(EmulatorError(Some(EmulatorErrorKind::StackOverflow))),
         }
     }
     fn pop(&mut self) -> Result<u64, EmulatorError> {
-        if self.sp < self.size as i64 - 1 {
-            self.sp += 1;
-            Ok(self.data[self.sp as usize])
-        } else {
-            Err(EmulatorError(Some(EmulatorErrorKind::StackUnderflow)))
+        match self.model {
+            StackModel::Urcl if self.sp >= self.base && self.sp < self.top => {
+                let value = self.data.get((self.sp - self.base) as usize);
+                self.sp += 1;
+                Ok(value)
+            },
+            StackModel::Legacy if self.sp >= self.base && self.sp < self.top - 1 => {
+                self.sp += 1;
+                Ok(self.data.get((self.sp - self.base) as usize))
+            },
+            _ => Err(EmulatorError(Some(EmulatorErrorKind::StackUnderflow))),
         }
     }
 }
 
 #[wasm_bindgen]
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum StepResult {
     Continue,
     HLT,
@@ -91,9 +143,31 @@ pub enum StepResult {
     Error,
 }
 
+// what a bounded run stopped on and how much it did before that
+#[wasm_bindgen]
+#[derive(Debug, Clone, Copy)]
+pub struct RunReport {
+    pub result: StepResult,
+    pub steps: u64,
+    pub cycles: u64,
+}
+
 pub const PC: u64 = u64::MAX;
 pub const SP: u64 = u64::MAX - 1;
 
+// reads a word of the given width as a signed number
+fn sext(value: u64, bits: u64) -> i64 {
+    if bits >= 64 {
+        return value as i64;
+    }
+    let shift = 64 - bits;
+    ((value << shift) as i64) >> shift
+}
+
+fn mask(value: u64, bits: u64) -> u64 {
+    if bits >= 64 {value} else {value & ((1 << bits) - 1)}
+}
+
 fn does_overflow(a: u64, b: u64) -> bool {
     match a.checked_add(b) {
         Some(_) => false,
@@ -107,25 +181,59 @@ unsafe impl Send for EmulatorState {}
 #[wasm_bindgen]
 #[allow(dead_code)]
 impl EmulatorState {
-    fn new(program: Program, devices: DeviceHost) -> Self {
+    fn new(program: Program, mut devices: DeviceHost) -> Self {
+        devices.loader.set_program(program.instructions.len(), program.memory.len() as u64);
+        devices.bits = program.headers.bits;
         let regs = vec![0; program.headers.minreg as usize];
-        let mut heap = vec![0; (program.memory.len() as u64 + program.headers.minheap) as usize];
+        let map = MemoryMap::new(program.memory.len() as u64, program.headers.minheap, program.headers.minstack);
+        let mut heap = Memory::new(map.stack_start as usize);
 
         for (i, el) in program.memory.iter().enumerate() {
-            heap[i] = *el;
+            heap.set(i, *el);
         }
 
         EmulatorState {
             regs,
             heap,
-            stack: Stack::new(program.headers.minstack as usize),
+            stack: Stack::new(map.stack_start, program.headers.minstack as usize, StackModel::Urcl),
+            map,
             pc: 0,
             program,
             devices,
+            cycle_model: CycleModel::new(),
+            steps: 0,
+            cycles: 0,
             error: EmulatorError::new(),
         }
     }
 
+    // resets sp, so pick the model before running anything
+    pub fn set_stack_model(&mut self, model: StackModel) {
+        self.stack.model = model;
+        self.stack.reset();
+    }
+
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    // words queued here are read back one at a time through %DATA
+    pub fn load_data(&mut self, data: &[u64]) {
+        self.devices.loader.load_data(data);
+    }
+
+    pub fn memory_map(&self) -> MemoryMap {
+        self.map
+    }
+
+    // for debuggers, None when the address is outside the address space
+    pub fn read_memory(&self, address: u64) -> Option<u64> {
+        self.read(address).ok()
+    }
+
     pub fn get_output(&self) -> String {
         self.devices.console.get_output().to_string()
     }
@@ -144,12 +252,23 @@ impl EmulatorState {
         }
     }
     fn run_for(&mut self, max_time: Duration) -> StepResult {
-        return self.run_for_ms(max_time.as_secs_f64() * 1000.0);
+        self.run_for_ms(max_time.as_secs_f64() * 1000.0)
     }
     pub fn show(&mut self) {
         clear_text();
         self.devices.show();
-        jsprintln!("Regs: {:?},\nMem: {:?},\nStack: {:?}", self.regs, self.heap, self.stack.data);
+        jsprintln!("Regs: {},\nMem: {:?},\nStack: {:?}", self.format_regs(), self.heap, self.stack.data);
+    }
+
+    // [R1: 0, R2 counter: 5], with the @reg names a register had
+    fn format_regs(&self) -> String {
+        let regs: Vec<String> = self.regs.iter().enumerate().map(|(i, v)| {
+            match self.program.debug.reg_names.get(&(i as u64 + 1)) {
+                Some(names) => format!("R{} {}: {}", i + 1, names.join("/"), v),
+                None => format!("R{}: {}", i + 1, v),
+            }
+        }).collect();
+        format!("[{}]", regs.join(", "))
     }
 
     pub fn run_for_ms(&mut self, max_time_ms: f64) -> StepResult {
@@ -173,6 +292,30 @@ impl EmulatorState {
         StepResult::Continue
     }
 
+    // unlike run_for_ms this stops at the same place on every machine
+    pub fn run_for_steps(&mut self, max_steps: u64) -> RunReport {
+        self.run_until(|_, steps, _| steps >= max_steps)
+    }
+
+    pub fn run_for_cycles(&mut self, max_cycles: u64) -> RunReport {
+        self.run_until(|emu, _, cycles| cycles + emu.next_cost() > max_cycles)
+    }
+
+    fn next_cost(&self) -> u64 {
+        self.program.instructions.get(self.pc).map_or(0, |inst| self.cycle_model.cost(inst))
+    }
+
+    fn run_until<F: Fn(&Self, u64, u64) -> bool>(&mut self, done: F) -> RunReport {
+        let (start_steps, start_cycles) = (self.steps, self.cycles);
+        let mut result = StepResult::Continue;
+        while !done(self, self.steps - start_steps, self.cycles - start_cycles) {
+            result = self.step();
+            if result != StepResult::Continue {
+                break;
+            }
+        }
+        RunReport { result, steps: self.steps - start_steps, cycles: self.cycles - start_cycles }
+    }
 
     // or maybe we just run on a sepperate thread 🤔 good idea
     // lets implement that
@@ -184,6 +327,29 @@ impl EmulatorState {
         let Some(inst) = self.program.instructions.get(self.pc) else {
             return StepResult::HLT
         };
+        let cost = self.cycle_model.cost(inst);
+        let bits = self.program.headers.bits;
+
+        // reads outside of memory give 0 and stop the program with an error
+        macro_rules! get_mem {
+            ($index:expr) => {
+                match self.read($index) {
+                    Ok(v) => v,
+                    Err(err) => {
+                        self.error = err;
+                        0
+                    },
+                }
+            };
+        }
+        macro_rules! set_mem {
+            ($index:expr, $value:expr) => {{
+                let (index, value) = ($index, $value);
+                if let Err(err) = self.write(index, value) {
+                    self.error = err;
+                }
+            }};
+        }
 
         macro_rules! get {
             ($operand:expr) => {
@@ -195,6 +361,7 @@ impl EmulatorState {
                         0  => 0,
                         _  => self.regs[*v as usize - 1],
                     },
+                    Operand::Mem(address) => get_mem!(*address),
                     _ => panic!("Unsupported operand {:?}", $operand),
                 }
             };
@@ -209,70 +376,19 @@ impl EmulatorState {
                         0  => {},
                         _  => self.regs[*v as usize - 1] = $value,
                     },
+                    Operand::Mem(address) => set_mem!(*address, $value),
                     _ => panic!("Unsupported target operand {:?}", $operand),
                 }
             };
         }
 
-        macro_rules! get_mem {
-            ($index:expr) => {
-                if $index < self.program.headers.minheap {
-                    self.heap[$index as usize]
-                } else {
-                    self.stack.data[($index - self.program.headers.minheap) as usize]
-                }
-            };
-        }
-        macro_rules! set_mem {
-            ($index:expr, $value:expr) => {
-                if $index < self.program.headers.minheap {
-                    self.heap[$index as usize] = $value
-                } else {
-                    self.stack.data[($index - self.program.headers.minheap) as usize] = $value
-                }
-            };
-        }
-
-        macro_rules! getm {
-            ($operand:expr) => {
-                if get!($operand) < self.program.headers.minheap {
-                    self.heap[get!($operand) as usize]
-                } else {
-                    self.stack.data[(get!($operand) - self.program.headers.minheap) as usize]
-                }
-            };
-        }
         macro_rules! setm {
             ($operand:expr, $value:expr) => {
-                if get!($operand) < self.program.headers.minheap {
-                    self.heap[get!($operand) as usize] = $value
-                } else {
-                    self.stack.data[(get!($operand) - self.program.headers.minheap) as usize] = $value
-                }
+                set_mem!(get!($operand), $value)
             };
         }
 
         macro_rules! insts {
-            (@pat($name:ident); $($raw:ident)*) => {
-                Inst::$name($($raw),*)
-            };
-            (@pat($name:ident) ($($($raw:ident$(: $_type_raw:ty)?)? $([$mem:ident$(: $_type_mem:ty)?])?),*)) => {
-                insts!(@pat($name); $($($raw)?)? $($($mem)?)?)
-            };
-            (@pat($name:ident)) => {
-                Inst::$name
-            };
-            (@read) => {};
-            (@read [$name:ident$(: $type:ty)?]$(, $($rest:tt)*)?) => {
-                #[allow(unused_variables)]
-                let $name = getm!($name) $(as $type)?;
-                insts!(@read $($($rest)*)?)
-            };
-            (@read $name:ident$(: $type:ty)?$(, $($rest:tt)*)?) => {
-                #[allow(unused_variables)]
-                let $name = get!($name) $(as $type)?;
-                insts!(@read $($($rest)*)?)
-            };
             (@assign; $body:expr) => {
                 $body
             };
@@ -285,18 +401,30 @@ impl EmulatorState {
                 setm!($to, value)
             }};
             (
-                $($name:ident$(($($ops:tt)*))? $(; $assign:tt)? => $body:expr$(,)?)*
+                $($(#[$flag:ident])* $name:ident($($op:ident: $kind:ident $(as $type:ty)?),*) $(; $assign:tt)? => $body:expr,)*
             ) => {
                 match inst {
                     $(
-
-                        insts!(@pat($name) $(($($ops)*))?) => {
+                        isa_inst!($name; $($op),*) => {
                             insts!(@assign $($assign)?; {
-                                $(insts!(@read $($ops)*);)?
+                                $(
+                                    #[allow(unused_variables)]
+                                    let $op = get!($op) $(as $type)?;
+                                )*
                                 $body
                             })
                         }
                     )*
+                    Inst::Custom(id, ops) => {
+                        let mut values = Vec::with_capacity(ops.len());
+                        for op in ops.iter() {
+                            values.push(get!(op));
+                        }
+                        let value = custom::run(*id, &values, bits);
+                        if let Some(to) = inst.destination() {
+                            set!(to, value);
+                        }
+                    },
                 }
             };
         }
@@ -311,6 +439,17 @@ impl EmulatorState {
             }
         }
 
+        macro_rules! FLOAT {
+            ($word:expr) => {
+                float::to_float($word, bits)
+            };
+        }
+        macro_rules! WORD {
+            ($float:expr) => {
+                float::from_float($float, bits)
+            };
+        }
+
         macro_rules! SET {
             ($cond:expr) => {
                 if $cond {
@@ -321,120 +460,72 @@ impl EmulatorState {
             };
         }
 
-        insts! {
-            NOP => {},
-            HLT => return StepResult::HLT,
-
-            PSH(a) => {
-                if let Err(err) = self.stack.push(a) {
+        // what the semantics in isa.rs get to use
+        macro_rules! halt {
+            () => {
+                return StepResult::HLT
+            };
+        }
+        macro_rules! pc {
+            () => {
+                self.pc as u64
+            };
+        }
+        macro_rules! bits {
+            () => {
+                bits
+            };
+        }
+        macro_rules! push {
+            ($value:expr) => {
+                if let Err(err) = self.stack.push($value) {
                     self.error = err;
                 }
-            },
-            POP(a); a => {
+            };
+        }
+        // gives back the fallback when the stack is empty
+        macro_rules! pop {
+            ($fallback:expr) => {
                 match self.stack.pop() {
                     Ok(v) => v,
                     Err(err) => {
                         self.error = err;
-                        a
+                        $fallback
                     },
                 }
-            },
-            CAL(a: usize) => {
-                if let Err(err) = self.stack.push(self.pc as u64) {
-                    self.error = err;
-                }
-                branch!(a)
-            },
-            RET => {
-                match self.stack.pop().map(|v| v as usize) {
-                    Ok(v) => branch!(v),
-                    Err(err) => self.error = err,
-                }
-            },
+            };
+        }
+        // a blocked read keeps the old value, the instruction is retried later
+        macro_rules! input {
+            ($port:expr, $old:expr) => {{
+                let value = self.devices.in_port($port);
+                if self.devices.blocked {$old} else {value}
+            }};
+        }
+        macro_rules! output {
+            ($port:expr, $value:expr) => {
+                self.devices.out($port, $value)
+            };
+        }
+
+        with_isa!(insts);
 
-            IN(a, b); a => self.devices.in_port(b),
-            OUT(a, b) => self.devices.out(a, b),
-
-            JMP(a: usize) => branch!(a),
-            BRG(a: usize, b, c) => branch!(a if b > c),
-            BGE(a: usize, b, c) => branch!(a if b >= c),
-            BRL(a: usize, b, c) => branch!(a if b < c),
-            BLE(a: usize, b, c) => branch!(a if b <= c),
-
-            BRE(a: usize, b, c) => branch!(a if b == c),
-            BNE(a: usize, b, c) => branch!(a if b != c),
-            BRZ(a: usize, b) => branch!(a if b == 0),
-            BNZ(a: usize, b) => branch!(a if b != 0),
-            BRC(a: usize, b, c) => branch!(a if does_overflow(b, c)),
-            BNC(a: usize, b, c) => branch!(a if !does_overflow(b, c)),
-
-            SBRG(a: usize, b: i64, c: i64) => branch!(a if b > c),
-            SBGE(a: usize, b: i64, c: i64) => branch!(a if b >= c),
-            SBRL(a: usize, b: i64, c: i64) => branch!(a if b < c),
-            SBLE(a: usize, b: i64, c: i64) => branch!(a if b <= c),
-
-            BEV(a: usize, b) => branch!(a if b&1 == 0),
-            BOD(a: usize, b) => branch!(a if b&1 == 1),
-            BRP(a: usize, b: i64) => branch!(a if b >= 0),
-            BRN(a: usize, b: i64) => branch!(a if b < 0),
-
-            MOV(a, b); a => b,
-            STR(a, b); [a] => b,
-            CPY(a, [b]); [a] => b,
-            LOD(a, [b]); a => b,
-            LLOD(a, b, c); a => get_mem!(b + c),
-            LSTR(a, b, c) => set_mem!(a + b, c),
-
-            ADD(a, b, c); a => b + c,
-            SUB(a, b, c); a => b - c,
-            INC(a, b); a => b + 1,
-            DEC(a, b); a => b - 1,
-
-            RSH(a, b); a => b >> 1,
-            LSH(a, b); a => b << 1,
-            SRS(a, b: i64); a => b >> 1,
-
-            BSR(a, b, c); a => b >> c,
-            BSL(a, b, c); a => b << c,
-            BSS(a, b: i64, c: i64); a => b >> c,
-
-            OR(a, b, c); a => b | c,
-            NOR(a, b, c); a => !(b | c),
-            AND(a, b, c); a => b & c,
-            NAND(a, b, c); a => !(b & c),
-            XOR(a, b, c); a => b ^ c,
-            XNOR(a, b, c); a => !(b ^ c),
-
-            NOT(a, b); a => !b,
-            NEG(a, b: i64); a => -b,
-            ABS(a, b: i64); a => b.abs(),
-
-            MLT(a, b, c); a => b * c,
-            DIV(a, b, c); a => b / c,
-            SDIV(a, b: i64, c: i64); a => b / c,
-            MOD(a, b, c); a => b % c,
-
-            SETE(a, b, c); a => SET!(b == c),
-            SETNE(a, b, c); a => SET!(b != c),
-            SETC(a, b, c); a => SET!(does_overflow(b, c)),
-            SETNC(a, b, c); a => SET!(!does_overflow(b, c)),
-
-            SETG(a, b, c); a => SET!(b > c),
-            SETGE(a, b, c); a => SET!(b >= c),
-            SETL(a, b, c); a => SET!(b < c),
-            SETLE(a, b, c); a => SET!(b <= c),
-            SSETG(a, b: i64, c: i64); a => SET!(b > c),
-            SSETGE(a, b: i64, c: i64); a => SET!(b >= c),
-            SSETL(a, b: i64, c: i64); a => SET!(b < c),
-            SSETLE(a, b: i64, c: i64); a => SET!(b <= c),
+        if self.devices.blocked {
+            self.devices.blocked = false;
+            return StepResult::Input;
+        }
+        if let Some(err) = self.devices.error.take() {
+            self.error = EmulatorError(Some(err));
         }
 
         self.pc += 1;
+        self.steps += 1;
+        self.cycles += cost;
 
         match &self.error {
             EmulatorError(Some(err)) => {
                 let mut out = String::new();
-                out_emu_err(&mut out, err, &self.program.debug.pc_to_line_start[self.pc-1].to_string(), "No Preview Yet!");
+                out_emu_err(&mut out, err, &self.program.debug.location(self.pc-1), "No Preview Yet!");
                 StepResult::Error
             }
             EmulatorError(None) => StepResult::Continue,
@@ -445,7 +536,7 @@ impl EmulatorState {
         match &self.error {
             EmulatorError(Some(err)) => {
                 let mut out = String::new();
-                out_emu_err(&mut out, err, &self.program.debug.pc_to_line_start[self.pc-1].to_string(), "No Preview Yet!");
+                out_emu_err(&mut out, err, &self.program.debug.location(self.pc-1), "No Preview Yet!");
                 Some(out)
             }
             EmulatorError(None) => None,
@@ -457,37 +548,305 @@ impl EmulatorState {
     }
 }
 
+#[allow(dead_code)]
+impl EmulatorState {
+    pub fn set_cycle_model(&mut self, model: CycleModel) {
+        self.cycle_model = model;
+    }
+
+    pub fn cpubus(&mut self) -> &mut devices::cpubus::CpuBus {
+        &mut self.devices.cpubus
+    }
+
+    pub fn program(&self) -> &Program {
+        &self.program
+    }
+
+    pub(crate) fn swap_heap(&mut self, heap: &mut Memory) {
+        std::mem::swap(&mut self.heap, heap);
+    }
+
+    fn read(&self, address: u64) -> Result<u64, EmulatorError> {
+        match self.map.region_of(address) {
+            Some(Region::Data | Region::Heap) => Ok(self.heap.get(address as usize)),
+            Some(Region::Stack) => Ok(self.stack.data.get((address - self.map.stack_start) as usize)),
+            None => Err(EmulatorError(Some(EmulatorErrorKind::OutOfBounds))),
+        }
+    }
+    fn write(&mut self, address: u64, value: u64) -> Result<(), EmulatorError> {
+        match self.map.region_of(address) {
+            Some(Region::Data | Region::Heap) => self.heap.set(address as usize, value),
+            Some(Region::Stack) => self.stack.data.set((address - self.map.stack_start) as usize, value),
+            None => return Err(EmulatorError(Some(EmulatorErrorKind::OutOfBounds))),
+        }
+        Ok(())
+    }
+}
+
 #[allow(dead_code)]
 #[wasm_bindgen]
 pub fn emulate(src: String) -> Option<EmulatorState> {
+    emulate_with_limits(src, ResourceLimits::new())
+}
+
+#[allow(dead_code)]
+#[wasm_bindgen]
+pub fn emulate_with_limits(src: String, limits: ResourceLimits) -> Option<EmulatorState> {
+    emulate_with_files(src, &VirtualFiles::new(), limits)
+}
+
+// @include reads from the files added to files, the main file is called main.urcl
+#[allow(dead_code)]
+#[wasm_bindgen]
+pub fn emulate_with_files(src: String, files: &VirtualFiles, limits: ResourceLimits) -> Option<EmulatorState> {
+    emulate_with_provider("main.urcl", src, files, limits)
+}
+
+#[allow(dead_code)]
+pub fn emulate_with_provider(name: &str, src: String, provider: &dyn FileProvider, limits: ResourceLimits) -> Option<EmulatorState> {
     // wifi died
-    let src = Rc::from(src);
     clear_text();
-    let toks = lexer::lex(&src);
+    let (emu, diagnostics) = silence_emulate_with_provider(name, src, provider, limits, &Lints::new());
+    jsprintln!("{}", diagnostic::render(&diagnostics, Format::Html));
+    emu
+}
 
-    let Parser {
-        ast: program, err, ..
-    } = ast::gen_ast(toks, src.clone());
-    jsprintln!("{}", err.to_string(&src));
-    if err.has_error() {
-        return None;
-    }
+#[allow(dead_code)]
+pub fn silence_emulate(body: String) -> Result<EmulatorState, Vec<Diagnostic>> {
+    silence_emulate_with_limits(body, ResourceLimits::new())
+}
 
-    let host = DeviceHost::new();
-    let emu = EmulatorState::new(program, host);
+#[allow(dead_code)]
+pub fn silence_emulate_with_limits(body: String, limits: ResourceLimits) -> Result<EmulatorState, Vec<Diagnostic>> {
+    silence_emulate_with_files(body, &VirtualFiles::new(), limits)
+}
 
-    return Some(emu);
+#[allow(dead_code)]
+pub fn silence_emulate_with_files(body: String, files: &VirtualFiles, limits: ResourceLimits) -> Result<EmulatorState, Vec<Diagnostic>> {
+    match silence_emulate_with_provider("main.urcl", body, files, limits, &Lints::new()) {
+        (Some(emu), _) => Ok(emu),
+        (None, diagnostics) => Err(diagnostics),
+    }
 }
 
+// the warnings come back even when it compiled, the caller decides how to show them
 #[allow(dead_code)]
-pub fn silence_emulate(body: String) -> Result<EmulatorState, String> {
-    let src = Rc::from(body);
-    let toks = lexer::lex(&src);
-    let Parser {ast: program, err, ..} = ast::gen_ast(toks, src.clone());
+pub fn silence_emulate_with_provider(name: &str, src: String, provider: &dyn FileProvider, limits: ResourceLimits, lints: &Lints) -> (Option<EmulatorState>, Vec<Diagnostic>) {
+    let (program, diagnostics) = compile(name, src, provider, &limits, lints);
+    (program.map(|program| EmulatorState::new(program, DeviceHost::with_limits(&limits))), diagnostics)
+}
+
+// gives back the program if it compiled, and the errors and warnings either way
+pub fn compile(name: &str, src: String, provider: &dyn FileProvider, limits: &ResourceLimits, lints: &Lints) -> (Option<Program>, Vec<Diagnostic>) {
+    let files = Rc::new(SourceFiles::load(name, Rc::from(src), provider, limits.max_source));
+    let size = files.total_size();
+    if size > limits.max_source {
+        let mut err = ErrorContext::new();
+        err.error(&Token::new(Kind::EOF, &files.main().src[..0]), ErrorKind::ResourceLimit {
+            what: "Source size", value: size as u64, limit: limits.max_source as u64, unit: "bytes"
+        });
+        return (None, err.diagnostics(&files));
+    }
+
+    let toks = lexer::lex(&files.main().src);
+    let mut p = ast::gen_ast(toks, &files, provider, limits);
+    p.check_limits();
+    if !p.err.has_error() {
+        lint::lint(&mut p, lints);
+    }
+
+    let diagnostics = p.err.diagnostics(&files);
+    if p.err.has_error() {
+        return (None, diagnostics);
+    }
+    let Parser {ast: program, ..} = p;
+    (Some(program), diagnostics)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn emulate(src: &str) -> EmulatorState {
+        silence_emulate(src.to_string()).unwrap_or_else(|diagnostics| panic!("{:?}", diagnostics))
+    }
+
+    // runs to the HLT and gives back R1 to R3
+    fn regs(src: &str) -> [u64; 3] {
+        let mut emu = emulate(src);
+        assert_eq!(emu.run_for_steps(1000).result, StepResult::HLT);
+        [emu.regs[0], emu.regs[1], emu.regs[2]]
+    }
+
+    #[test]
+    fn loader_ports() {
+        // 4 instructions and 2 words of DW, the heap starts after them
+        assert_eq!(regs("IN R1 %INSTR\nIN R2 %NADDR\nHLT\nDW [1 2]\nHLT"), [4, 2, 0]);
+        let mut emu = emulate("IN R1 %DATA\nIN R2 %DATA\nIN R3 %DATA\nHLT");
+        emu.load_data(&[7, 8]);
+        assert_eq!(emu.run_for_steps(100).result, StepResult::HLT);
+        // an empty stream reads as 0
+        assert_eq!(emu.regs[..3], [7, 8, 0]);
+    }
+
+    #[test]
+    fn unsupported_port() {
+        let mut emu = emulate("OUT %BUS 1\nHLT");
+        assert_eq!(emu.run_for_steps(100).result, StepResult::Error);
+        let mut emu = emulate("IN R1 %BUS\nHLT");
+        assert_eq!(emu.run_for_steps(100).result, StepResult::Error);
+    }
+
+    const LOOP: &str = ".loop\nINC R1 R1\nJMP .loop";
+
+    #[test]
+    fn step_budget() {
+        let mut emu = emulate(LOOP);
+        let report = emu.run_for_steps(10);
+        assert_eq!((report.result, report.steps, report.cycles), (StepResult::Continue, 10, 10));
+        assert_eq!(emu.regs[0], 5);
+        // budgets count from where the last run stopped
+        let report = emu.run_for_steps(3);
+        assert_eq!((report.steps, emu.steps()), (3, 13));
+    }
+
+    #[test]
+    fn cycle_budget() {
+        let mut emu = emulate(LOOP);
+        emu.set_cycle_model(CycleModel::new().with_cost("INC", 3));
+        // INC JMP INC JMP is 8 cycles, the next INC would go over
+        let report = emu.run_for_cycles(10);
+        assert_eq!((report.result, report.steps, report.cycles), (StepResult::Continue, 4, 8));
+        assert_eq!(emu.cycles(), 8);
+    }
+
+    #[test]
+    fn budget_after_halt() {
+        let mut emu = emulate("INC R1 R1\nHLT");
+        // HLT stops the program without being a step of its own
+        let report = emu.run_for_steps(100);
+        assert_eq!((report.result, report.steps), (StepResult::HLT, 1));
+        let report = emu.run_for_cycles(100);
+        assert_eq!((report.result, report.steps), (StepResult::HLT, 0));
+    }
+
+    #[test]
+    fn out_of_bounds() {
+        for src in ["BITS 8\nLOD R1 99999\nHLT", "BITS 8\nSTR 200 1\nHLT", "BITS 8\nCPY 1 250\nHLT", "MOV SP 0\nPSH 1\nHLT", "POP R1\nHLT"] {
+            let mut emu = silence_emulate_with_limits(src.to_string(), ResourceLimits::untrusted()).unwrap();
+            assert_eq!(emu.run_for_steps(100).result, StepResult::Error, "{}", src);
+        }
+    }
+
+    #[test]
+    fn limits() {
+        for src in ["MINHEAP 2000000\nHLT", "MINSTACK 100000\nHLT", "MINREG 5000\nHLT", "DW [0]*2000000\nHLT"] {
+            let codes: Vec<_> = silence_emulate_with_limits(src.to_string(), ResourceLimits::untrusted()).unwrap_err().iter().map(|d| d.code).collect();
+            assert_eq!(codes, ["E0013"], "{}", src);
+            assert!(silence_emulate(src.to_string()).is_ok(), "{}", src);
+        }
+    }
+
+    #[test]
+    fn paged_heap() {
+        let src = "MINHEAP 1000000\nSTR M0 1\nSTR M999999 2\nLOD R1 M0\nLOD R2 M999999\nLOD R3 M500000\nHLT";
+        let mut emu = silence_emulate_with_limits(src.to_string(), ResourceLimits::untrusted()).unwrap();
+        assert_eq!(emu.run_for_steps(100).result, StepResult::HLT);
+        assert_eq!(emu.regs[..3], [1, 2, 0]);
+        assert_eq!(emu.heap.allocated_pages(), 2);
+    }
 
-    if err.has_error() {
-        return Err(err.to_string(&src));
+    #[test]
+    fn address_space() {
+        let mut emu = emulate("MINHEAP 4\nMINSTACK 4\nDW [5 6]\nSTR M1 7\nPSH 8\nLOD R1 SP\nMOV R2 SP\nHLT");
+        assert_eq!(emu.memory_map(), MemoryMap::new(2, 4, 4));
+        assert_eq!(emu.run_for_steps(100).result, StepResult::HLT);
+        // the stack grows down from the top and sp points at the last pushed word
+        assert_eq!(emu.regs[..2], [8, 9]);
+        let memory: Vec<_> = (0..11).map(|a| emu.read_memory(a)).collect();
+        assert_eq!(memory, [Some(5), Some(6), Some(0), Some(7), Some(0), Some(0), Some(0), Some(0), Some(0), Some(8), None]);
     }
 
-    Ok(EmulatorState::new(program, DeviceHost::new()))
+    #[test]
+    fn stack_models() {
+        let src = "MINSTACK 2\nMOV R1 SP\nPSH 1\nPSH 2\nMOV R2 SP\nPSH 3\nHLT";
+        let mut emu = emulate(src);
+        let top = emu.memory_map().end;
+        // sp starts one past the end and both words fit, the third overflows
+        assert_eq!(emu.run_for_steps(100).result, StepResult::Error);
+        assert_eq!(emu.regs[..2], [top, top - 2]);
+
+        let mut emu = emulate(src);
+        emu.set_stack_model(StackModel::Legacy);
+        // the lowest word is never used, so the second push already overflows
+        assert_eq!(emu.run_for_steps(100).result, StepResult::Error);
+        assert_eq!(emu.regs[..2], [top - 1, 0]);
+    }
+
+    #[test]
+    fn set_sp() {
+        // sp is a real address, moving it by hand and writing through it is the same as a push
+        assert_eq!(regs("MINSTACK 4\nMOV R1 SP\nSUB SP SP 2\nSTR SP 9\nPOP R2\nSUB R3 R1 SP\nHLT")[1..], [9, 1]);
+    }
+
+    #[test]
+    fn smod() {
+        assert_eq!(regs("BITS 8\nSMOD R1 -7 2\nSMOD R2 -128 -1\nSMOD R3 5 0\nHLT"), [0xff, 0, 0]);
+        assert_eq!(regs("BITS 8\nSMOD R1 7 -2\nSMOD R2 -7 -2\nHLT"), [1, 0xff, 0]);
+        assert_eq!(regs("BITS 64\nSMOD R1 -7 2\nSMOD R2 0x8000000000000000 -1\nSMOD R3 5 0\nHLT"), [u64::MAX, 0, 0]);
+    }
+
+    #[test]
+    fn divide_by_zero() {
+        assert_eq!(regs("BITS 8\nDIV R1 5 0\nSDIV R2 -5 0\nMOD R3 5 0\nHLT"), [0, 0, 0]);
+        assert_eq!(regs("BITS 64\nSDIV R1 0x8000000000000000 -1\nHLT"), [1 << 63, 0, 0]);
+    }
+
+    #[test]
+    fn umlt() {
+        assert_eq!(regs("BITS 8\nUMLT R1 200 200\nUMLT R2 255 255\nUMLT R3 15 15\nHLT"), [156, 254, 0]);
+        assert_eq!(regs("BITS 64\nUMLT R1 0xffffffffffffffff 0xffffffffffffffff\nUMLT R2 0x100000000 0x100000000\nHLT"), [u64::MAX - 1, 1, 0]);
+    }
+
+    #[test]
+    fn sumlt() {
+        assert_eq!(regs("BITS 8\nSUMLT R1 -128 -128\nSUMLT R2 -1 1\nSUMLT R3 127 127\nHLT"), [64, 0xff, 63]);
+        assert_eq!(regs("BITS 64\nSUMLT R1 0x8000000000000000 0x8000000000000000\nSUMLT R2 -1 1\nSUMLT R3 -1 -1\nHLT"), [1 << 62, u64::MAX, 0]);
+    }
+
+    #[test]
+    fn signed_below_64_bits() {
+        assert_eq!(regs("BITS 8\nSDIV R1 -6 2\nSDIV R2 -128 -1\nABS R3 -5\nHLT"), [0xfd, 0x80, 5]);
+        assert_eq!(regs("BITS 8\nSRS R1 0x80\nBSS R2 -16 2\nNEG R3 1\nHLT"), [0xc0, 0xfc, 0xff]);
+        assert_eq!(regs("BITS 8\nSSETL R1 -1 0\nSSETG R2 -1 0\nSSETGE R3 -128 127\nHLT"), [u64::MAX, 0, 0]);
+        assert_eq!(regs("BITS 8\nSBRL .a -1 0\nHLT\n.a\nBRN .b 0xff\nHLT\n.b\nIMM R1 1\nHLT"), [1, 0, 0]);
+    }
+
+    fn output(src: &str) -> String {
+        let mut emu = emulate(src);
+        assert_eq!(emu.run_for_steps(1000).result, StepResult::HLT);
+        emu.get_output()
+    }
+
+    #[test]
+    fn floats() {
+        assert_eq!(output("FLOAT\nBITS 16\nFADD R1 1.5 0.25\nOUT %FLOAT R1\nOUT %TEXT ' '\nFDIV R1 1.0 3.0\nOUT %FLOAT R1\nHLT"), "1.75 0.3333");
+        assert_eq!(output("FLOAT\nBITS 32\nITOF R1 -3\nFMLT R1 R1 0.5\nOUT %FLOAT R1\nFTOI R2 R1\nOUT %TEXT ' '\nOUT %NUMB R2\nHLT"), "-1.5 4294967295");
+        assert_eq!(output("FLOAT\nBITS 64\nFSQRT R1 2.0\nOUT %FLOAT R1\nHLT"), "1.4142135623730951");
+        assert_eq!(regs("FLOAT\nBITS 32\nFBRL .a -1.0 0.5\nHLT\n.a\nIMM R1 1\nHLT"), [1, 0, 0]);
+    }
+
+    #[test]
+    fn floats_need_the_header() {
+        let codes = |src: &str| silence_emulate(src.to_string()).unwrap_err().iter().map(|d| d.code).collect::<Vec<_>>();
+        assert_eq!(codes("FADD R1 R2 R3\nHLT"), ["E0014"]);
+        assert_eq!(codes("FLOAT\nBITS 8\nFADD R1 R2 R3\nHLT"), ["E0015"]);
+    }
+
+    #[test]
+    fn label_operands() {
+        // every operand of every instruction goes through the same fixups, destinations included
+        assert_eq!(regs("MINHEAP 2\nLOD R1 .d\nMOV R2 .d\nLLOD R3 .d 1\nHLT\n.d\nDW [5 6]"), [5, 0, 6]);
+        assert_eq!(regs("MINHEAP 2\nCPY M0 .d\nSTR M1 .e\nLOD R1 M0\nLOD R2 M1\nHLT\n.d\nDW 7\n.e\nDW 8"), [7, 1, 0]);
+    }
 }
@@ -1,8 +1,8 @@
-use std::{fmt::{Debug, Display}, collections::HashMap};
+use std::fmt::{Debug, Display};
 
 use strum_macros::Display;
 
-use super::{lexer::{UToken}, ast::AstOp};
+use super::{lexer::{UToken, IntError}, ast::AstOp, source::SourceFiles, diagnostic::{Diagnostic, Location}};
 
 #[allow(dead_code)]
 pub struct ErrorContext<'a> {
@@ -10,6 +10,12 @@ pub struct ErrorContext<'a> {
     has_error: bool,
 }
 
+impl <'a> Default for ErrorContext<'a> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[allow(dead_code)]
 impl <'a> ErrorContext<'a> {
     pub fn new() -> Self {
@@ -17,34 +23,59 @@ impl <'a> ErrorContext<'a> {
     }
 
     pub fn error(&mut self, token: &UToken<'a>, kind: ErrorKind<'a>) {
-        self.errors.push(Error {kind, span: token.str, level: ErrorLevel::Error});
+        self.push(token, kind, ErrorLevel::Error);
         self.has_error = true;
     }
     pub fn warn(&mut self, token: &UToken<'a>, kind: ErrorKind<'a>) {
-        self.errors.push(Error {kind, span: token.str, level: ErrorLevel::Warning});
+        self.push(token, kind, ErrorLevel::Warning);
     }
     pub fn info(&mut self, token: &UToken<'a>, kind: ErrorKind<'a>) {
-        self.errors.push(Error {kind, span: token.str, level: ErrorLevel::Info});
+        self.push(token, kind, ErrorLevel::Info);
+    }
+    fn push(&mut self, token: &UToken<'a>, kind: ErrorKind<'a>, level: ErrorLevel) {
+        let mut error = Error {kind, span: token.str, level, notes: Vec::new(), related: Vec::new()};
+        // errors inside a macro body also point at the call that expanded it
+        if let Some(call) = token.origin {
+            error.related.push((call, ErrorKind::InMacroExpansion));
+        }
+        self.errors.push(error);
+    }
+    // extra text for the last error
+    pub fn note(&mut self, note: impl Into<String>) {
+        if let Some(error) = self.errors.last_mut() {
+            error.notes.push(note.into());
+        }
+    }
+    // a "did you mean" note for the last error, if one of the names is close enough to be a typo
+    pub fn suggest<S: AsRef<str>>(&mut self, name: &str, candidates: &[S]) {
+        if let Some(close) = closest(name, candidates) {
+            self.did_you_mean(close);
+        }
+    }
+    pub fn did_you_mean(&mut self, name: &str) {
+        self.note(format!("did you mean '{}'?", name));
     }
     pub fn has_error(&self) -> bool {
         self.has_error
     }
 
-    pub fn to_string(&self, src: &str) -> String {
-        let mut linenos = HashMap::new();
-        for (i, line) in src.lines().enumerate() {
-            linenos.insert(line.as_ptr(), i+1);
-        }
-
-        let mut output = String::new();
-        for error in &self.errors {
-            let (line, col) = line(src, error.span);
-            let lineno = linenos.get(&line.as_ptr()).map_or(0, |i|*i);
-            let lineno = format!("{} ", lineno);
-
-            crate::out_err(&mut output, error, &lineno, line, col);
-        }
-        output
+    // the spans only mean something next to the source, so they get turned into line and column here.
+    // they come out in the order they are in the source and not the order they were found in, spans
+    // outside every file go at the end of the main file like Location::new puts them
+    pub fn diagnostics(&self, files: &SourceFiles) -> Vec<Diagnostic> {
+        let mut errors: Vec<&Error> = self.errors.iter().collect();
+        errors.sort_by_key(|error| files.locate(error.span).map_or((0, usize::MAX), |(file, _, _, offset)| (file, offset)));
+        errors.into_iter().map(|error| {
+            let (code, name) = error.kind.code();
+            Diagnostic {
+                severity: error.level,
+                code, name,
+                message: error.kind.to_string(),
+                location: Location::new(files, error.span),
+                notes: error.notes.clone(),
+                secondary: error.related.iter().map(|(span, kind)| (Location::new(files, span), kind.to_string())).collect(),
+            }
+        }).collect()
     }
 }
 
@@ -58,7 +89,7 @@ pub fn get_indent_level(src: &str) -> usize {
     tabs
 }
 
-#[derive(Debug, Display)]
+#[derive(Debug, Display, Clone, Copy, PartialEq, Eq)]
 pub enum ErrorLevel {
     Info, Warning, Error
 }
@@ -67,7 +98,9 @@ pub enum ErrorLevel {
 pub struct Error<'a> {
     pub kind: ErrorKind<'a>,
     pub span: &'a str, // start and end of code that caused the error
-    pub level: ErrorLevel
+    pub level: ErrorLevel,
+    pub notes: Vec<String>,
+    pub related: Vec<(&'a str, ErrorKind<'a>)>, // other code that explains this error
 }
 
 #[allow(dead_code)]
@@ -85,8 +118,108 @@ pub enum ErrorKind<'a> {
     EOFBeforeEndOfString,
     EOFBeforeEndOfChar,
     DuplicatedLabelName,
+    ResourceLimit{what: &'a str, value: u64, limit: u64, unit: &'a str},
+    FloatNotEnabled,
+    FloatWidth(u64),
+    IncludeNotFound(String),
+    IncludeCycle,
+    ExpectedIncludePath,
+    MacroNoEnding,
+    MacroNoName,
+    InvalidMacroParameter,
+    DuplicatedMacroName,
+    MacroArguments{expected: usize, actual: usize},
+    MacroTooDeep,
+    InMacroExpansion,
+    InvalidCondition,
+    IfNoEnding,
+    NoMatchingIf,
+    DuplicatedElse,
+    UndefinedName,
+    InvalidExpression,
+    ParenNoEnding,
+    DivisionByZero,
+    Overflow(u64),
+    UnknownConstant,
+    NoMatchingSquare,
+    InvalidRepeat,
+    InvalidNumber(IntError),
+    UnexpectedToken,
+    ExpectedHeaderValue,
+    DefineNoName,
+    InvalidRegAlias,
+    RegisterAboveMinreg{reg: u64, minreg: u64},
+    Truncated(u64),
+    UnusedLabel,
+    UnreachableCode,
+    SelfJump,
+    WriteToR0,
+    ImmediateDestination,
+    UnsupportedPort{inst: &'a str, port: String},
+    RecursiveDefine,
+    ExpressionTooDeep,
     YoMamma
 }
+impl <'a> ErrorKind<'a> {
+    // codes for looking errors up and for tools to match on. they never change, new kinds get the next number
+    pub fn code(&self) -> (&'static str, &'static str) {
+        match self {
+            ErrorKind::UnexpectedMacro => ("E0001", "UnexpectedMacro"),
+            ErrorKind::NotEnoughOperands => ("E0002", "NotEnoughOperands"),
+            ErrorKind::ToManyOperands => ("E0003", "ToManyOperands"),
+            ErrorKind::InvalidOperandType { .. } => ("E0004", "InvalidOperandType"),
+            ErrorKind::InvalidOperand => ("E0005", "InvalidOperand"),
+            ErrorKind::UndefinedLabel => ("E0006", "UndefinedLabel"),
+            ErrorKind::UnknownPort => ("E0007", "UnknownPort"),
+            ErrorKind::UnknownInstruction => ("E0008", "UnknownInstruction"),
+            ErrorKind::DWNoEnding => ("E0009", "DWNoEnding"),
+            ErrorKind::EOFBeforeEndOfString => ("E0010", "EOFBeforeEndOfString"),
+            ErrorKind::EOFBeforeEndOfChar => ("E0011", "EOFBeforeEndOfChar"),
+            ErrorKind::DuplicatedLabelName => ("E0012", "DuplicatedLabelName"),
+            ErrorKind::ResourceLimit { .. } => ("E0013", "ResourceLimit"),
+            ErrorKind::FloatNotEnabled => ("E0014", "FloatNotEnabled"),
+            ErrorKind::FloatWidth(_) => ("E0015", "FloatWidth"),
+            ErrorKind::IncludeNotFound(_) => ("E0016", "IncludeNotFound"),
+            ErrorKind::IncludeCycle => ("E0017", "IncludeCycle"),
+            ErrorKind::ExpectedIncludePath => ("E0018", "ExpectedIncludePath"),
+            ErrorKind::MacroNoEnding => ("E0019", "MacroNoEnding"),
+            ErrorKind::MacroNoName => ("E0020", "MacroNoName"),
+            ErrorKind::InvalidMacroParameter => ("E0021", "InvalidMacroParameter"),
+            ErrorKind::DuplicatedMacroName => ("E0022", "DuplicatedMacroName"),
+            ErrorKind::MacroArguments { .. } => ("E0023", "MacroArguments"),
+            ErrorKind::MacroTooDeep => ("E0024", "MacroTooDeep"),
+            ErrorKind::InMacroExpansion => ("E0025", "InMacroExpansion"),
+            ErrorKind::InvalidCondition => ("E0026", "InvalidCondition"),
+            ErrorKind::IfNoEnding => ("E0027", "IfNoEnding"),
+            ErrorKind::NoMatchingIf => ("E0028", "NoMatchingIf"),
+            ErrorKind::DuplicatedElse => ("E0029", "DuplicatedElse"),
+            ErrorKind::UndefinedName => ("E0030", "UndefinedName"),
+            ErrorKind::InvalidExpression => ("E0031", "InvalidExpression"),
+            ErrorKind::ParenNoEnding => ("E0032", "ParenNoEnding"),
+            ErrorKind::DivisionByZero => ("E0033", "DivisionByZero"),
+            ErrorKind::Overflow(_) => ("E0034", "Overflow"),
+            ErrorKind::UnknownConstant => ("E0035", "UnknownConstant"),
+            ErrorKind::NoMatchingSquare => ("E0036", "NoMatchingSquare"),
+            ErrorKind::InvalidRepeat => ("E0037", "InvalidRepeat"),
+            ErrorKind::InvalidNumber(_) => ("E0038", "InvalidNumber"),
+            ErrorKind::UnexpectedToken => ("E0039", "UnexpectedToken"),
+            ErrorKind::ExpectedHeaderValue => ("E0040", "ExpectedHeaderValue"),
+            ErrorKind::DefineNoName => ("E0041", "DefineNoName"),
+            ErrorKind::InvalidRegAlias => ("E0042", "InvalidRegAlias"),
+            ErrorKind::RegisterAboveMinreg { .. } => ("E0043", "RegisterAboveMinreg"),
+            ErrorKind::Truncated(_) => ("E0044", "Truncated"),
+            ErrorKind::YoMamma => ("E0045", "YoMamma"),
+            ErrorKind::UnusedLabel => ("E0046", "UnusedLabel"),
+            ErrorKind::UnreachableCode => ("E0047", "UnreachableCode"),
+            ErrorKind::SelfJump => ("E0048", "SelfJump"),
+            ErrorKind::WriteToR0 => ("E0049", "WriteToR0"),
+            ErrorKind::ImmediateDestination => ("E0050", "ImmediateDestination"),
+            ErrorKind::UnsupportedPort { .. } => ("E0051", "UnsupportedPort"),
+            ErrorKind::RecursiveDefine => ("E0052", "RecursiveDefine"),
+            ErrorKind::ExpressionTooDeep => ("E0053", "ExpressionTooDeep"),
+        }
+    }
+}
 impl <'a> Display for ErrorKind<'a> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -102,29 +235,112 @@ impl <'a> Display for ErrorKind<'a> {
             ErrorKind::UndefinedLabel => write!(f, "Undefined label"),
             ErrorKind::DuplicatedLabelName => write!(f, "Duplicated label name"),
             ErrorKind::UnknownInstruction => write!(f, "Unknown instruction"),
+            ErrorKind::FloatNotEnabled => write!(f, "Floats need the FLOAT header"),
+            ErrorKind::FloatWidth(bits) => write!(f, "Floats need BITS 16, 32 or 64 but BITS is {}", bits),
+            ErrorKind::ResourceLimit { what, value, limit, unit } => write!(f, "{} of {} exceeds the limit of {} {}", what, value, limit, unit),
+            ErrorKind::IncludeNotFound(path) => write!(f, "Cannot find file \"{}\"", path),
+            ErrorKind::IncludeCycle => write!(f, "Include cycle, this file is already being included"),
+            ErrorKind::ExpectedIncludePath => write!(f, "Expected a path in quotes after '@include'"),
+            ErrorKind::MacroNoEnding => write!(f, "Missing '@endmacro'"),
+            ErrorKind::MacroNoName => write!(f, "Expected a name after '@macro'"),
+            ErrorKind::InvalidMacroParameter => write!(f, "Macro parameters have to be names"),
+            ErrorKind::DuplicatedMacroName => write!(f, "Duplicated macro name"),
+            ErrorKind::MacroArguments { expected, actual } => write!(f, "Expected {} macro argument{} but got {}", expected, if *expected == 1 {""} else {"s"}, actual),
+            ErrorKind::MacroTooDeep => write!(f, "Macros nested too deep, does one call itself?"),
+            ErrorKind::InMacroExpansion => write!(f, "In this macro call"),
+            ErrorKind::InvalidCondition => write!(f, "Invalid condition, expected a value or two values compared with ==, !=, <, <=, > or >="),
+            ErrorKind::IfNoEnding => write!(f, "Missing '@endif'"),
+            ErrorKind::NoMatchingIf => write!(f, "No '@if' to match"),
+            ErrorKind::DuplicatedElse => write!(f, "Only one '@else' per '@if'"),
+            ErrorKind::UndefinedName => write!(f, "Undefined name, it needs an '@define' or '@reg' first"),
+            ErrorKind::InvalidExpression => write!(f, "Invalid expression"),
+            ErrorKind::ParenNoEnding => write!(f, "Missing ')'"),
+            ErrorKind::DivisionByZero => write!(f, "Division by zero"),
+            ErrorKind::Overflow(bits) => write!(f, "Value does not fit in {} bits", bits),
+            ErrorKind::UnknownConstant => write!(f, "Unknown constant"),
+            ErrorKind::NoMatchingSquare => write!(f, "No '[' to match"),
+            ErrorKind::InvalidRepeat => write!(f, "Repeat count cannot be negative"),
+            ErrorKind::InvalidNumber(IntError::NoDigits) => write!(f, "Expected digits after the prefix"),
+            ErrorKind::InvalidNumber(IntError::InvalidDigit) => write!(f, "Invalid digit for the base of this number"),
+            ErrorKind::InvalidNumber(IntError::TooLarge) => write!(f, "Number does not fit in 64 bits"),
+            ErrorKind::InvalidNumber(IntError::Underscore) => write!(f, "'_' can only go between two digits"),
+            ErrorKind::UnexpectedToken => write!(f, "Expected an instruction, label, header or macro at the start of a line"),
+            ErrorKind::ExpectedHeaderValue => write!(f, "Expected a number after the header"),
+            ErrorKind::DefineNoName => write!(f, "Expected a name after '@define'"),
+            ErrorKind::InvalidRegAlias => write!(f, "Expected '@reg name register'"),
+            ErrorKind::RegisterAboveMinreg { reg, minreg } => write!(f, "R{} is above MINREG {}, MINREG is raised to {}", reg, minreg, reg),
+            ErrorKind::Truncated(bits) => write!(f, "Value does not fit in {} bits, only the low {} bits are kept", bits, bits),
+            ErrorKind::UnusedLabel => write!(f, "Label is never used"),
+            ErrorKind::UnreachableCode => write!(f, "Unreachable code, nothing jumps here and the instruction before never continues"),
+            ErrorKind::SelfJump => write!(f, "Jumps to itself forever, HLT stops the program"),
+            ErrorKind::WriteToR0 => write!(f, "R0 is always 0, writing to it does nothing"),
+            ErrorKind::ImmediateDestination => write!(f, "Destination is not a register, the result goes nowhere"),
+            ErrorKind::UnsupportedPort { inst, port } => write!(f, "No device supports {} on {}", inst, port),
+            ErrorKind::RecursiveDefine => write!(f, "Define refers to itself"),
+            ErrorKind::ExpressionTooDeep => write!(f, "Expression nested too deep"),
             ErrorKind::YoMamma => write!(f, "Token too large")
         }
     }
 }
-const LF_B: u8 = '\n' as u8;
 
 pub fn str_width(src: &str) -> usize {
     src.chars().count()
 }
 
-fn line<'a>(src: &'a str, span: &'a str) -> (&'a str, usize) {
-    let mut offset = span.as_ptr() as usize - src.as_ptr() as usize;
-    if offset >= src.len() {
-        offset = src.len();
+// the candidate with the fewest edits from name, ignoring case. a third of the name can be wrong,
+// more than that and its probably a different name and not a typo
+pub fn closest<'c, S: AsRef<str>>(name: &str, candidates: &'c [S]) -> Option<&'c str> {
+    let len = name.chars().count();
+    let max = (len / 3).max(1);
+    candidates.iter()
+        .map(|c| c.as_ref())
+        .filter(|c| *c != name)
+        // every char more or less is an edit, so most names can be skipped without comparing them
+        .filter(|c| c.chars().count().abs_diff(len) <= max)
+        .map(|c| (edit_distance(name, c), c))
+        .filter(|(d, _)| *d <= max)
+        // ties go to the first name alphabetically, candidates often come out of a hash map in any order
+        .min_by_key(|(d, c)| (*d, *c))
+        .map(|(_, c)| c)
+}
+
+// levenshtein distance in chars, with swapping two letters next to each other counting as one edit
+fn edit_distance(a: &str, b: &str) -> usize {
+    let lower = |s: &str| -> Vec<char> {s.chars().flat_map(char::to_lowercase).collect()};
+    let (a, b) = (lower(a), lower(b));
+    // d[i][j] is the distance between the first i chars of a and the first j of b
+    let mut d = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for (i, row) in d.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    for (j, cell) in d[0].iter_mut().enumerate() {
+        *cell = j;
     }
-    drop(span);
-    let mut start = offset;
-    while start > 0 && src.as_bytes()[start - 1] != LF_B  {
-        start -= 1;
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let cost = (a[i - 1] != b[j - 1]) as usize;
+            d[i][j] = (d[i - 1][j] + 1).min(d[i][j - 1] + 1).min(d[i - 1][j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                d[i][j] = d[i][j].min(d[i - 2][j - 2] + 1);
+            }
+        }
     }
-    let mut end = offset;
-    while end < src.len() && src.as_bytes()[end] != LF_B {
-        end += 1;
+    d[a.len()][b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn closest_names() {
+        assert_eq!(closest("lop", &["loop", "stop", "end"]), Some("loop"));
+        assert_eq!(closest("LOOP", &["loop"]), Some("loop"));
+        assert_eq!(closest("loop", &["loop"]), None); // the same name isnt a suggestion
+        assert_eq!(closest("ADDD", &["ADD", "SUB"]), Some("ADD"));
+        // swapping two letters is one edit
+        assert_eq!(closest("HTL", &["HLT"]), Some("HLT"));
+        assert_eq!(closest("abc", &["abd", "abe"]), Some("abd"));
+        assert_eq!(closest("xyz", &["loop"]), None);
     }
-    (&src[start..end], src[start..offset].chars().count())
 }
@@ -0,0 +1,262 @@
+use super::{lexer::{self, Token, Kind, UToken}, errorcontext::ErrorKind};
+
+// assemble time expressions like `.table+3`, `@max-1` or `(WIDTH*HEIGHT)`. labels in them might not be
+// defined yet, so they stay a tree until they can be evaluated
+
+// deep enough for defines built out of other defines, shallow enough that `@define A A+1` still stops
+pub const MAX_DEPTH: usize = 64;
+// parentheses and signs inside one expression, nobody writes more by hand and every level is a stack frame
+const MAX_NESTING: usize = 256;
+
+#[derive(Debug, Clone, Copy)]
+pub enum BinOp {
+    Add, Sub, Mul, Div, And, Or, Xor, Shl, Shr,
+}
+
+// the tokens are kept for pointing errors at
+#[derive(Debug, Clone)]
+pub enum Expr<'a> {
+    Value(i128),
+    Label(String, UToken<'a>), // the full name, ..local labels are already qualified
+    Const(UToken<'a>),
+    Neg(Box<Expr<'a>>, UToken<'a>),
+    Binary(BinOp, Box<Expr<'a>>, Box<Expr<'a>>, UToken<'a>),
+}
+
+pub type ExprError<'a> = (UToken<'a>, ErrorKind<'a>);
+
+// what the expression parser cant work out by itself, labels, @defines, @bits and relatives
+pub trait Symbols<'a> {
+    fn symbol(&mut self, tok: &UToken<'a>, depth: usize) -> Result<Expr<'a>, ExprError<'a>>;
+}
+
+pub fn is_binary(kind: Kind) -> bool {
+    binary(kind).is_some()
+}
+
+// higher binds tighter, same order as c
+fn binary(kind: Kind) -> Option<(u8, BinOp)> {
+    Some(match kind {
+        Kind::Star => (5, BinOp::Mul),
+        Kind::Slash => (5, BinOp::Div),
+        Kind::Plus => (4, BinOp::Add),
+        Kind::Minus => (4, BinOp::Sub),
+        Kind::Shl => (3, BinOp::Shl),
+        Kind::Shr => (3, BinOp::Shr),
+        Kind::And => (2, BinOp::And),
+        Kind::Xor => (1, BinOp::Xor),
+        Kind::Or => (0, BinOp::Or),
+        _ => return None,
+    })
+}
+
+pub fn parse<'a>(toks: &[UToken<'a>], symbols: &mut dyn Symbols<'a>, depth: usize) -> Result<Expr<'a>, ExprError<'a>> {
+    let toks: Vec<_> = toks.iter().filter(|t| !matches!(t.kind, Kind::White | Kind::Comment)).cloned().collect();
+    let mut p = ExprParser { toks: &toks, i: 0, symbols, depth, nesting: 0 };
+    let expr = p.binary(0)?;
+    match toks.get(p.i) {
+        Some(tok) => Err((tok.clone(), ErrorKind::InvalidExpression)),
+        None => Ok(expr),
+    }
+}
+
+struct ExprParser<'a, 't, 's> {
+    toks: &'t [UToken<'a>],
+    i: usize,
+    symbols: &'s mut dyn Symbols<'a>,
+    depth: usize,
+    nesting: usize, // how many ( and - the current primary is inside of
+}
+
+impl <'a, 't, 's> ExprParser<'a, 't, 's> {
+    fn next(&mut self) -> Result<UToken<'a>, ExprError<'a>> {
+        match self.toks.get(self.i) {
+            Some(tok) => {
+                self.i += 1;
+                Ok(tok.clone())
+            },
+            None => Err((self.toks.last().cloned().unwrap_or(Token::new(Kind::EOF, "")), ErrorKind::InvalidExpression)),
+        }
+    }
+
+    fn binary(&mut self, min: u8) -> Result<Expr<'a>, ExprError<'a>> {
+        let mut lhs = self.primary()?;
+        while let Some(tok) = self.toks.get(self.i).cloned() {
+            let (prec, op) = match binary(tok.kind) {
+                Some(op) => op,
+                // `(a -1)` lexes as a and the number -1, which is the same as adding them
+                None if matches!(tok.kind, Kind::Int(_)) && tok.str.starts_with(['+', '-']) => (4, BinOp::Add),
+                None => break,
+            };
+            if prec < min {
+                break;
+            }
+            if binary(tok.kind).is_some() {
+                self.i += 1;
+            }
+            let rhs = self.binary(prec + 1)?;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs), tok);
+        }
+        Ok(lhs)
+    }
+
+    // an error ends the whole expression, so only the way out without one has to step back out
+    fn enter(&mut self, tok: &UToken<'a>) -> Result<(), ExprError<'a>> {
+        if self.nesting >= MAX_NESTING {
+            return Err((tok.clone(), ErrorKind::ExpressionTooDeep));
+        }
+        self.nesting += 1;
+        Ok(())
+    }
+
+    fn primary(&mut self) -> Result<Expr<'a>, ExprError<'a>> {
+        let tok = self.next()?;
+        match tok.kind {
+            Kind::Int(_) => Ok(Expr::Value(lexer::int_value(&tok))),
+            Kind::BadInt(err) => Err((tok, ErrorKind::InvalidNumber(err))),
+            Kind::Minus => {
+                self.enter(&tok)?;
+                let expr = self.primary()?;
+                self.nesting -= 1;
+                Ok(Expr::Neg(Box::new(expr), tok))
+            },
+            Kind::LParen => {
+                self.enter(&tok)?;
+                let expr = self.binary(0)?;
+                self.nesting -= 1;
+                match self.toks.get(self.i) {
+                    Some(end) if end.kind == Kind::RParen => {
+                        self.i += 1;
+                        Ok(expr)
+                    },
+                    _ => Err((tok, ErrorKind::ParenNoEnding)),
+                }
+            },
+            // 'a' is three tokens
+            Kind::Char => {
+                let value = match self.next()?.kind {
+                    Kind::Text => self.toks[self.i - 1].str.chars().next().unwrap(),
+                    Kind::Escape(c) => c,
+                    _ => return Err((tok, ErrorKind::EOFBeforeEndOfChar)),
+                };
+                match self.next() {
+                    Ok(end) if end.kind == Kind::Char => Ok(Expr::Value(value as i128)),
+                    _ => Err((tok, ErrorKind::EOFBeforeEndOfChar)),
+                }
+            },
+            Kind::Label | Kind::Macro | Kind::Name | Kind::Relative(_) => self.symbols.symbol(&tok, self.depth),
+            _ => Err((tok, ErrorKind::InvalidExpression)),
+        }
+    }
+}
+
+impl <'a> Expr<'a> {
+    // symbol gives the value of a label or constant by name, or None if it isnt known (yet)
+    pub fn eval(&self, symbol: &dyn Fn(&str) -> Option<u64>) -> Result<i128, ExprError<'a>> {
+        match self {
+            Expr::Value(v) => Ok(*v),
+            Expr::Label(name, tok) => symbol(name).map(|v| v as i128).ok_or_else(|| (tok.clone(), ErrorKind::UndefinedLabel)),
+            Expr::Const(tok) => symbol(tok.str).map(|v| v as i128).ok_or_else(|| (tok.clone(), ErrorKind::UnknownConstant)),
+            Expr::Neg(a, tok) => a.eval(symbol)?.checked_neg().ok_or_else(|| (tok.clone(), ErrorKind::Overflow(128))),
+            Expr::Binary(op, a, b, tok) => {
+                let (a, b) = (a.eval(symbol)?, b.eval(symbol)?);
+                let value = match op {
+                    BinOp::Add => a.checked_add(b),
+                    BinOp::Sub => a.checked_sub(b),
+                    BinOp::Mul => a.checked_mul(b),
+                    BinOp::Div if b == 0 => return Err((tok.clone(), ErrorKind::DivisionByZero)),
+                    BinOp::Div => a.checked_div(b),
+                    BinOp::And => Some(a & b),
+                    BinOp::Or => Some(a | b),
+                    BinOp::Xor => Some(a ^ b),
+                    BinOp::Shl => u32::try_from(b).ok().and_then(|b| a.checked_mul(2i128.checked_pow(b)?)),
+                    BinOp::Shr => u32::try_from(b).ok().map(|b| a >> b.min(127)),
+                };
+                value.ok_or_else(|| (tok.clone(), ErrorKind::Overflow(128)))
+            },
+        }
+    }
+}
+
+impl <'a> Expr<'a> {
+    // the full name of the label tok stands for, ..locals are qualified when parsed so tok alone isnt enough
+    pub fn label_name(&self, tok: &UToken) -> Option<&str> {
+        match self {
+            Expr::Label(name, t) if std::ptr::eq(t.str, tok.str) => Some(name),
+            Expr::Neg(a, _) => a.label_name(tok),
+            Expr::Binary(_, a, b, _) => a.label_name(tok).or_else(|| b.label_name(tok)),
+            _ => None,
+        }
+    }
+}
+
+// a value as a word, negative ones in two's complement. None if it doesnt fit in BITS either way
+pub fn fit(value: i128, bits: u64) -> Option<u64> {
+    let bits = bits.clamp(1, 64) as u32;
+    let min = -(1i128 << (bits - 1));
+    let max = (1i128 << bits) - 1;
+    (min..=max).contains(&value).then_some(value as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // labels are numbered by their length so there is something to look up
+    struct Labels;
+    impl <'a> Symbols<'a> for Labels {
+        fn symbol(&mut self, tok: &UToken<'a>, _depth: usize) -> Result<Expr<'a>, ExprError<'a>> {
+            match tok.kind {
+                Kind::Label => Ok(Expr::Label(tok.str.to_string(), tok.clone())),
+                _ => Err((tok.clone(), ErrorKind::UndefinedName)),
+            }
+        }
+    }
+
+    fn eval(src: &str) -> Result<i128, ErrorKind<'_>> {
+        let toks: Vec<_> = lexer::lex(src).into_iter().filter(|t| t.kind != Kind::EOF).collect();
+        parse(&toks, &mut Labels, 0)
+            .and_then(|expr| expr.eval(&|name| Some(name.len() as u64)))
+            .map_err(|(_, kind)| kind)
+    }
+
+    #[test]
+    fn precedence() {
+        assert_eq!(eval("1+2*3").unwrap(), 7);
+        assert_eq!(eval("(1+2)*3").unwrap(), 9);
+        assert_eq!(eval("1<<2+1").unwrap(), 8);
+        assert_eq!(eval("6&3|8^1").unwrap(), 11);
+        assert_eq!(eval("10/3-1").unwrap(), 2);
+        assert_eq!(eval("-(2*3)").unwrap(), -6);
+        assert_eq!(eval("--1").unwrap(), 1);
+        assert_eq!(eval("'a'+1").unwrap(), 98);
+        assert_eq!(eval("(.abc)*2").unwrap(), 8);
+        assert_eq!(eval(".a-b * 2").unwrap(), 8);
+    }
+
+    #[test]
+    fn overflow() {
+        assert!(matches!(eval("0xffffffffffffffff*0xffffffffffffffff*0xffffffffffffffff"), Err(ErrorKind::Overflow(128))));
+        assert!(matches!(eval("1<<127"), Err(ErrorKind::Overflow(128))));
+        assert!(matches!(eval("1<<200"), Err(ErrorKind::Overflow(128))));
+        assert!(matches!(eval("1/0"), Err(ErrorKind::DivisionByZero)));
+        assert_eq!(eval("1<<64").unwrap(), 1 << 64);
+        assert_eq!(eval("-1>>200").unwrap(), -1);
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(matches!(eval("(1+2"), Err(ErrorKind::ParenNoEnding)));
+        assert!(matches!(eval("1+"), Err(ErrorKind::InvalidExpression)));
+        assert!(matches!(eval("1 2"), Err(ErrorKind::InvalidExpression)));
+        assert!(matches!(eval("NAME"), Err(ErrorKind::UndefinedName)));
+    }
+
+    #[test]
+    fn nesting() {
+        let deep = |n| format!("{}1{}", "(".repeat(n), ")".repeat(n));
+        assert_eq!(eval(&deep(MAX_NESTING)).unwrap(), 1);
+        assert!(matches!(eval(&deep(50_000)), Err(ErrorKind::ExpressionTooDeep)));
+        assert!(matches!(eval(&format!("{}1", "-".repeat(100_000))), Err(ErrorKind::ExpressionTooDeep)));
+    }
+}
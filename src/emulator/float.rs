@@ -0,0 +1,129 @@
+// words hold IEEE-754 floats at the program width: half for 16 bits, single for 32 and double for 64
+
+pub fn supported_width(bits: u64) -> bool {
+    matches!(bits, 16 | 32 | 64)
+}
+
+pub fn to_float(value: u64, bits: u64) -> f64 {
+    match bits {
+        16 => f16_to_f32(value as u16) as f64,
+        32 => f32::from_bits(value as u32) as f64,
+        _ => f64::from_bits(value),
+    }
+}
+
+pub fn from_float(value: f64, bits: u64) -> u64 {
+    match bits {
+        16 => f32_to_f16(value as f32) as u64,
+        32 => (value as f32).to_bits() as u64,
+        _ => value.to_bits(),
+    }
+}
+
+// prints the shortest text that reads back as the same float at that width. rust already does that
+// for f32 and f64, halves try more and more digits (5 is always enough) until one reads back the same
+pub fn format(value: u64, bits: u64) -> String {
+    match bits {
+        16 => {
+            let float = to_float(value, 16);
+            if !float.is_finite() {
+                return (float as f32).to_string();
+            }
+            (1..=5).map(|digits| format!("{:.*e}", digits - 1, float).parse::<f64>().unwrap())
+                .find(|short| from_float(*short, 16) == value & 0xffff)
+                .unwrap_or(float).to_string()
+        },
+        32 => f32::from_bits(value as u32).to_string(),
+        _ => f64::from_bits(value).to_string(),
+    }
+}
+
+fn f16_to_f32(half: u16) -> f32 {
+    let sign = ((half >> 15) as u32) << 31;
+    let exp = ((half >> 10) & 0x1f) as u32;
+    let frac = (half & 0x3ff) as u32;
+
+    let bits = match (exp, frac) {
+        (0, 0) => sign,
+        (0, _) => {
+            // subnormal, shift it up until it is a normal f32
+            let mut exp = 127 - 15 + 1;
+            let mut frac = frac;
+            while frac & 0x400 == 0 {
+                frac <<= 1;
+                exp -= 1;
+            }
+            sign | (exp << 23) | ((frac & 0x3ff) << 13)
+        },
+        (0x1f, _) => sign | 0x7f80_0000 | (frac << 13),
+        _ => sign | ((exp + 127 - 15) << 23) | (frac << 13),
+    };
+    f32::from_bits(bits)
+}
+
+fn f32_to_f16(value: f32) -> u16 {
+    let bits = value.to_bits();
+    let sign = ((bits >> 16) & 0x8000) as u16;
+    let exp = ((bits >> 23) & 0xff) as i32;
+    let frac = bits & 0x7f_ffff;
+
+    if exp == 0xff {
+        // keep nans as nans
+        return sign | 0x7c00 | if frac != 0 {0x200} else {0};
+    }
+
+    let exp = exp - 127 + 15;
+    if exp >= 0x1f {
+        return sign | 0x7c00;
+    }
+    if exp <= 0 {
+        if exp < -10 {
+            return sign;
+        }
+        let frac = frac | 0x80_0000;
+        let shift = (14 - exp) as u32;
+        let half = frac >> shift;
+        let round = (frac >> (shift - 1)) & 1 == 1 && (frac & ((1 << (shift - 1)) - 1) != 0 || half & 1 == 1);
+        return sign | (half as u16 + round as u16);
+    }
+
+    let half = ((exp as u32) << 10) | (frac >> 13);
+    let round = frac & 0x1000 != 0 && (frac & 0xfff != 0 || half & 1 == 1);
+    // a carry out of the fraction bumps the exponent, which is exactly what should happen
+    sign | (half + round as u32) as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shortest() {
+        // the f32 text of this half would be 1.2246094
+        assert_eq!(format(from_float(1.2246, 16), 16), "1.225");
+        assert_eq!(format(from_float(0.1, 16), 16), "0.1");
+        assert_eq!(format(from_float(65504.0, 16), 16), "65500");
+        assert_eq!(format(from_float(-2.5, 16), 16), "-2.5");
+        assert_eq!(format(0x0001, 16), "0.00000006");
+        assert_eq!(format(from_float(0.1, 32), 32), "0.1");
+        assert_eq!(format(from_float(0.1, 64), 64), "0.1");
+        assert_eq!(format(0x7c00, 16), "inf");
+    }
+
+    #[test]
+    fn every_half_reads_back() {
+        for half in (0..=0xffffu64).filter(|h| to_float(*h, 16).is_finite()) {
+            let text = format(half, 16);
+            assert_eq!(from_float(text.parse().unwrap(), 16), half, "{}", text);
+        }
+    }
+
+    #[test]
+    fn rounding() {
+        // halfway between 1 and the next half goes to the even one
+        assert_eq!(from_float(1.0 + 1.0 / 2048.0, 16), 0x3c00);
+        assert_eq!(from_float(1.0 + 3.0 / 2048.0, 16), 0x3c02);
+        assert_eq!(from_float(1e6, 16), 0x7c00);
+        assert_eq!(to_float(0x8000, 16).to_bits(), (-0.0f64).to_bits());
+    }
+}
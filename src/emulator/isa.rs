@@ -0,0 +1,201 @@
+// every instruction is defined once in the table below, the Inst enum, the parser, the operand fixups
+// and the interpreter are all generated from it, so adding an instruction is adding a line here
+//
+// a line looks like `NAME(a: Kind, b: Kind as type); a => semantics,`
+//   the kinds are the variants of OperandKind and decide how the parser checks the operand
+//   `as type` casts the value before the semantics see it, like `as usize` for jump targets. signed
+//   instructions use sext(value, bits!()) instead of `as i64` so the sign bit is the one at BITS
+//   `; a =>` writes the result to a, `; [a] =>` writes it to memory at a, without it the result is ignored
+//   `#[float]` marks instructions that need the FLOAT header
+//   dividing by zero gives 0, it is up to the program to check
+//
+// the semantics run inside EmulatorState::step and can only use what step provides through macros:
+//   get_mem!, set_mem!, branch!, push!, pop!, input!, output!, halt!, pc!, bits!, SET!, FLOAT! and WORD!
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OperandKind {
+    Reg, // has to be a register
+    Op, // anything
+    Imm, // anything but a register
+    Mem, // a memory address or a register holding one
+    Jmp, // a label, relative jump or a register holding an address
+    Port, // a port or a register holding one
+    Loc, // like Op, but a memory address stands for the word stored there
+}
+
+// what an instruction looks like without its operands, one for each line of the table
+#[derive(Debug, Clone, Copy)]
+pub struct InstInfo {
+    pub mnemonic: &'static str,
+    pub operands: &'static [OperandKind],
+    pub destination: Option<usize>, // the operand the result goes to, memory writes dont count
+    pub float: bool,
+}
+
+// const versions of == and position for building InstInfo out of the table
+pub const fn same(a: &str, b: &str) -> bool {
+    let (a, b) = (a.as_bytes(), b.as_bytes());
+    if a.len() != b.len() {
+        return false;
+    }
+    let mut i = 0;
+    while i < a.len() {
+        if a[i] != b[i] {
+            return false;
+        }
+        i += 1;
+    }
+    true
+}
+
+pub const fn position(names: &[&str], name: Option<&str>) -> Option<usize> {
+    let Some(name) = name else { return None };
+    let mut i = 0;
+    while i < names.len() {
+        if same(names[i], name) {
+            return Some(i);
+        }
+        i += 1;
+    }
+    None
+}
+
+macro_rules! with_isa {
+    ($callback:ident) => {
+        $callback! {
+            NOP() => {},
+            HLT() => halt!(),
+
+            PSH(a: Op) => push!(a),
+            POP(a: Reg); a => pop!(a),
+            CAL(a: Jmp as usize) => {
+                push!(pc!());
+                branch!(a)
+            },
+            // if the pop fails the target is the next instruction, so nothing happens
+            RET() => {
+                let to = pop!(pc!() + 1) as usize;
+                branch!(to)
+            },
+
+            IN(a: Loc, b: Port); a => input!(b, a),
+            OUT(a: Port, b: Loc) => output!(a, b),
+
+            JMP(a: Jmp as usize) => branch!(a),
+            BRG(a: Jmp as usize, b: Op, c: Op) => branch!(a if b > c),
+            BGE(a: Jmp as usize, b: Op, c: Op) => branch!(a if b >= c),
+            BRL(a: Jmp as usize, b: Op, c: Op) => branch!(a if b < c),
+            BLE(a: Jmp as usize, b: Op, c: Op) => branch!(a if b <= c),
+
+            BRE(a: Jmp as usize, b: Op, c: Op) => branch!(a if b == c),
+            BNE(a: Jmp as usize, b: Op, c: Op) => branch!(a if b != c),
+            BRZ(a: Jmp as usize, b: Op) => branch!(a if b == 0),
+            BNZ(a: Jmp as usize, b: Op) => branch!(a if b != 0),
+            BRC(a: Jmp as usize, b: Op, c: Op) => branch!(a if does_overflow(b, c)),
+            BNC(a: Jmp as usize, b: Op, c: Op) => branch!(a if !does_overflow(b, c)),
+
+            SBRG(a: Jmp as usize, b: Op, c: Op) => branch!(a if sext(b, bits!()) > sext(c, bits!())),
+            SBGE(a: Jmp as usize, b: Op, c: Op) => branch!(a if sext(b, bits!()) >= sext(c, bits!())),
+            SBRL(a: Jmp as usize, b: Op, c: Op) => branch!(a if sext(b, bits!()) < sext(c, bits!())),
+            SBLE(a: Jmp as usize, b: Op, c: Op) => branch!(a if sext(b, bits!()) <= sext(c, bits!())),
+
+            BEV(a: Jmp as usize, b: Op) => branch!(a if b&1 == 0),
+            BOD(a: Jmp as usize, b: Op) => branch!(a if b&1 == 1),
+            BRP(a: Jmp as usize, b: Op) => branch!(a if sext(b, bits!()) >= 0),
+            BRN(a: Jmp as usize, b: Op) => branch!(a if sext(b, bits!()) < 0),
+
+            MOV(a: Reg, b: Op); a => b,
+            IMM(a: Reg, b: Imm); a => b,
+            STR(a: Mem, b: Op); [a] => b,
+            CPY(a: Mem, b: Mem); [a] => get_mem!(b),
+            LOD(a: Reg, b: Mem); a => get_mem!(b),
+            LLOD(a: Reg, b: Op, c: Op); a => get_mem!(b + c),
+            LSTR(a: Op, b: Op, c: Op) => set_mem!(a + b, c),
+
+            ADD(a: Reg, b: Op, c: Op); a => b + c,
+            SUB(a: Reg, b: Op, c: Op); a => b - c,
+            INC(a: Reg, b: Op); a => b + 1,
+            DEC(a: Reg, b: Op); a => b - 1,
+
+            RSH(a: Reg, b: Op); a => b >> 1,
+            LSH(a: Reg, b: Op); a => b << 1,
+            SRS(a: Reg, b: Op); a => mask((sext(b, bits!()) >> 1) as u64, bits!()),
+
+            BSR(a: Reg, b: Op, c: Op); a => b >> c,
+            BSL(a: Reg, b: Op, c: Op); a => b << c,
+            BSS(a: Reg, b: Op, c: Op); a => mask((sext(b, bits!()) >> c) as u64, bits!()),
+
+            OR(a: Reg, b: Op, c: Op); a => b | c,
+            NOR(a: Reg, b: Op, c: Op); a => !(b | c),
+            AND(a: Reg, b: Op, c: Op); a => b & c,
+            NAND(a: Reg, b: Op, c: Op); a => !(b & c),
+            XOR(a: Reg, b: Op, c: Op); a => b ^ c,
+            XNOR(a: Reg, b: Op, c: Op); a => !(b ^ c),
+
+            NOT(a: Reg, b: Op); a => !b,
+            NEG(a: Reg, b: Op); a => mask(sext(b, bits!()).wrapping_neg() as u64, bits!()),
+            ABS(a: Reg, b: Op); a => mask(sext(b, bits!()).wrapping_abs() as u64, bits!()),
+
+            MLT(a: Reg, b: Op, c: Op); a => b * c,
+            DIV(a: Reg, b: Op, c: Op); a => b.checked_div(c).unwrap_or(0),
+            SDIV(a: Reg, b: Op, c: Op); a => match sext(c, bits!()) {0 => 0, c => mask(sext(b, bits!()).wrapping_div(c) as u64, bits!())},
+            MOD(a: Reg, b: Op, c: Op); a => b.checked_rem(c).unwrap_or(0),
+
+            SETE(a: Reg, b: Op, c: Op); a => SET!(b == c),
+            SETNE(a: Reg, b: Op, c: Op); a => SET!(b != c),
+            SETC(a: Reg, b: Op, c: Op); a => SET!(does_overflow(b, c)),
+            SETNC(a: Reg, b: Op, c: Op); a => SET!(!does_overflow(b, c)),
+
+            SETG(a: Reg, b: Op, c: Op); a => SET!(b > c),
+            SETGE(a: Reg, b: Op, c: Op); a => SET!(b >= c),
+            SETL(a: Reg, b: Op, c: Op); a => SET!(b < c),
+            SETLE(a: Reg, b: Op, c: Op); a => SET!(b <= c),
+            SSETG(a: Reg, b: Op, c: Op); a => SET!(sext(b, bits!()) > sext(c, bits!())),
+            SSETGE(a: Reg, b: Op, c: Op); a => SET!(sext(b, bits!()) >= sext(c, bits!())),
+            SSETL(a: Reg, b: Op, c: Op); a => SET!(sext(b, bits!()) < sext(c, bits!())),
+            SSETLE(a: Reg, b: Op, c: Op); a => SET!(sext(b, bits!()) <= sext(c, bits!())),
+
+            UMLT(a: Reg, b: Op as u128, c: Op as u128); a => mask(((b * c) >> bits!()) as u64, bits!()),
+            SUMLT(a: Reg, b: Op, c: Op); a => mask(((sext(b, bits!()) as i128 * sext(c, bits!()) as i128) >> bits!()) as u64, bits!()),
+            // MIN % -1 is the only other remainder that can fail, and it is 0 anyway
+            SMOD(a: Reg, b: Op, c: Op); a => mask(sext(b, bits!()).checked_rem(sext(c, bits!())).unwrap_or(0) as u64, bits!()),
+
+            #[float] ITOF(a: Reg, b: Op); a => WORD!(sext(b, bits!()) as f64),
+            #[float] FTOI(a: Reg, b: Op); a => mask(FLOAT!(b) as i64 as u64, bits!()),
+            #[float] FADD(a: Reg, b: Op, c: Op); a => WORD!(FLOAT!(b) + FLOAT!(c)),
+            #[float] FSUB(a: Reg, b: Op, c: Op); a => WORD!(FLOAT!(b) - FLOAT!(c)),
+            #[float] FMLT(a: Reg, b: Op, c: Op); a => WORD!(FLOAT!(b) * FLOAT!(c)),
+            #[float] FDIV(a: Reg, b: Op, c: Op); a => WORD!(FLOAT!(b) / FLOAT!(c)),
+            #[float] FSQRT(a: Reg, b: Op); a => WORD!(FLOAT!(b).sqrt()),
+            #[float] FABS(a: Reg, b: Op); a => WORD!(FLOAT!(b).abs()),
+            #[float] FNEG(a: Reg, b: Op); a => WORD!(-FLOAT!(b)),
+            #[float] FBRG(a: Jmp as usize, b: Op, c: Op) => branch!(a if FLOAT!(b) > FLOAT!(c)),
+            #[float] FBGE(a: Jmp as usize, b: Op, c: Op) => branch!(a if FLOAT!(b) >= FLOAT!(c)),
+            #[float] FBRL(a: Jmp as usize, b: Op, c: Op) => branch!(a if FLOAT!(b) < FLOAT!(c)),
+            #[float] FBLE(a: Jmp as usize, b: Op, c: Op) => branch!(a if FLOAT!(b) <= FLOAT!(c)),
+        }
+    };
+}
+pub(crate) use with_isa;
+
+// Inst::NAME for instructions without operands and Inst::NAME(a, b) for the rest, works for patterns too
+macro_rules! isa_inst {
+    ($name:ident;) => {
+        Inst::$name
+    };
+    ($name:ident; $($op:ident),+) => {
+        Inst::$name($($op),+)
+    };
+}
+pub(crate) use isa_inst;
+
+// the same but without binding the operands
+macro_rules! isa_any {
+    ($name:ident;) => {
+        Inst::$name
+    };
+    ($name:ident; $($op:ident),+) => {
+        Inst::$name(..)
+    };
+}
+pub(crate) use isa_any;
@@ -8,17 +8,30 @@ pub enum Kind {
     Unknown, Error, Comment,
     White, LF, EOF,
     Name, Macro, 
-    Int(i64), Memory(u64), Reg(u64), Label, Relative(i64),
+    Int(i64), BadInt(IntError), Float(u64), Memory(u64), Reg(u64), Label, Relative(i64),
     Port, PortNum(u64),
-    Eq, GE, LE,
+    Eq, NE, GE, LE, GT, LT,
+    Plus, Minus, Star, Slash, And, Or, Xor, Shl, Shr, LParen, RParen,
     LSquare, RSquare, String, Char, Text, Escape(char),
 }
 
+// why a number couldnt be read, the parser reports it once it knows where the number goes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IntError {
+    NoDigits, InvalidDigit, TooLarge, Underscore,
+}
+
 pub fn is_inline_white(c: char) -> bool {
     c.is_whitespace() && c != '\n'
 }
 
-pub fn lex(src: &str) -> Vec<Token<Kind>>{
+// a + or - right after one of these is an operator, anywhere else it is the sign of a number
+fn is_value(kind: Kind) -> bool {
+    use Kind::*;
+    matches!(kind, Int(_) | BadInt(_) | Float(_) | Memory(_) | Reg(_) | Label | Relative(_) | Port | PortNum(_) | Name | Macro | Char | RParen)
+}
+
+pub fn lex(src: &str) -> Vec<Token<'_, Kind>>{
     use Kind::*;
     let mut s = Scanner::<Kind>::new(src);
 
@@ -26,40 +39,55 @@ pub fn lex(src: &str) -> Vec<Token<Kind>>{
         match c {
             '[' => s.create(LSquare),
             ']' => s.create(RSquare),
+            '(' => s.create(LParen),
+            ')' => s.create(RParen),
+            '*' => s.create(Star),
+            '&' => s.create(And),
+            '|' => s.create(Or),
+            '^' => s.create(Xor),
+            '+' if s.last_token().is_some_and(|t| is_value(t.kind)) => s.create(Plus),
+            '-' if s.last_token().is_some_and(|t| is_value(t.kind)) => s.create(Minus),
+            '+' | '-' if !s.peek().is_some_and(|c| c.is_ascii_digit()) => s.create(if c == '+' {Plus} else {Minus}),
             ' ' | '\x09' | '\x0b'..='\x0d' => {s._while(is_inline_white); s.create(White);},
             '\n' => s.create(LF),
-            '0' => {
-                let a = parse_prefixed_number(&mut s, 0);
-                if a != None {s.create(Int(a.unwrap()));} else {s.create(Error);};
-            },
-            '-' | '+' | '1'..='9' => {
+            '-' | '+' | '0'..='9' => {
                 s._while(|c|c.is_ascii_digit());
-                let value = s.str().parse().unwrap_or(0);
-                s.create(Int(value))
+                if s.peek() == Some('.') && s.peek2().is_some_and(|c| c.is_ascii_digit()) {
+                    lex_float(&mut s);
+                } else {
+                    // the whole run so `0b102` and `12ab` are one bad number and not a number and a name
+                    s._while(|c| c.is_ascii_alphanumeric() || c == '_');
+                    match parse_int(s.str()) {
+                        Ok(value) => s.create(Int(value as i64)),
+                        Err(err) => s.create(BadInt(err)),
+                    }
+                }
             },
             '~' => {
                 s._while(|c|c.is_ascii_digit() || c == '-' || c == '+');
-                let value = s.str_after(1).parse().unwrap_or(0);
-                s.create(Relative(value));
+                match parse_int(s.str_after(1)) {
+                    Ok(value) => s.create(Relative(value as i64)),
+                    Err(err) => s.create(BadInt(err)),
+                }
             },
             '#' | 'm' | 'M' => {
-                if s.peek().unwrap_or(' ') == '0' {
-                    s.next();
-                    let a = parse_prefixed_number(&mut s, 1);
-                    if a != None {s.create(Memory(a.unwrap() as u64))} else {s.create(Error)};
-                } else if s.peek().unwrap_or(' ').is_ascii_digit() {
-                    s._while(char::is_alphanumeric); s.create(Memory(s.str_after(1).parse().unwrap_or(0)));
+                if s.peek().unwrap_or(' ').is_ascii_digit() {
+                    s._while(|c| c.is_ascii_alphanumeric() || c == '_');
+                    match parse_int(s.str_after(1)) {
+                        Ok(value) => s.create(Memory(value as u64)),
+                        Err(_) => s.create(Error),
+                    }
                 } else {
                     s._while(|c| c.is_alphanumeric() || c == '_'); s.create(Name);
                 }
             },
             '$' | 'r' | 'R' => {
-                if s.peek().unwrap_or(' ') == '0' {
-                    s.next();
-                    let a = parse_prefixed_number(&mut s, 1);
-                    if a != None {s.create(Reg(a.unwrap() as u64))} else {s.create(Error)};
-                } else if s.peek().unwrap_or(' ').is_ascii_digit() {
-                    s._while(char::is_alphanumeric); s.create(Reg(s.str_after(1).parse().unwrap_or(0)));
+                if s.peek().unwrap_or(' ').is_ascii_digit() {
+                    s._while(|c| c.is_ascii_alphanumeric() || c == '_');
+                    match parse_int(s.str_after(1)) {
+                        Ok(value) => s.create(Reg(value as u64)),
+                        Err(_) => s.create(Error),
+                    }
                 } else {
                     s._while(|c| c.is_alphanumeric() || c == '_'); s.create(Name);
                 }
@@ -84,18 +112,21 @@ pub fn lex(src: &str) -> Vec<Token<Kind>>{
                     _ => s.create(Name)
                 }
             },
-            '>' => {if s._if(|c|c=='=') {s.create(GE);} else {s.create(Error);}}
-            '<' => {if s._if(|c|c=='=') {s.create(LE);} else {s.create(Error);}}
+            '>' => {if s._if(|c|c=='=') {s.create(GE);} else if s._if(|c|c=='>') {s.create(Shr);} else {s.create(GT);}}
+            '<' => {if s._if(|c|c=='=') {s.create(LE);} else if s._if(|c|c=='<') {s.create(Shl);} else {s.create(LT);}}
             '=' => {if s._if(|c|c=='=') {s.create(Eq);} else {s.create(Error);}}
-            '.' => {s._while(|c| !c.is_whitespace()); s.create(Label)},
+            '!' => {if s._if(|c|c=='=') {s.create(NE);} else {s.create(Error);}}
+            // labels can have anything but whitespace in them, so `.my-label` is one label and arithmetic
+            // on them needs spaces or parentheses, `.table + 3` or `(.table)+3`
+            '.' => {s._while(|c| !c.is_whitespace() && c != ')'); s.create(Label)},
             '/' => {if s._if(|c| c == '/') {
                 s._while(|c| c != '\n');
                 s.create(Comment);
             } else if s._if(|c| c == '*') {
-                while s.next().map_or(false, |c| c != '*') || s.next().map_or(false, |c| c != '/'){}
+                while s.next().is_some_and(|c| c != '*') || s.next().is_some_and(|c| c != '/'){}
                 s.create(Comment);
             } else {
-                s.create(Error);
+                s.create(Slash);
             }},
             '\'' => {
                 s.create(Char);
@@ -149,34 +180,61 @@ pub fn lex(src: &str) -> Vec<Token<Kind>>{
     s.tokens()
 }
 
-fn parse_prefixed_number<'a>(s: &mut Scanner<'a, Kind>, pref_len: usize) -> Option<i64> {
-    let total_pref_len = pref_len + 2;
+// Int holds the bits of the number, this gets the value back. only numbers written with a - are
+// negative, 0xFFFFFFFFFFFFFFFF is 2^64-1 and not -1
+pub fn int_value(tok: &UToken) -> i128 {
+    match tok.kind {
+        Kind::Int(v) if tok.str.starts_with('-') => v as i128,
+        Kind::Int(v) => v as u64 as i128,
+        _ => 0,
+    }
+}
 
-    match s.peek().unwrap_or(' ') {
-        '0'..='9' => {
-            s.next();
-            s._while(|c|c.is_ascii_digit());
-            Some(s.str().parse().unwrap_or(0))
-        },
-        'b' => {
-            s.next();
-            s._while(|c|c == '0' || c == '1');
-            if s.str().len() <= total_pref_len { return None; }
-            Some(i64::from_str_radix(&s.str()[total_pref_len..s.str().len()], 2).unwrap_or(0))
-        },
-        'o' => {
-            s.next();
-            s._while(|c|c.is_ascii_digit() && c != '8' && c != '9');
-            if s.str().len() <= total_pref_len { return None; }
-            Some(i64::from_str_radix(&s.str()[total_pref_len..s.str().len()], 8).unwrap_or(0))
-        },
-        'x' => {
-            s.next();
-            s._while(|c|c.is_ascii_hexdigit());
-            if s.str().len() <= total_pref_len { return None; }
-            Some(i64::from_str_radix(&s.str()[total_pref_len..s.str().len()], 16).unwrap_or(0))
-        },
-        _ => Some(0)
+// decimal or with a 0b, 0o or 0x prefix, a single _ can go between two digits. anything from -2^63 to 2^64-1
+// is a word at some width, checking it against BITS is up to the parser
+pub fn parse_int(text: &str) -> Result<i128, IntError> {
+    let (negative, text) = match text.strip_prefix('-') {
+        Some(text) => (true, text),
+        None => (false, text.strip_prefix('+').unwrap_or(text)),
+    };
+    let (radix, digits) = match text.get(..2).map(|p| p.to_ascii_lowercase()).as_deref() {
+        Some("0b") => (2, &text[2..]),
+        Some("0o") => (8, &text[2..]),
+        Some("0x") => (16, &text[2..]),
+        _ => (10, text),
+    };
+    if digits.is_empty() {
+        return Err(IntError::NoDigits);
+    }
+    // 1_000 is fine, _1, 1_ and 1__0 are typos
+    if digits.starts_with('_') || digits.ends_with('_') || digits.contains("__") {
+        return Err(IntError::Underscore);
+    }
+    let digits: String = digits.chars().filter(|c| *c != '_').collect();
+    if !digits.chars().all(|c| c.is_digit(radix)) {
+        return Err(IntError::InvalidDigit);
+    }
+    let value = u64::from_str_radix(&digits, radix).map_err(|_| IntError::TooLarge)? as i128;
+    match negative {
+        true if value > 1 << 63 => Err(IntError::TooLarge),
+        true => Ok(-value),
+        false => Ok(value),
+    }
+}
+
+// the integer part is already eaten, this does the fraction and exponent
+fn lex_float<'a>(s: &mut Scanner<'a, Kind>) {
+    s._while(|c|c.is_ascii_digit());
+    if s._if(|c|c == '.') {
+        s._while(|c|c.is_ascii_digit());
+    }
+    if s._if(|c|c == 'e' || c == 'E') {
+        s._if(|c|c == '-' || c == '+');
+        s._while(|c|c.is_ascii_digit());
+    }
+    match s.str().parse::<f64>() {
+        Ok(value) => s.create(Kind::Float(value.to_bits())),
+        Err(_) => s.create(Kind::Error),
     }
 }
 
@@ -205,6 +263,8 @@ impl Kind {
             Kind::White => "white",
             Kind::LF => "white",
             Kind::Int(_) => "int",
+            Kind::BadInt(_) => "error",
+            Kind::Float(_) => "int",
             Kind::LSquare => "left-square",
             Kind::RSquare => "right-square",
             Kind::String => "string",
@@ -219,8 +279,22 @@ impl Kind {
             Kind::Name => "name",
             Kind::Macro => "macro",
             Kind::Eq => "comparison",
+            Kind::NE => "comparison",
             Kind::GE => "comparison",
             Kind::LE => "comparison",
+            Kind::GT => "comparison",
+            Kind::LT => "comparison",
+            Kind::Plus => "operator",
+            Kind::Minus => "operator",
+            Kind::Star => "operator",
+            Kind::Slash => "operator",
+            Kind::And => "operator",
+            Kind::Or => "operator",
+            Kind::Xor => "operator",
+            Kind::Shl => "operator",
+            Kind::Shr => "operator",
+            Kind::LParen => "operator",
+            Kind::RParen => "operator",
             Kind::Label => "label",
             Kind::Comment => "comment",
             Kind::Relative(_) => "relative",
@@ -233,6 +307,14 @@ impl Kind {
 pub struct Token<'a, T> {
     pub kind: T,
     pub str: &'a str,
+    pub origin: Option<&'a str>, // the macro call this token was expanded from
+    pub scope: u32, // local labels of different macro expansions get different scopes, 0 is the file itself
+}
+
+impl <'a, T> Token<'a, T> {
+    pub fn new(kind: T, str: &'a str) -> Self {
+        Self { kind, str, origin: None, scope: 0 }
+    }
 }
 
 pub struct Scanner <'a, T> {
@@ -256,16 +338,20 @@ impl <'a, T> Scanner<'a, T> {
         self.chars.clone().next()
     }
     #[inline]
+    pub fn peek2(&self) -> Option<char>{
+        self.chars.clone().nth(1)
+    }
+    #[inline]
     pub fn _while<F: Fn(char) -> bool>(&mut self, f: F){
         while self._if(|c| f(c)) {}
     }
     #[inline]
     pub fn _if<F: Fn(char) -> bool>(&mut self, f: F) -> bool {
-        if self.peek().map_or(false, f) {
+        if self.peek().is_some_and(f) {
             self.next();
             return true;
         }
-        return false;
+        false
     }
     #[inline]
     pub fn create(&mut self, kind: T) {
@@ -274,7 +360,7 @@ impl <'a, T> Scanner<'a, T> {
         self.start = end;
 
         let str = &self.src[start..end];
-        self.tokens.push(Token { kind, str });
+        self.tokens.push(Token::new(kind, str));
     }
     #[inline]
     pub fn str(&self) -> &'a str{
@@ -288,6 +374,9 @@ impl <'a, T> Scanner<'a, T> {
         let end = self.pos();
         &self.src[start..end]
     }
+    pub fn last_token(&self) -> Option<&Token<'a, T>> {
+        self.tokens.last()
+    }
     pub fn tokens(self) -> Vec<Token<'a, T>> {
         self.tokens
     }
@@ -300,3 +389,69 @@ impl <'a, T> Iterator for Scanner<'a, T> {
         self.chars.next()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kinds(src: &str) -> Vec<Kind> {
+        lex(src).into_iter().map(|t| t.kind).filter(|k| !matches!(k, Kind::White | Kind::Comment)).collect()
+    }
+
+    #[test]
+    fn parse_int_bases() {
+        assert_eq!(parse_int("42"), Ok(42));
+        assert_eq!(parse_int("1_000"), Ok(1000));
+        assert_eq!(parse_int("0b1010"), Ok(10));
+        assert_eq!(parse_int("0o17"), Ok(15));
+        assert_eq!(parse_int("0xff_ff"), Ok(0xffff));
+        assert_eq!(parse_int("0XFF"), Ok(255));
+        assert_eq!(parse_int("+7"), Ok(7));
+        assert_eq!(parse_int("-0x80"), Ok(-128));
+    }
+
+    #[test]
+    fn parse_int_range() {
+        assert_eq!(parse_int("18446744073709551615"), Ok(u64::MAX as i128));
+        assert_eq!(parse_int("18446744073709551616"), Err(IntError::TooLarge));
+        assert_eq!(parse_int("-9223372036854775808"), Ok(i64::MIN as i128));
+        assert_eq!(parse_int("-9223372036854775809"), Err(IntError::TooLarge));
+    }
+
+    #[test]
+    fn parse_int_errors() {
+        assert_eq!(parse_int("0x"), Err(IntError::NoDigits));
+        assert_eq!(parse_int("0b102"), Err(IntError::InvalidDigit));
+        assert_eq!(parse_int("12ab"), Err(IntError::InvalidDigit));
+        for text in ["1__0", "1_", "0x1_", "0x_1", "_1"] {
+            assert_eq!(parse_int(text), Err(IntError::Underscore), "{}", text);
+        }
+    }
+
+    #[test]
+    fn lex_instruction() {
+        use Kind::*;
+        assert_eq!(kinds("IMM R1 0x10 // comment\n.loop"), [Name, Reg(1), Int(16), LF, Label, EOF]);
+        assert_eq!(kinds("ADD $2 M3 ~-1"), [Name, Reg(2), Memory(3), Relative(-1), EOF]);
+        assert_eq!(kinds("OUT %NUMB 0b12"), [Name, Port, BadInt(IntError::InvalidDigit), EOF]);
+    }
+
+    #[test]
+    fn lex_signs() {
+        use Kind::*;
+        // after a value a sign is an operator, anywhere else it belongs to the number
+        assert_eq!(kinds("(1 -2)"), [LParen, Int(1), Int(-2), RParen, EOF]);
+        assert_eq!(kinds("(1-2)"), [LParen, Int(1), Minus, Int(2), RParen, EOF]);
+        assert_eq!(kinds("-(1)"), [Minus, LParen, Int(1), RParen, EOF]);
+        assert_eq!(kinds("@MAX-1"), [Macro, Minus, Int(1), EOF]);
+    }
+
+    #[test]
+    fn lex_labels() {
+        use Kind::*;
+        // labels go up to whitespace, or the end of the parentheses theyre in
+        assert_eq!(lex(".my-label+1")[0].str, ".my-label+1");
+        assert_eq!(kinds(".a + 1"), [Label, Plus, Int(1), EOF]);
+        assert_eq!(kinds("(.a)+1"), [LParen, Label, RParen, Plus, Int(1), EOF]);
+    }
+}
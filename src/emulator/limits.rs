@@ -0,0 +1,51 @@
+use wasm_bindgen::prelude::*;
+
+// upper bounds on what a program may ask for, checked before anything gets allocated
+#[wasm_bindgen]
+#[derive(Debug, Clone, Copy)]
+pub struct ResourceLimits {
+    pub max_heap: u64, // words, DW data included
+    pub max_stack: u64, // words
+    pub max_regs: u64,
+    pub max_screen_width: usize,
+    pub max_screen_height: usize,
+    pub max_output: usize, // bytes of console output over the whole run
+    pub max_source: usize, // bytes
+}
+
+impl Default for ResourceLimits {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[wasm_bindgen]
+#[allow(dead_code)]
+impl ResourceLimits {
+    // generous enough for anything reasonable, but a typo like MINHEAP 4000000000 still gets caught
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> Self {
+        Self {
+            max_heap: 1 << 24,
+            max_stack: 1 << 24,
+            max_regs: 1 << 16,
+            max_screen_width: 4096,
+            max_screen_height: 4096,
+            max_output: usize::MAX,
+            max_source: 1 << 24,
+        }
+    }
+
+    // for code from people you dont know, like the discord bot
+    pub fn untrusted() -> Self {
+        Self {
+            max_heap: 1 << 20,
+            max_stack: 1 << 16,
+            max_regs: 1024,
+            max_screen_width: 512,
+            max_screen_height: 512,
+            max_output: 1 << 16,
+            max_source: 1 << 18,
+        }
+    }
+}
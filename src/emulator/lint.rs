@@ -0,0 +1,187 @@
+use std::collections::HashSet;
+
+use num_traits::FromPrimitive;
+use wasm_bindgen::prelude::*;
+
+use super::{ast::{Parser, Inst, Operand, Label}, isa::OperandKind, errorcontext::ErrorKind, devices::{DeviceHost, IOPort}};
+
+// warnings about programs that assemble fine but probably dont do what was meant. they only run
+// when there are no errors, and every one of them can be turned off
+#[wasm_bindgen]
+#[derive(Debug, Clone, Copy)]
+pub struct Lints {
+    pub unused_label: bool,
+    pub unreachable: bool,
+    pub self_jump: bool,
+    pub write_r0: bool,
+    pub immediate_destination: bool,
+    pub unsupported_port: bool,
+}
+
+impl Default for Lints {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[wasm_bindgen]
+#[allow(dead_code)]
+impl Lints {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> Self {
+        Self { unused_label: true, unreachable: true, self_jump: true, write_r0: true, immediate_destination: true, unsupported_port: true }
+    }
+
+    pub fn none() -> Self {
+        Self { unused_label: false, unreachable: false, self_jump: false, write_r0: false, immediate_destination: false, unsupported_port: false }
+    }
+
+    // by the names the cli uses, like -Wno-unused-label. false if there is no lint called that
+    pub fn set(&mut self, name: &str, on: bool) -> bool {
+        let lint = match name {
+            "unused-label" => &mut self.unused_label,
+            "unreachable" => &mut self.unreachable,
+            "self-jump" => &mut self.self_jump,
+            "write-r0" => &mut self.write_r0,
+            "immediate-destination" => &mut self.immediate_destination,
+            "unsupported-port" => &mut self.unsupported_port,
+            _ => return false,
+        };
+        *lint = on;
+        true
+    }
+}
+
+pub fn lint<'a>(p: &mut Parser<'a>, lints: &Lints) {
+    let program = &p.ast;
+    let mut warnings = Vec::new();
+
+    if lints.unused_label {
+        // .g is used by .g.loop, its what ..loop gets its scope from even if nothing jumps to it
+        let parents: HashSet<&str> = p.used_labels.iter()
+            .flat_map(|name| name.match_indices('.').filter(|(i, _)| *i > 0).map(move |(i, _)| &name[..i]))
+            .collect();
+        for (name, tok, _) in p.label_defs.iter() {
+            if !p.used_labels.contains(name) && !parents.contains(name.as_str()) {
+                warnings.push((tok.clone(), ErrorKind::UnusedLabel));
+            }
+        }
+    }
+
+    // code can only be reached by running into it or jumping to it, register jumps could go
+    // anywhere so only labels and constant targets count
+    let mut entries = vec![false; program.instructions.len() + 1];
+    for (name, _, data) in p.label_defs.iter() {
+        if let (false, Some(Label::Defined(pc))) = (data, program.labels.get(name)) {
+            entries[*pc] = true;
+        }
+    }
+    for inst in program.instructions.iter() {
+        for (kind, op) in inst.operand_kinds().iter().zip(inst.operands()) {
+            if let (OperandKind::Jmp, Operand::Imm(to)) = (kind, op) {
+                entries[(*to as usize).min(program.instructions.len())] = true;
+            }
+        }
+    }
+
+    // without a HLT anywhere a jump to itself means the program can never stop, with one its probably on purpose
+    let halts = program.instructions.iter().any(|inst| matches!(inst, Inst::HLT));
+    let mut reachable = true;
+    for (pc, inst) in program.instructions.iter().enumerate() {
+        let tok = &p.inst_toks[pc];
+        reachable |= entries[pc];
+        if !reachable && lints.unreachable {
+            warnings.push((tok.clone(), ErrorKind::UnreachableCode));
+            // once for every piece of unreachable code
+            reachable = true;
+        }
+        if matches!(inst, Inst::HLT | Inst::RET | Inst::JMP(_)) {
+            reachable = false;
+        }
+
+        if let (true, false, Inst::JMP(Operand::Imm(to))) = (lints.self_jump, halts, inst) {
+            if *to as usize == pc {
+                warnings.push((tok.clone(), ErrorKind::SelfJump));
+            }
+        }
+        match inst.destination() {
+            Some(Operand::Reg(0)) if lints.write_r0 => warnings.push((tok.clone(), ErrorKind::WriteToR0)),
+            Some(Operand::Imm(_)) if lints.immediate_destination => warnings.push((tok.clone(), ErrorKind::ImmediateDestination)),
+            _ => {},
+        }
+        if lints.unsupported_port {
+            let port = match inst {
+                Inst::IN(_, Operand::Imm(port)) if !DeviceHost::supports_in(*port) => Some(("IN", *port)),
+                Inst::OUT(Operand::Imm(port), _) if !DeviceHost::supports_out(*port) => Some(("OUT", *port)),
+                _ => None,
+            };
+            if let Some((name, port)) = port {
+                let port = match IOPort::from_u64(port) {
+                    Some(port) => format!("%{:?}", port),
+                    None => format!("%{}", port),
+                };
+                warnings.push((tok.clone(), ErrorKind::UnsupportedPort { inst: name, port }));
+            }
+        }
+    }
+
+    for (tok, kind) in warnings {
+        p.err.warn(&tok, kind);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::{emulator::compile, limits::ResourceLimits, source::VirtualFiles};
+
+    fn warnings_with(src: &str, lints: &Lints) -> Vec<&'static str> {
+        let (program, diagnostics) = compile("main.urcl", src.to_string(), &VirtualFiles::new(), &ResourceLimits::new(), lints);
+        assert!(program.is_some(), "{:?}", diagnostics);
+        diagnostics.iter().map(|d| d.code).collect()
+    }
+
+    fn warnings(src: &str) -> Vec<&'static str> {
+        warnings_with(src, &Lints::new())
+    }
+
+    #[test]
+    fn unused_labels() {
+        assert_eq!(warnings(".a\nHLT"), ["E0046"]);
+        assert_eq!(warnings("JMP .a\n.a\nHLT"), Vec::<&str>::new());
+        // .g only gives ..loop its scope, using ..loop uses .g
+        assert_eq!(warnings(".g\nIMM R1 1\n..loop\nBRZ ..loop R1\nHLT"), Vec::<&str>::new());
+    }
+
+    #[test]
+    fn unreachable() {
+        assert_eq!(warnings("HLT\nIMM R1 1\nIMM R1 2\nHLT"), ["E0047"]);
+        assert_eq!(warnings("JMP .b\nIMM R1 1\n.b\nHLT"), ["E0047"]);
+        assert_eq!(warnings("BRZ .b R1\nIMM R1 1\n.b\nHLT"), Vec::<&str>::new());
+    }
+
+    #[test]
+    fn self_jumps() {
+        assert_eq!(warnings("JMP .end\n.end\nJMP .end"), ["E0048"]);
+        // with a HLT somewhere the jump is on purpose
+        assert_eq!(warnings("BRZ .end R1\nHLT\n.end\nJMP .end"), Vec::<&str>::new());
+    }
+
+    #[test]
+    fn registers_and_ports() {
+        assert_eq!(warnings("IMM R0 1\nHLT"), ["E0049"]);
+        assert_eq!(warnings("OUT %ASCII7 1\nIN R1 %UTF16\nHLT"), ["E0051", "E0051"]);
+        assert_eq!(warnings("OUT %NUMB 1\nHLT"), Vec::<&str>::new());
+    }
+
+    #[test]
+    fn configurable() {
+        let src = ".a\nIMM R0 1\nHLT\nOUT %ASCII7 1";
+        assert_eq!(warnings(src), ["E0046", "E0049", "E0047", "E0051"]);
+        assert_eq!(warnings_with(src, &Lints::none()), Vec::<&str>::new());
+        let mut lints = Lints::new();
+        assert!(lints.set("unused-label", false) && lints.set("write-r0", false));
+        assert!(!lints.set("no-such-lint", false));
+        assert_eq!(warnings_with(src, &lints), ["E0047", "E0051"]);
+    }
+}
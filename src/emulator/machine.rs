@@ -0,0 +1,251 @@
+use super::emulator::{EmulatorState, EmulatorErrorKind, StepResult};
+use super::memory::Memory;
+
+// how the cores take turns, both are deterministic so a run can be reproduced exactly
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Schedule {
+    LockStep, // every core does one instruction per tick, bus words arrive at the end of the tick
+    RoundRobin { quantum: usize }, // one core runs up to quantum instructions per tick
+}
+
+// several cores wired together in a ring, OUT %CPUBUS on core n is read with IN %CPUBUS on core n+1
+pub struct Machine {
+    cores: Vec<EmulatorState>,
+    halted: Vec<bool>,
+    shared_heap: Option<Memory>,
+    schedule: Schedule,
+    next: usize,
+    idle: usize,
+    failed: Option<usize>,
+    deadlocked: bool,
+}
+
+#[allow(dead_code)]
+impl Machine {
+    pub fn new(cores: Vec<EmulatorState>, schedule: Schedule) -> Self {
+        let halted = vec![false; cores.len()];
+        Self { cores, halted, shared_heap: None, schedule, next: 0, idle: 0, failed: None, deadlocked: false }
+    }
+
+    // every core uses the heap of the first core (including its DW data) instead of its own.
+    // addresses have to mean the same thing on every core, so the memory maps and the DW data have to match
+    pub fn with_shared_heap(mut self) -> Result<Self, String> {
+        let Some((first, rest)) = self.cores.split_first() else {
+            return Ok(self);
+        };
+        let map = first.memory_map();
+        for (i, core) in rest.iter().enumerate() {
+            if core.memory_map() != map {
+                return Err(format!("core {} lays out memory differently from core 0, they cant share a heap", i + 1));
+            }
+            if (0..map.heap_start).any(|a| core.read_memory(a) != first.read_memory(a)) {
+                return Err(format!("core {} has different DW data from core 0, they cant share a heap", i + 1));
+            }
+        }
+        let mut heap = Memory::dense(0);
+        self.cores[0].swap_heap(&mut heap);
+        self.shared_heap = Some(heap);
+        Ok(self)
+    }
+
+    pub fn cores(&self) -> &[EmulatorState] {
+        &self.cores
+    }
+    pub fn core(&self, index: usize) -> &EmulatorState {
+        &self.cores[index]
+    }
+
+    pub fn run(&mut self) -> StepResult {
+        loop {
+            let result = self.step();
+            if result != StepResult::Continue {
+                return result;
+            }
+        }
+    }
+
+    pub fn run_for_ticks(&mut self, ticks: usize) -> StepResult {
+        for _ in 0..ticks {
+            let result = self.step();
+            if result != StepResult::Continue {
+                return result;
+            }
+        }
+        StepResult::Continue
+    }
+
+    pub fn step(&mut self) -> StepResult {
+        if self.failed.is_some() || self.deadlocked {
+            return StepResult::Error;
+        }
+        if self.halted.iter().all(|h| *h) {
+            return StepResult::HLT;
+        }
+
+        let progressed = match self.schedule {
+            Schedule::LockStep => {
+                let mut progressed = false;
+                for i in 0..self.cores.len() {
+                    if self.halted[i] {continue;}
+                    match self.step_core(i) {
+                        StepResult::Input => (),
+                        StepResult::Error => return StepResult::Error,
+                        _ => progressed = true,
+                    }
+                }
+                self.deliver() || progressed
+            },
+            Schedule::RoundRobin { quantum } => {
+                while self.halted[self.next] {
+                    self.next = (self.next + 1) % self.cores.len();
+                }
+                let i = self.next;
+                self.next = (self.next + 1) % self.cores.len();
+
+                let mut progressed = false;
+                for _ in 0..quantum.max(1) {
+                    let result = self.step_core(i);
+                    progressed |= self.deliver();
+                    match result {
+                        StepResult::Continue => progressed = true,
+                        StepResult::Input => break,
+                        StepResult::Error => return StepResult::Error,
+                        StepResult::HLT => {progressed = true; break},
+                    }
+                }
+                progressed
+            },
+        };
+
+        if self.halted.iter().all(|h| *h) {
+            return StepResult::HLT;
+        }
+
+        // a full turn of every running core without anything happening means nobody can move again
+        self.idle = if progressed {0} else {self.idle + 1};
+        let turns = match self.schedule {
+            Schedule::LockStep => 1,
+            Schedule::RoundRobin { .. } => self.halted.iter().filter(|h| !**h).count(),
+        };
+        if self.idle >= turns {
+            self.deadlocked = true;
+            return StepResult::Error;
+        }
+        StepResult::Continue
+    }
+
+    pub fn get_err(&mut self) -> Option<String> {
+        if self.deadlocked {
+            return Some(format!("Error: {}", EmulatorErrorKind::Deadlock));
+        }
+        let i = self.failed?;
+        self.cores[i].get_err().map(|err| format!("core {}: {}", i, err))
+    }
+
+    fn step_core(&mut self, i: usize) -> StepResult {
+        if let Some(heap) = &mut self.shared_heap {
+            self.cores[i].swap_heap(heap);
+        }
+        let result = self.cores[i].step();
+        if let Some(heap) = &mut self.shared_heap {
+            self.cores[i].swap_heap(heap);
+        }
+
+        match result {
+            StepResult::HLT => self.halted[i] = true,
+            StepResult::Error => self.failed = Some(i),
+            _ => (),
+        }
+        result
+    }
+
+    fn deliver(&mut self) -> bool {
+        let mut delivered = false;
+        let count = self.cores.len();
+        for i in 0..count {
+            let to = (i + 1) % count;
+            if !self.cores[i].cpubus().has_outgoing() || self.cores[to].cpubus().has_incoming() {continue;}
+
+            let value = self.cores[i].cpubus().take_outgoing().unwrap();
+            self.cores[to].cpubus().deliver(value);
+            delivered = true;
+        }
+        delivered
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::emulator::silence_emulate;
+
+    fn machine(srcs: &[&str], schedule: Schedule) -> Machine {
+        let cores = srcs.iter().map(|src| silence_emulate(src.to_string()).unwrap_or_else(|d| panic!("{:?}", d))).collect();
+        Machine::new(cores, schedule)
+    }
+
+    // core 0 sends two words around the ring, core 1 adds them and sends the sum back
+    const RING: [&str; 2] = [
+        "OUT %CPUBUS 5\nOUT %CPUBUS 6\nIN R1 %CPUBUS\nOUT %NUMB R1\nHLT",
+        "IN R1 %CPUBUS\nIN R2 %CPUBUS\nADD R3 R1 R2\nOUT %CPUBUS R3\nHLT",
+    ];
+
+    #[test]
+    fn ring() {
+        for schedule in [Schedule::LockStep, Schedule::RoundRobin { quantum: 1 }, Schedule::RoundRobin { quantum: 3 }] {
+            let mut m = machine(&RING, schedule);
+            assert_eq!(m.run_for_ticks(1000), StepResult::HLT, "{:?}", schedule);
+            assert_eq!(m.core(0).get_output(), "11", "{:?}", schedule);
+        }
+    }
+
+    #[test]
+    fn deterministic() {
+        for schedule in [Schedule::LockStep, Schedule::RoundRobin { quantum: 2 }] {
+            let runs: Vec<Vec<u64>> = (0..3).map(|_| {
+                let mut m = machine(&RING, schedule);
+                m.run_for_ticks(1000);
+                m.cores().iter().map(|core| core.steps()).collect()
+            }).collect();
+            assert!(runs.windows(2).all(|w| w[0] == w[1]), "{:?} {:?}", schedule, runs);
+        }
+    }
+
+    #[test]
+    fn lockstep_ticks() {
+        // one instruction per core per tick
+        let mut m = machine(&[".a\nINC R1 R1\nJMP .a", ".a\nINC R1 R1\nJMP .a"], Schedule::LockStep);
+        assert_eq!(m.run_for_ticks(7), StepResult::Continue);
+        assert!(m.cores().iter().all(|core| core.steps() == 7));
+    }
+
+    #[test]
+    fn round_robin_quantum() {
+        let mut m = machine(&[".a\nINC R1 R1\nJMP .a", ".a\nINC R1 R1\nJMP .a"], Schedule::RoundRobin { quantum: 4 });
+        assert_eq!(m.run_for_ticks(3), StepResult::Continue);
+        assert_eq!((m.core(0).steps(), m.core(1).steps()), (8, 4));
+    }
+
+    #[test]
+    fn deadlock() {
+        let mut m = machine(&["IN R1 %CPUBUS\nHLT", "IN R1 %CPUBUS\nHLT"], Schedule::RoundRobin { quantum: 1 });
+        assert_eq!(m.run_for_ticks(100), StepResult::Error);
+        assert!(m.get_err().is_some_and(|err| err.contains(&EmulatorErrorKind::Deadlock.to_string())));
+    }
+
+    #[test]
+    fn shared_heap() {
+        let srcs = ["MINHEAP 4\nSTR M1 42\nOUT %CPUBUS 1\nHLT", "MINHEAP 4\nIN R1 %CPUBUS\nLOD R1 M1\nOUT %NUMB R1\nHLT"];
+        let mut m = machine(&srcs, Schedule::LockStep).with_shared_heap().unwrap();
+        assert_eq!(m.run_for_ticks(100), StepResult::HLT);
+        assert_eq!(m.core(1).get_output(), "42");
+    }
+
+    #[test]
+    fn shared_heap_layouts() {
+        assert!(machine(&["MINHEAP 4\nHLT", "MINHEAP 8\nHLT"], Schedule::LockStep).with_shared_heap().is_err());
+        assert!(machine(&["DW 1\nHLT", "DW 2\nHLT"], Schedule::LockStep).with_shared_heap().is_err());
+        assert!(machine(&["DW 1\nHLT", "DW 1\nHLT"], Schedule::LockStep).with_shared_heap().is_ok());
+    }
+}
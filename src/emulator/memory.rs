@@ -0,0 +1,181 @@
+use std::collections::HashMap;
+use std::fmt::{Debug, Formatter, Result};
+use std::ops::Range;
+
+use wasm_bindgen::prelude::*;
+
+const PAGE_BITS: usize = 12;
+const PAGE_SIZE: usize = 1 << PAGE_BITS; // 4K words
+const PAGE_MASK: usize = PAGE_SIZE - 1;
+
+// anything bigger than this (in words) gets paged instead of allocated up front. well below the heap
+// ResourceLimits::untrusted allows, so a bot user asking for all of it doesnt get it allocated
+pub const SPARSE_THRESHOLD: usize = 1 << 16;
+
+// word addressed memory, either one flat vec or pages that only exist once something is written to them
+pub enum Memory {
+    Dense(Vec<u64>),
+    Paged(PagedMemory),
+}
+
+pub struct PagedMemory {
+    len: usize,
+    pages: HashMap<usize, Box<[u64]>>,
+}
+
+#[allow(dead_code)]
+impl Memory {
+    pub fn new(len: usize) -> Self {
+        if len > SPARSE_THRESHOLD {
+            Self::paged(len)
+        } else {
+            Self::dense(len)
+        }
+    }
+    pub fn dense(len: usize) -> Self {
+        Self::Dense(vec![0; len])
+    }
+    pub fn paged(len: usize) -> Self {
+        Self::Paged(PagedMemory { len, pages: HashMap::new() })
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            Self::Dense(data) => data.len(),
+            Self::Paged(mem) => mem.len,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // out of range accesses panic just like indexing a vec would
+    pub fn get(&self, index: usize) -> u64 {
+        match self {
+            Self::Dense(data) => data[index],
+            Self::Paged(mem) => {
+                mem.check(index);
+                mem.pages.get(&(index >> PAGE_BITS)).map_or(0, |page| page[index & PAGE_MASK])
+            },
+        }
+    }
+    pub fn set(&mut self, index: usize, value: u64) {
+        match self {
+            Self::Dense(data) => data[index] = value,
+            Self::Paged(mem) => {
+                mem.check(index);
+                let page = mem.pages.entry(index >> PAGE_BITS).or_insert_with(|| vec![0; PAGE_SIZE].into_boxed_slice());
+                page[index & PAGE_MASK] = value;
+            },
+        }
+    }
+
+    // how many pages are actually backed by memory, dense memory counts as all of them
+    pub fn allocated_pages(&self) -> usize {
+        match self {
+            Self::Dense(data) => (data.len() + PAGE_MASK) >> PAGE_BITS,
+            Self::Paged(mem) => mem.pages.len(),
+        }
+    }
+}
+
+impl PagedMemory {
+    fn check(&self, index: usize) {
+        if index >= self.len {
+            panic!("index out of bounds: the len is {} but the index is {}", self.len, index);
+        }
+    }
+}
+
+impl Debug for Memory {
+    fn fmt(&self, fmt: &mut Formatter<'_>) -> Result {
+        match self {
+            Self::Dense(data) => data.fmt(fmt),
+            Self::Paged(mem) => write!(fmt, "[{} words in {} of {} pages]", mem.len, mem.pages.len(), (mem.len + PAGE_MASK) >> PAGE_BITS),
+        }
+    }
+}
+
+#[wasm_bindgen]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Region {
+    Data, Heap, Stack,
+}
+
+// where everything lives in the address space: DW data, then the heap, then the stack at the top
+#[wasm_bindgen]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryMap {
+    pub heap_start: u64,
+    pub stack_start: u64,
+    pub end: u64,
+}
+
+#[allow(dead_code)]
+impl MemoryMap {
+    pub fn new(data: u64, heap: u64, stack: u64) -> Self {
+        Self { heap_start: data, stack_start: data + heap, end: data + heap + stack }
+    }
+
+    pub fn range_of(&self, region: Region) -> Range<u64> {
+        match region {
+            Region::Data => 0..self.heap_start,
+            Region::Heap => self.heap_start..self.stack_start,
+            Region::Stack => self.stack_start..self.end,
+        }
+    }
+}
+
+// for the web debugger, ranges cant go through bindgen so js reads the bounds off the fields
+#[wasm_bindgen]
+impl MemoryMap {
+    pub fn region_of(&self, address: u64) -> Option<Region> {
+        match address {
+            a if a < self.heap_start => Some(Region::Data),
+            a if a < self.stack_start => Some(Region::Heap),
+            a if a < self.end => Some(Region::Stack),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn threshold() {
+        assert!(matches!(Memory::new(SPARSE_THRESHOLD), Memory::Dense(_)));
+        assert!(matches!(Memory::new(SPARSE_THRESHOLD + 1), Memory::Paged(_)));
+    }
+
+    #[test]
+    fn paged() {
+        let mut mem = Memory::paged(3 * PAGE_SIZE);
+        assert_eq!((mem.len(), mem.allocated_pages()), (3 * PAGE_SIZE, 0));
+        // reads dont allocate anything
+        assert_eq!(mem.get(PAGE_SIZE + 5), 0);
+        assert_eq!(mem.allocated_pages(), 0);
+        mem.set(PAGE_SIZE + 5, 7);
+        mem.set(PAGE_SIZE + 6, 8);
+        mem.set(3 * PAGE_SIZE - 1, 9);
+        assert_eq!(mem.allocated_pages(), 2);
+        assert_eq!((mem.get(PAGE_SIZE + 5), mem.get(PAGE_SIZE + 6), mem.get(3 * PAGE_SIZE - 1), mem.get(PAGE_SIZE + 4)), (7, 8, 9, 0));
+    }
+
+    #[test]
+    fn regions() {
+        let map = MemoryMap::new(2, 3, 4);
+        let regions: Vec<_> = (0..10).map(|a| map.region_of(a)).collect();
+        let (d, h, s) = (Some(Region::Data), Some(Region::Heap), Some(Region::Stack));
+        assert_eq!(regions, [d, d, h, h, h, s, s, s, s, None]);
+        assert_eq!(map.range_of(Region::Heap), 2..5);
+    }
+
+    #[test]
+    #[should_panic]
+    fn paged_out_of_range() {
+        Memory::paged(10).get(10);
+    }
+}
@@ -2,6 +2,19 @@ pub mod ast;
 pub mod emulator;
 pub mod lexer;
 pub mod errorcontext;
+pub mod diagnostic;
 pub mod highlighter;
 pub mod devices;
+pub mod machine;
+pub mod cycles;
+pub mod limits;
+pub mod memory;
+pub mod float;
+pub mod expr;
+pub mod isa;
+pub mod custom;
+pub mod preprocess;
+pub mod source;
+pub mod lint;
+pub mod cfg;
 use super::*;
\ No newline at end of file
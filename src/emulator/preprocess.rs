@@ -0,0 +1,287 @@
+use std::collections::{HashMap, HashSet};
+
+use super::{lexer::{self, Kind, UToken}, errorcontext::{ErrorContext, ErrorKind}, source::{FileProvider, SourceFiles, is_include, include_path}};
+
+// deep enough for any sane macro, shallow enough that a macro calling itself doesnt blow the stack
+const MAX_DEPTH: usize = 64;
+// a macro calling itself twice doubles with every level, so the depth alone doesnt stop it in time
+const MAX_EXPANDED: usize = 1 << 18; // tokens
+
+struct Macro<'a> {
+    params: Vec<&'a str>,
+    body: Vec<UToken<'a>>,
+    labels: HashSet<&'a str>, // labels defined in the body, every expansion gets its own copy of these
+}
+
+pub struct Preprocessor<'a> {
+    files: &'a SourceFiles,
+    provider: &'a dyn FileProvider,
+    macros: HashMap<String, Macro<'a>>,
+    scopes: u32,
+    expanded: usize, // tokens that came out of macro calls so far
+    including: Vec<usize>, // the chain of files being included right now, to catch cycles
+    included: HashSet<usize>, // every file only gets included once
+    stop: usize, // where in the output the conditional the last expansion stopped at ended up
+}
+
+// @if, @ifdef, @ifndef, @else and @endif
+fn is_conditional(tok: &UToken) -> bool {
+    tok.kind == Kind::Macro && ["@if", "@ifdef", "@ifndef", "@else", "@endif"].iter().any(|c| tok.str.eq_ignore_ascii_case(c))
+}
+
+fn is_white(tok: &UToken) -> bool {
+    matches!(tok.kind, Kind::White | Kind::Comment)
+}
+
+fn is_end(tok: &UToken) -> bool {
+    matches!(tok.kind, Kind::LF | Kind::EOF)
+}
+
+impl <'a> Preprocessor<'a> {
+    pub fn new(files: &'a SourceFiles, provider: &'a dyn FileProvider) -> Self {
+        Preprocessor { files, provider, macros: HashMap::new(), scopes: 0, expanded: 0, including: vec![0], included: HashSet::from([0]), stop: 0 }
+    }
+
+    // splices in @included files, takes out @macro definitions and replaces calls with their bodies,
+    // before the parser sees anything. only the parser knows which @if branches are assembled, so
+    // everything from the first conditional on is left as it is and the parser calls expand_rest as it
+    // gets past them. gives back the tokens and where the ones that arent expanded yet start
+    pub fn preprocess(&mut self, toks: Vec<UToken<'a>>, err: &mut ErrorContext<'a>) -> (Vec<UToken<'a>>, usize) {
+        let (out, len, stop) = self.expand_rest(&toks, err);
+        (out.into_iter().chain(toks[len..].iter().cloned()).collect(), stop)
+    }
+
+    // expands toks up to the next @if, @else or @endif. gives back the expansion, how many tokens of
+    // toks it replaces and where in it the conditional is, the length when there wasnt one
+    pub fn expand_rest(&mut self, toks: &[UToken<'a>], err: &mut ErrorContext<'a>) -> (Vec<UToken<'a>>, usize, usize) {
+        // includes are relative to the file the tokens come from
+        let file = toks.iter().find_map(|t| self.files.locate(t.str)).map_or(0, |(file, ..)| file);
+        self.including = vec![file];
+        let mut out = Vec::with_capacity(toks.len());
+        match self.expand(toks, &mut out, 0, err) {
+            Some(len) => (out, len, self.stop),
+            None => {
+                let stop = out.len();
+                (out, toks.len(), stop)
+            },
+        }
+    }
+
+    // stops at the first conditional and gives back where it is, without one everything is expanded
+    fn expand(&mut self, toks: &[UToken<'a>], out: &mut Vec<UToken<'a>>, depth: usize, err: &mut ErrorContext<'a>) -> Option<usize> {
+        let mut i = 0;
+        let mut line_start = true;
+        while i < toks.len() {
+            let tok = &toks[i];
+            match tok.kind {
+                Kind::Macro if is_conditional(tok) => {
+                    self.stop = out.len();
+                    return Some(i);
+                },
+                Kind::Macro if tok.str.eq_ignore_ascii_case("@macro") => {
+                    i = self.define(toks, i, out, err);
+                    continue;
+                },
+                Kind::Macro if is_include(tok) => {
+                    let (next, stopped) = self.include(toks, i, out, depth, err);
+                    if stopped {
+                        return Some(next);
+                    }
+                    i = next;
+                    continue;
+                },
+                Kind::Macro if tok.str.eq_ignore_ascii_case("@endmacro") => {
+                    err.error(tok, ErrorKind::UnexpectedMacro);
+                },
+                Kind::Name if line_start && self.macros.contains_key(&tok.str.to_lowercase()) => {
+                    let (next, stopped) = self.call(toks, i, out, depth, err);
+                    if stopped {
+                        return Some(next);
+                    }
+                    i = next;
+                    continue;
+                },
+                _ => out.push(tok.clone()),
+            }
+            if is_end(tok) {
+                line_start = true;
+            } else if !is_white(tok) {
+                line_start = false;
+            }
+            i += 1;
+        }
+        None
+    }
+
+    fn include(&mut self, toks: &[UToken<'a>], start: usize, out: &mut Vec<UToken<'a>>, depth: usize, err: &mut ErrorContext<'a>) -> (usize, bool) {
+        let Some((path, end)) = include_path(toks, start) else {
+            err.error(&toks[start], ErrorKind::ExpectedIncludePath);
+            return (start + 1, false);
+        };
+
+        let from = *self.including.last().unwrap();
+        let mut stopped = false;
+        // files are only read once an @include is reached, so ones in skipped @if branches never are
+        let file = match self.files.include(&path, from, self.provider) {
+            Ok(file) => file,
+            Err(size) => {
+                err.error(&toks[start], ErrorKind::ResourceLimit {
+                    what: "Source size", value: size as u64, limit: self.files.max_size as u64, unit: "bytes"
+                });
+                return (end, false);
+            },
+        };
+        match file {
+            None => err.error(&toks[start], ErrorKind::IncludeNotFound(path)),
+            Some(file) if self.including.contains(&file) => err.error(&toks[start], ErrorKind::IncludeCycle),
+            Some(file) if !self.included.insert(file) => {},
+            Some(file) => {
+                let mut included = lexer::lex(&self.files.get(file).src);
+                included.pop(); // the end of file token
+                self.including.push(file);
+                // the rest of the file waits for the parser like everything after it
+                if let Some(stop) = self.expand(&included, out, depth, err) {
+                    out.extend_from_slice(&included[stop..]);
+                    stopped = true;
+                }
+                self.including.pop();
+            },
+        }
+        (end, stopped)
+    }
+
+    // @macro NAME a b, then the body until @endmacro
+    fn define(&mut self, toks: &[UToken<'a>], start: usize, out: &mut Vec<UToken<'a>>, err: &mut ErrorContext<'a>) -> usize {
+        let mut i = start + 1;
+        let mut header = Vec::new();
+        while i < toks.len() && !is_end(&toks[i]) {
+            if !is_white(&toks[i]) {
+                header.push(toks[i].clone());
+            }
+            i += 1;
+        }
+
+        let body_start = i;
+        let mut end = None;
+        while i < toks.len() && toks[i].kind != Kind::EOF {
+            // keep the line breaks so line numbers after the definition still line up
+            if toks[i].kind == Kind::LF {
+                out.push(toks[i].clone());
+            }
+            if toks[i].kind == Kind::Macro && toks[i].str.eq_ignore_ascii_case("@endmacro") {
+                end = Some(i);
+                break;
+            }
+            i += 1;
+        }
+        let Some(end) = end else {
+            err.error(&toks[start], ErrorKind::MacroNoEnding);
+            return i;
+        };
+
+        let Some((name, params)) = header.split_first() else {
+            err.error(&toks[start], ErrorKind::MacroNoName);
+            return end + 1;
+        };
+        for param in params.iter().filter(|t| t.kind != Kind::Name) {
+            err.error(param, ErrorKind::InvalidMacroParameter);
+        }
+        if name.kind != Kind::Name {
+            err.error(name, ErrorKind::MacroNoName);
+            return end + 1;
+        }
+        if self.macros.contains_key(&name.str.to_lowercase()) {
+            err.error(name, ErrorKind::DuplicatedMacroName);
+        }
+
+        // skip the line break after the header, its already in out
+        let body = toks[body_start + 1..end].to_vec();
+        let mut labels = HashSet::new();
+        let mut line_start = true;
+        for tok in body.iter() {
+            if line_start && tok.kind == Kind::Label {
+                labels.insert(tok.str);
+            }
+            if is_end(tok) {
+                line_start = true;
+            } else if !is_white(tok) {
+                line_start = false;
+            }
+        }
+
+        let params = params.iter().map(|t| t.str).collect();
+        self.macros.insert(name.str.to_lowercase(), Macro { params, body, labels });
+        end + 1
+    }
+
+    fn call(&mut self, toks: &[UToken<'a>], start: usize, out: &mut Vec<UToken<'a>>, depth: usize, err: &mut ErrorContext<'a>) -> (usize, bool) {
+        let call = &toks[start];
+        if depth >= MAX_DEPTH {
+            err.error(call, ErrorKind::MacroTooDeep);
+            return (start + 1, false);
+        }
+        if self.expanded >= MAX_EXPANDED {
+            // the call that went over already said so
+            return (start + 1, false);
+        }
+
+        // every argument is a run of tokens without whitespace, so 'a' and "hi" stay in one piece.
+        // whitespace inside parentheses doesnt count, so (a + b) is one argument too
+        let mut args: Vec<Vec<UToken<'a>>> = Vec::new();
+        let mut i = start + 1;
+        let mut in_arg = false;
+        let mut parens = 0usize;
+        while i < toks.len() && !is_end(&toks[i]) {
+            match toks[i].kind {
+                Kind::LParen => parens += 1,
+                Kind::RParen => parens = parens.saturating_sub(1),
+                _ => {},
+            }
+            if is_white(&toks[i]) && (parens == 0 || !in_arg) {
+                in_arg = false;
+            } else {
+                if !in_arg {
+                    args.push(Vec::new());
+                    in_arg = true;
+                }
+                args.last_mut().unwrap().push(toks[i].clone());
+            }
+            i += 1;
+        }
+
+        let name = call.str.to_lowercase();
+        let def = &self.macros[&name];
+        if args.len() != def.params.len() {
+            err.error(call, ErrorKind::MacroArguments { expected: def.params.len(), actual: args.len() });
+            return (i, false);
+        }
+
+        self.scopes += 1;
+        self.expanded += def.body.len();
+        if self.expanded >= MAX_EXPANDED {
+            err.error(call, ErrorKind::MacroTooDeep);
+        }
+        let scope = self.scopes;
+        let mut body = Vec::with_capacity(def.body.len());
+        for tok in def.body.iter() {
+            if let Some(arg) = def.params.iter().position(|p| tok.kind == Kind::Name && *p == tok.str) {
+                body.extend(args[arg].iter().cloned());
+                continue;
+            }
+            let mut tok = tok.clone();
+            tok.origin = Some(call.str);
+            if tok.kind == Kind::Label && def.labels.contains(tok.str) {
+                tok.scope = scope;
+            }
+            body.push(tok);
+        }
+
+        match self.expand(&body, out, depth + 1, err) {
+            Some(stop) => {
+                out.extend_from_slice(&body[stop..]);
+                (i, true)
+            },
+            None => (i, false),
+        }
+    }
+}
@@ -0,0 +1,326 @@
+use std::{cell::Cell, collections::HashMap, io::Read, rc::Rc};
+
+use elsa::FrozenVec;
+use wasm_bindgen::prelude::*;
+
+use super::lexer::{Kind, UToken};
+
+// where @include gets its files from
+pub trait FileProvider {
+    // anything past max bytes is over the source limit anyway, so it doesnt have to be read
+    fn read(&self, path: &str, max: usize) -> Option<String>;
+
+    // searched after the directory of the file doing the include
+    fn include_paths(&self) -> &[String] {
+        &[]
+    }
+
+    // names that are @defined before the program starts, like -D on the command line
+    fn defines(&self) -> &[(String, String)] {
+        &[]
+    }
+}
+
+#[allow(dead_code)] // only the cli reads real files
+pub struct FileSystem {
+    pub include_paths: Vec<String>,
+    pub defines: Vec<(String, String)>,
+}
+
+impl FileProvider for FileSystem {
+    fn read(&self, path: &str, max: usize) -> Option<String> {
+        // reads one byte too many so files that are too big (or never end, like /dev/zero) can be told apart
+        let mut bytes = Vec::new();
+        std::fs::File::open(path).ok()?.take(max as u64 + 1).read_to_end(&mut bytes).ok()?;
+        if bytes.len() > max {
+            Some(String::from_utf8_lossy(&bytes).into_owned())
+        } else {
+            String::from_utf8(bytes).ok()
+        }
+    }
+    fn include_paths(&self) -> &[String] {
+        &self.include_paths
+    }
+    fn defines(&self) -> &[(String, String)] {
+        &self.defines
+    }
+}
+
+// files handed over by name, for the web page and the discord bot where there is no file system
+#[wasm_bindgen]
+#[derive(Debug, Default)]
+pub struct VirtualFiles {
+    files: HashMap<String, String>,
+    include_paths: Vec<String>,
+    defines: Vec<(String, String)>,
+}
+
+#[wasm_bindgen]
+#[allow(dead_code)]
+impl VirtualFiles {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_file(&mut self, name: &str, src: String) {
+        self.files.insert(normalize(name), src);
+    }
+
+    pub fn add_include_path(&mut self, path: &str) {
+        self.include_paths.push(normalize(path));
+    }
+
+    pub fn add_define(&mut self, name: &str, value: &str) {
+        self.defines.push((name.to_owned(), value.to_owned()));
+    }
+}
+
+impl FileProvider for VirtualFiles {
+    fn read(&self, path: &str, _max: usize) -> Option<String> {
+        self.files.get(path).cloned()
+    }
+    fn include_paths(&self) -> &[String] {
+        &self.include_paths
+    }
+    fn defines(&self) -> &[(String, String)] {
+        &self.defines
+    }
+}
+
+#[derive(Debug)]
+pub struct SourceFile {
+    pub name: String,
+    pub src: Rc<str>,
+    line_starts: Vec<usize>,
+}
+
+impl SourceFile {
+    fn new(name: String, src: Rc<str>) -> Self {
+        let line_starts = std::iter::once(0).chain(src.match_indices('\n').map(|(i, _)| i + 1)).collect();
+        Self { name, src, line_starts }
+    }
+
+    pub fn line(&self, line: usize) -> &str {
+        let start = self.line_starts[line - 1];
+        let end = self.line_starts.get(line).map_or(self.src.len(), |end| end - 1);
+        &self.src[start..end]
+    }
+}
+
+// the main file and everything it includes. included files are only read once the preprocessor gets
+// to their @include, files are never taken out again so tokens can borrow all of them
+pub struct SourceFiles {
+    files: FrozenVec<Box<SourceFile>>,
+    size: Cell<usize>,
+    pub max_size: usize, // bytes
+    include_paths: Vec<String>,
+}
+
+// the defines from the command line or the api get a file of their own, one NAME=value per line,
+// so errors in them can point somewhere
+pub const COMMAND_LINE: &str = "<command line>";
+
+impl std::fmt::Debug for SourceFiles {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_list().entries(self.files.iter()).finish()
+    }
+}
+
+#[allow(dead_code)]
+impl SourceFiles {
+    pub fn load(name: &str, src: Rc<str>, provider: &dyn FileProvider, max_size: usize) -> Self {
+        let files = Self {
+            files: FrozenVec::from(vec![Box::new(SourceFile::new(normalize(name), src.clone()))]),
+            size: Cell::new(src.len()), max_size,
+            include_paths: provider.include_paths().to_vec(),
+        };
+        if !provider.defines().is_empty() {
+            let text: String = provider.defines().iter().map(|(name, value)| format!("{}={}\n", name, value.replace('\n', " "))).collect();
+            files.size.set(files.size.get() + text.len());
+            files.files.push(Box::new(SourceFile::new(COMMAND_LINE.to_owned(), Rc::from(text))));
+        }
+        files
+    }
+
+    // names and values of the defines, borrowed from the command line file
+    pub fn defines(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.files.iter().skip(1).take(1).filter(|f| f.name == COMMAND_LINE)
+            .flat_map(|f| f.src.lines()).filter_map(|line| line.split_once('='))
+    }
+
+    // the file an @include in file from refers to, read through the provider the first time. a file that
+    // would take the sources over max_size isnt kept, that gives back the size they would have had
+    pub fn include(&self, path: &str, from: usize, provider: &dyn FileProvider) -> Result<Option<usize>, usize> {
+        if let Some(file) = self.find(path, from) {
+            return Ok(Some(file));
+        }
+        let left = self.max_size.saturating_sub(self.size.get());
+        for candidate in self.candidates(path, from) {
+            if let Some(text) = provider.read(&candidate, left) {
+                if text.len() > left {
+                    return Err(self.size.get() + text.len());
+                }
+                self.size.set(self.size.get() + text.len());
+                self.files.push(Box::new(SourceFile::new(candidate, Rc::from(text))));
+                return Ok(Some(self.files.len() - 1));
+            }
+        }
+        Ok(None)
+    }
+
+    pub fn main(&self) -> &SourceFile {
+        &self.files[0]
+    }
+
+    pub fn get(&self, file: usize) -> &SourceFile {
+        &self.files[file]
+    }
+
+    pub fn len(&self) -> usize {
+        self.files.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
+    pub fn total_size(&self) -> usize {
+        self.size.get()
+    }
+
+    // relative to the including file first, then the include paths, then as is
+    fn candidates(&self, path: &str, from: usize) -> Vec<String> {
+        let dir = self.files[from].name.rsplit_once('/').map_or("", |(dir, _)| dir);
+        let mut candidates = vec![join(dir, path)];
+        candidates.extend(self.include_paths.iter().map(|inc| join(inc, path)));
+        candidates.push(normalize(path));
+        candidates
+    }
+
+    pub fn find(&self, path: &str, from: usize) -> Option<usize> {
+        self.candidates(path, from).iter().find_map(|c| self.files.iter().position(|f| f.name == *c))
+    }
+
+    // which file, line, column and byte a piece of source starts at, lines start at 1
+    pub fn locate(&self, span: &str) -> Option<(usize, usize, usize, usize)> {
+        let ptr = span.as_ptr() as usize;
+        self.files.iter().enumerate().find_map(|(i, file)| {
+            let start = file.src.as_ptr() as usize;
+            if ptr < start || ptr > start + file.src.len() {
+                return None;
+            }
+            let offset = ptr - start;
+            let line = file.line_starts.partition_point(|s| *s <= offset);
+            let col = file.src[file.line_starts[line - 1]..offset].chars().count();
+            Some((i, line, col, offset))
+        })
+    }
+
+    // the source from the start of first to the end of last, for errors about more than one token.
+    // None if they arent in the same file in that order, like when one came out of a macro
+    pub fn between(&self, first: &str, last: &str) -> Option<&str> {
+        let (a, _, _, start) = self.locate(first)?;
+        let (b, _, _, end) = self.locate(last)?;
+        (a == b && start <= end).then(|| &self.files[a].src[start..end + last.len()])
+    }
+
+    // just the line number for single file programs, file:line once there are includes
+    pub fn describe(&self, file: usize, line: usize) -> String {
+        if self.files[file].name == COMMAND_LINE || self.files.iter().filter(|f| f.name != COMMAND_LINE).count() > 1 {
+            format!("{}:{}", self.files[file].name, line)
+        } else {
+            line.to_string()
+        }
+    }
+}
+
+pub fn is_include(tok: &UToken) -> bool {
+    tok.kind == Kind::Macro && (tok.str.eq_ignore_ascii_case("@include") || tok.str.eq_ignore_ascii_case("@import"))
+}
+
+// the path of `@include "path"` starting at the @include token, and the index after the closing quote
+pub fn include_path(toks: &[UToken], start: usize) -> Option<(String, usize)> {
+    let mut i = start + 1;
+    while toks.get(i)?.kind == Kind::White {
+        i += 1;
+    }
+    if toks[i].kind != Kind::String {
+        return None;
+    }
+    let mut path = String::new();
+    loop {
+        i += 1;
+        match toks.get(i)?.kind {
+            Kind::String => return Some((path, i + 1)),
+            Kind::Text => path += toks[i].str,
+            Kind::Escape(c) => path.push(c),
+            _ => return None,
+        }
+    }
+}
+
+fn join(dir: &str, path: &str) -> String {
+    if dir.is_empty() || path.starts_with('/') {
+        normalize(path)
+    } else {
+        normalize(&format!("{}/{}", dir, path))
+    }
+}
+
+// gets rid of `.` and `dir/..` so the same file always has the same name
+fn normalize(path: &str) -> String {
+    let mut parts: Vec<&str> = Vec::new();
+    for part in path.split('/') {
+        match part {
+            "." => {},
+            "" if !parts.is_empty() => {},
+            ".." if matches!(parts.last(), Some(p) if *p != ".." && !p.is_empty()) => {parts.pop();},
+            _ => parts.push(part),
+        }
+    }
+    parts.join("/")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::{emulator::compile, limits::ResourceLimits, lint::Lints};
+
+    // panics when a file called never.urcl is read
+    struct Watched(VirtualFiles);
+    impl FileProvider for Watched {
+        fn read(&self, path: &str, max: usize) -> Option<String> {
+            assert_ne!(path, "never.urcl");
+            self.0.read(path, max)
+        }
+    }
+
+    fn codes(src: &str, provider: &dyn FileProvider) -> Vec<&'static str> {
+        let (_, diagnostics) = compile("main.urcl", src.to_owned(), provider, &ResourceLimits::untrusted(), &Lints::new());
+        diagnostics.iter().map(|d| d.code).collect()
+    }
+
+    #[test]
+    fn skipped_includes_arent_read() {
+        let mut files = VirtualFiles::new();
+        files.add_file("never.urcl", "HLT".to_owned());
+        files.add_file("used.urcl", "HLT".to_owned());
+        let files = Watched(files);
+        assert_eq!(codes("@if @BITS == 16\n@include \"never.urcl\"\n@else\n@include \"used.urcl\"\n@endif", &files), Vec::<&str>::new());
+    }
+
+    #[test]
+    fn includes_count_towards_max_source() {
+        let mut files = VirtualFiles::new();
+        files.add_file("big.urcl", "//".repeat(1 << 18));
+        assert_eq!(codes("@include \"big.urcl\"\nHLT", &files), ["E0013"]);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn endless_include() {
+        let files = FileSystem { include_paths: Vec::new(), defines: Vec::new() };
+        assert_eq!(codes("@include \"/dev/zero\"\nHLT", &files), ["E0013"]);
+    }
+}
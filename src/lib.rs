@@ -1,5 +1,5 @@
 use wasm_bindgen::prelude::*;
-mod emulator;
+pub mod emulator;
 
 extern crate console_error_panic_hook;
 use std::panic;
@@ -50,20 +50,13 @@ pub fn init_panic_hook() {
     console_error_panic_hook::set_once();
 }
 
-pub fn out_err(out: &mut String, error: &emulator::errorcontext::Error, lineno: &String, line: &str, col: usize) {
-    use std::fmt::Write;
-    use crate::emulator::errorcontext::*;
-    writeln!(out, "<span class=\"{}\">{}: {}</span>",
-        format!("{}", error.level).to_lowercase(), error.level, error.kind
-    ).unwrap();
-    writeln!(out, "{}| {}", 
-        lineno, html_escape::encode_text(&line.split_at(get_indent_level(line)).1.replace("\t", " "))
-    ).unwrap();
-    writeln!(out, "{}| {}{}",
-        " ".repeat(str_width(lineno)),
-        &" ".repeat(col - get_indent_level(line)),
-        &"^".repeat(str_width(error.span).max(1))
-    ).unwrap();
+// assembles without running or printing anything, for the fuzz target in fuzz/
+pub fn check(src: String) -> Result<(), Vec<emulator::diagnostic::Diagnostic>> {
+    use emulator::{emulator::compile, source::VirtualFiles, limits::ResourceLimits, lint::Lints};
+    match compile("main.urcl", src, &VirtualFiles::new(), &ResourceLimits::untrusted(), &Lints::new()) {
+        (Some(_), _) => Ok(()),
+        (None, err) => Err(err),
+    }
 }
 
 pub fn out_emu_err(out: &mut String, error: &emulator::emulator::EmulatorErrorKind, lineno: &String, line: &str) {
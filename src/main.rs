@@ -7,25 +7,125 @@ mod discord_bot;
 fn main() {
     #[cfg(not(feature = "bot"))] {
         let args: Vec<String> = std::env::args().collect();
-        if args.len() <= 1 {
+        // -I dir adds a directory to search for @include files, -D NAME=value defines NAME before the program starts
+        // --format ansi|plain|json picks how errors are printed, -Wno-name turns a warning off and -Wname back on
+        // --dot prints the control flow graph for graphviz instead of running, --calls just the call graph
+        // --data file queues the numbers in file to be read through %DATA
+        let mut include_paths = Vec::new();
+        let mut format = emulator::diagnostic::Format::Ansi;
+        let mut lints = emulator::lint::Lints::new();
+        let mut dot = false;
+        let mut calls = false;
+        let mut data = Vec::new();
+        let mut defines = Vec::new();
+        let mut fnames = Vec::new();
+        let mut rest = args[1..].iter();
+        while let Some(arg) = rest.next() {
+            if let Some(path) = arg.strip_prefix("-I") {
+                include_paths.extend(if path.is_empty() {rest.next().cloned()} else {Some(path.to_owned())});
+            } else if let Some(define) = arg.strip_prefix("-D") {
+                let define = if define.is_empty() {rest.next().map_or("", |d| d.as_str())} else {define};
+                let (name, value) = define.split_once('=').unwrap_or((define, "1"));
+                defines.push((name.to_owned(), value.to_owned()));
+            } else if let Some(lint) = arg.strip_prefix("-W") {
+                let (name, on) = match lint.strip_prefix("no-") {
+                    Some(name) => (name, false),
+                    None => (lint, true),
+                };
+                if !lints.set(name, on) {
+                    println!("\x1b[1;31mError: Unknown warning {}.\x1b[0;0m", name);
+                    return;
+                }
+            } else if arg == "--dot" {
+                dot = true;
+            } else if arg == "--calls" {
+                dot = true;
+                calls = true;
+            } else if arg == "--data" {
+                let Some(fname) = rest.next() else {
+                    println!("\x1b[1;31mError: Expected a file after --data.\x1b[0;0m");
+                    return;
+                };
+                let text = match std::fs::read_to_string(fname) {
+                    Ok(text) => text,
+                    Err(err) => {
+                        println!("\x1b[1;31mError: Cannot read file {} (Returns error \"{}\")\x1b[0;0m", fname, err);
+                        return;
+                    },
+                };
+                // words are separated by whitespace or commas and written like URCL numbers, 0x10 or -1
+                for word in text.split(|c: char| c.is_whitespace() || c == ',').filter(|w| !w.is_empty()) {
+                    match emulator::lexer::parse_int(word) {
+                        Ok(value) => data.push(value as u64),
+                        Err(err) => {
+                            println!("\x1b[1;31mError: Invalid number {} in {} ({})\x1b[0;0m", word, fname, emulator::errorcontext::ErrorKind::InvalidNumber(err));
+                            return;
+                        },
+                    }
+                }
+            } else if arg == "--format" {
+                match rest.next().map(|f| f.parse()) {
+                    Some(Ok(f)) => format = f,
+                    _ => {
+                        println!("\x1b[1;31mError: Expected ansi, plain, json or html after --format.\x1b[0;0m");
+                        return;
+                    },
+                }
+            } else {
+                fnames.push(arg);
+            }
+        }
+        if fnames.is_empty() {
             println!("\x1b[1;31mError: Not enough arguments.\x1b[0;0m");
             return;
         }
-        let fname = &args[1];
-        let src = std::fs::read_to_string(fname);
-        match &src {Err(err) => {
-            println!("\x1b[1;31mError: Cannot read file {} (Returns error \"{}\")\x1b[0;0m", fname, err);
-            return;
-        }, _ => ()}
-        let emu = emulator::emulator::emulate(src.unwrap());
-        match emu {
-            None => {
-                println!("\x1b[1;31mError: Compilation failed\x1b[0;0m");
+        let files = emulator::source::FileSystem { include_paths, defines };
+        let mut cores = Vec::new();
+        for fname in fnames {
+            let src = std::fs::read_to_string(fname);
+            match &src {Err(err) => {
+                println!("\x1b[1;31mError: Cannot read file {} (Returns error \"{}\")\x1b[0;0m", fname, err);
                 return;
+            }, _ => ()}
+            let (emu, diagnostics) = emulator::emulator::silence_emulate_with_provider(fname, src.unwrap(), &files, emulator::limits::ResourceLimits::new(), &lints);
+            // the graph is meant to be piped into dot, warnings would get in the way
+            if !dot || emu.is_none() {
+                println!("{}", emulator::diagnostic::render(&diagnostics, format));
+            }
+            match emu {
+                None => {
+                    print!("{}", emulator::diagnostic::failed(format));
+                    return;
+                }
+                _ => (),
+            } 
+            let mut emu = emu.unwrap();
+            emu.load_data(&data);
+            cores.push(emu);
+        }
+        if dot {
+            for core in cores.iter() {
+                let cfg = emulator::cfg::Cfg::new(core.program());
+                print!("{}", if calls {cfg.calls_to_dot()} else {cfg.to_dot(core.program())});
             }
-            _ => (),
-        } 
-        println!("{:?}", emu.unwrap().run());
+            return;
+        }
+        if cores.len() == 1 {
+            println!("{:?}", cores.pop().unwrap().run());
+            return;
+        }
+
+        // more than one file runs every file on its own core
+        use emulator::machine::{Machine, Schedule};
+        let mut machine = Machine::new(cores, Schedule::LockStep);
+        let result = machine.run();
+        for (i, core) in machine.cores().iter().enumerate() {
+            println!("core {}:\n{}", i, core.get_output());
+        }
+        if let Some(err) = machine.get_err() {
+            println!("\x1b[1;31m{}\x1b[0;0m", err);
+        }
+        println!("{:?}", result);
     }
 
     #[cfg(feature = "bot")] {
@@ -87,45 +187,6 @@ pub fn srand(seed: u64) {
     }
 }
 
-pub fn out_err(out: &mut String, error: &emulator::errorcontext::Error, lineno: &String, line: &str, col: usize) {
-    use std::fmt::Write;
-    use crate::emulator::errorcontext::*;
-    #[cfg(not(feature = "bot"))] {
-        writeln!(out, "\x1b[1;{}m{}: {}\x1b[0;0m",
-            match error.level {
-                ErrorLevel::Info    => 36,
-                ErrorLevel::Warning => 33,
-                ErrorLevel::Error   => 31,
-            }, error.level, error.kind
-        ).unwrap();
-        writeln!(out, "\t{}| {}", 
-            lineno, html_escape::encode_text(&line.split_at(get_indent_level(line)).1.replace("\t", " "))
-        ).unwrap();
-        writeln!(out, "\t{}| {}{}",
-            " ".repeat(str_width(lineno)),
-            &" ".repeat(col - get_indent_level(line)),
-            &"^".repeat(str_width(error.span).max(1))
-        ).unwrap();
-    }
-    #[cfg(feature = "bot")] {
-        writeln!(out, "\x1b[1;{}m{}: {}\x1b[0;0m",
-            match error.level {
-                ErrorLevel::Info    => 36,
-                ErrorLevel::Warning => 33,
-                ErrorLevel::Error   => 31,
-            }, error.level, error.kind
-        ).unwrap();
-        writeln!(out, "\t{}| {}", 
-            lineno, html_escape::encode_text(&line.split_at(get_indent_level(line)).1.replace("\t", " "))
-        ).unwrap();
-        writeln!(out, "\t{}| {}{}",
-            " ".repeat(str_width(lineno)),
-            &" ".repeat(col - get_indent_level(line)),
-            &"^".repeat(str_width(error.span).max(1))
-        ).unwrap();
-    }
-}
-
 pub fn out_emu_err(out: &mut String, error: &emulator::emulator::EmulatorErrorKind, lineno: &String, line: &str) {
     use std::fmt::Write;
     use crate::emulator::errorcontext::*;